@@ -4,6 +4,11 @@ use chacha20poly1305::{
     ChaCha20Poly1305, KeyInit,
 };
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    time::{Duration, Instant},
+};
+use zeroize::Zeroizing;
 
 #[derive(Serialize, Deserialize)]
 pub struct EncryptData {
@@ -13,6 +18,132 @@ pub struct EncryptData {
     n: Vec<u8>,
     /// data
     d: Vec<u8>,
+    /// 根据密码生成密钥时使用的 argon2 参数，使用密钥直接加密时为None
+    k: Option<KdfParams>,
+}
+
+/// 旧版本的加密数据，没有保存 argon2 参数，根据密码生成密钥时使用的是`KdfParams::legacy()`
+#[derive(Deserialize)]
+struct LegacyEncryptData {
+    s: Vec<u8>,
+    n: Vec<u8>,
+    d: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum KdfVariant {
+    Argon2d,
+    Argon2i,
+    Argon2id,
+}
+
+/// 根据密码生成密钥时使用的 argon2 参数，和加密数据保存在一起，因此修改参数不影响已加密的数据
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct KdfParams {
+    pub variant: KdfVariant,
+    /// 内存开销(KiB)
+    pub mem_cost: u32,
+    /// 迭代次数
+    pub time_cost: u32,
+    /// 并行度
+    pub lanes: u32,
+    /// salt的字节数
+    pub salt_len: u32,
+}
+
+impl Default for KdfParams {
+    /// 新加密的数据默认使用的参数：argon2id，19MiB内存，迭代2次
+    fn default() -> Self {
+        Self {
+            variant: KdfVariant::Argon2id,
+            mem_cost: 19 * 1024,
+            time_cost: 2,
+            lanes: 1,
+            salt_len: 16,
+        }
+    }
+}
+
+impl Display for KdfParams {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let variant = match self.variant {
+            KdfVariant::Argon2d => "argon2d",
+            KdfVariant::Argon2i => "argon2i",
+            KdfVariant::Argon2id => "argon2id",
+        };
+        write!(
+            f,
+            "{} m={}KiB t={} p={} salt={}",
+            variant, self.mem_cost, self.time_cost, self.lanes, self.salt_len
+        )
+    }
+}
+
+impl KdfParams {
+    /// 旧版本使用的参数(rust-argon2 1.0的默认参数，8字节salt)
+    pub fn legacy() -> Self {
+        Self {
+            variant: KdfVariant::Argon2i,
+            mem_cost: 4096,
+            time_cost: 3,
+            lanes: 1,
+            salt_len: 8,
+        }
+    }
+
+    /// 在本机上测试 argon2 的速度，选择使得根据密码生成一次密钥大约耗时target的参数，内存开销不超过max_mem(KiB)，
+    /// 返回选择的参数和使用该参数实际的耗时
+    ///
+    /// 优先增加内存开销(抵抗GPU爆破)，内存达到上限后再增加迭代次数
+    pub fn tune(target: Duration, max_mem: u32) -> (Self, Duration) {
+        let lanes = std::thread::available_parallelism()
+            .map(|n| n.get() as u32)
+            .unwrap_or(1)
+            .min(4);
+        let min_mem = 8 * 1024 * lanes;
+        let mut params = Self {
+            variant: KdfVariant::Argon2id,
+            mem_cost: min_mem,
+            time_cost: 2,
+            lanes,
+            salt_len: 16,
+        };
+
+        // 耗时和 mem_cost*time_cost 大致成正比
+        let ratio = target.as_secs_f64() / params.bench().as_secs_f64().max(1e-6);
+        let mem = (min_mem as f64 * ratio).min(max_mem as f64) as u32;
+        params.mem_cost = mem.max(min_mem) / (8 * lanes) * (8 * lanes);
+        let time = params.time_cost as f64 * ratio * min_mem as f64 / params.mem_cost as f64;
+        params.time_cost = (time.round() as u32).max(params.time_cost);
+
+        let elapsed = params.bench();
+        (params, elapsed)
+    }
+
+    /// 使用该参数根据密码生成一次密钥的耗时
+    fn bench(&self) -> Duration {
+        let salt = EncryptData::gen_salt_with_len(self.salt_len);
+        let start = Instant::now();
+        let _ = EncryptData::gen_passwd("pser kdf bench", &salt, &[], self);
+        start.elapsed()
+    }
+
+    fn config<'a>(&self, secret: &'a [u8]) -> argon2::Config<'a> {
+        argon2::Config {
+            variant: match self.variant {
+                KdfVariant::Argon2d => argon2::Variant::Argon2d,
+                KdfVariant::Argon2i => argon2::Variant::Argon2i,
+                KdfVariant::Argon2id => argon2::Variant::Argon2id,
+            },
+            mem_cost: self.mem_cost,
+            time_cost: self.time_cost,
+            lanes: self.lanes,
+            thread_mode: argon2::ThreadMode::from_threads(self.lanes),
+            hash_length: 32,
+            secret,
+            ..argon2::Config::default()
+        }
+    }
 }
 
 impl EncryptData {
@@ -21,24 +152,129 @@ impl EncryptData {
     where
         T: Serialize,
     {
-        let v8 = bincode::serialize(data)?;
-        let encrypt_data = Self::inner_encrypt(v8, passwd)?;
-        let encrypt_data = bincode::serialize(&encrypt_data).unwrap();
-        Ok(encrypt_data)
+        Self::encrypt_with_secret(data, passwd, &[])
     }
 
     /// 对加密后的数据(bincode序列化之后的加密数据)进行解密
     pub fn decrypt<S>(enc_data: &[u8], passwd: &str) -> Result<S, anyhow::Error>
+    where
+        S: DeserializeOwned,
+    {
+        Self::decrypt_with_secret(enc_data, passwd, &[])
+    }
+
+    /// 和`encrypt`相同，但 argon2 根据密码生成密钥时还会混入secret(例如密钥文件的hash)，
+    /// 解密时必须同时提供密码和secret。secret为空时和`encrypt`等价
+    pub fn encrypt_with_secret<T>(
+        data: &T,
+        passwd: &str,
+        secret: &[u8],
+    ) -> Result<Vec<u8>, anyhow::Error>
+    where
+        T: Serialize,
+    {
+        Self::encrypt_with_params(data, passwd, secret, &KdfParams::default())
+    }
+
+    /// 和`encrypt_with_secret`相同，但使用指定的 argon2 参数，参数会和加密数据保存在一起，解密时无需再提供
+    pub fn encrypt_with_params<T>(
+        data: &T,
+        passwd: &str,
+        secret: &[u8],
+        params: &KdfParams,
+    ) -> Result<Vec<u8>, anyhow::Error>
+    where
+        T: Serialize,
+    {
+        let v8 = Zeroizing::new(bincode::serialize(data)?);
+        let encrypt_data = Self::inner_encrypt(&v8, passwd, secret, params)?;
+        let encrypt_data = bincode::serialize(&encrypt_data).unwrap();
+        Ok(encrypt_data)
+    }
+
+    /// 对`encrypt_with_secret`加密后的数据进行解密
+    pub fn decrypt_with_secret<S>(
+        enc_data: &[u8],
+        passwd: &str,
+        secret: &[u8],
+    ) -> Result<S, anyhow::Error>
     where
         S: DeserializeOwned,
     {
         // let bincode_data = hex::decode(enc_data)?;
-        let encrypt_data = bincode::deserialize::<Self>(enc_data)?;
-        let plain_data_vec = encrypt_data.inner_decrypt(passwd)?;
+        let encrypt_data = Self::decode(enc_data)?;
+        let plain_data_vec = encrypt_data.inner_decrypt(passwd, secret)?;
+        bincode::deserialize::<S>(&plain_data_vec)
+            .map_err(|e| anyhow!("bincode deserialize error: {}", e))
+    }
+
+    /// 使用已经生成好的密钥(见`derive_key`)加密，并使用bincode对加密后的数据进行序列化
+    ///
+    /// 和`encrypt`不同，不会再执行 argon2，适合使用同一个密钥加密大量数据
+    pub fn encrypt_with_key<T>(data: &T, key: &[u8]) -> Result<Vec<u8>, anyhow::Error>
+    where
+        T: Serialize,
+    {
+        let v8 = Zeroizing::new(bincode::serialize(data)?);
+        let encrypt_data = Self::cipher_encrypt(&v8, key, vec![])?;
+        let encrypt_data = bincode::serialize(&encrypt_data).unwrap();
+        Ok(encrypt_data)
+    }
+
+    /// 使用已经生成好的密钥对`encrypt_with_key`加密后的数据进行解密
+    pub fn decrypt_with_key<S>(enc_data: &[u8], key: &[u8]) -> Result<S, anyhow::Error>
+    where
+        S: DeserializeOwned,
+    {
+        let plain_data_vec = Self::decrypt_raw_with_key(enc_data, key)?;
         bincode::deserialize::<S>(&plain_data_vec)
             .map_err(|e| anyhow!("bincode deserialize error: {}", e))
     }
 
+    /// 和`decrypt_with_key`相同，但不反序列化，返回加密前bincode序列化的数据，
+    /// 用于数据的类型已经改变、需要先转换旧格式的情况
+    pub fn decrypt_raw_with_key(
+        enc_data: &[u8],
+        key: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, anyhow::Error> {
+        let encrypt_data = Self::decode(enc_data)?;
+        encrypt_data.cipher_decrypt(key)
+    }
+
+    /// 生成随机的32字节密钥，可用于`encrypt_with_key`
+    pub fn gen_key() -> Zeroizing<Vec<u8>> {
+        let mut key = Zeroizing::new(vec![0u8; 32]);
+        OsRng.fill_bytes(&mut key);
+        key
+    }
+
+    /// 给定明文密码和salt，根据 argon2 生成32字节的密钥(使用旧版本的参数)，可用于`encrypt_with_key`
+    pub fn derive_key(passwd: &str, salt: &[u8]) -> Zeroizing<Vec<u8>> {
+        Self::gen_passwd(passwd, salt, &[], &KdfParams::legacy()).unwrap()
+    }
+
+    /// 加密数据时根据密码生成密钥所使用的 argon2 参数，使用密钥直接加密的数据返回None
+    pub fn kdf_params(enc_data: &[u8]) -> Result<Option<KdfParams>, anyhow::Error> {
+        let encrypt_data = Self::decode(enc_data)?;
+        Ok(match encrypt_data.s.is_empty() {
+            true => None,
+            false => Some(encrypt_data.k.unwrap_or_else(KdfParams::legacy)),
+        })
+    }
+
+    /// 反序列化加密数据，兼容旧版本没有保存 argon2 参数的数据
+    fn decode(enc_data: &[u8]) -> Result<Self, anyhow::Error> {
+        bincode::deserialize::<Self>(enc_data).or_else(|e| {
+            let legacy = bincode::deserialize::<LegacyEncryptData>(enc_data).map_err(|_| e)?;
+            Ok(Self {
+                s: legacy.s,
+                n: legacy.n,
+                d: legacy.d,
+                k: None,
+            })
+        })
+    }
+
     /// 生成96bit(12bytes)的nonce
     fn gen_nonce() -> [u8; 12] {
         let mut nonce = [0u8; 12];
@@ -47,51 +283,94 @@ impl EncryptData {
     }
 
     /// 生成salt，长度至少8位
-    fn gen_salt() -> [u8; 8] {
+    pub fn gen_salt() -> [u8; 8] {
         let mut salt = [0u8; 8];
         OsRng.fill_bytes(&mut salt);
         salt
     }
 
-    /// 给定明文指定的密码，根据 argon2 生成安全的指定长度的密码(hash之后的)
-    fn gen_passwd(passwd: &str, salt: &[u8]) -> Vec<u8> {
-        let config = argon2::Config {
-            hash_length: 32,
-            ..argon2::Config::default()
-        };
-        argon2::hash_raw(passwd.as_bytes(), salt, &config).unwrap()
+    /// 生成指定长度的salt，长度至少8位
+    fn gen_salt_with_len(len: u32) -> Vec<u8> {
+        let mut salt = vec![0u8; len.max(8) as usize];
+        OsRng.fill_bytes(&mut salt);
+        salt
+    }
+
+    /// 给定明文指定的密码，根据 argon2 生成安全的指定长度的密码(hash之后的)，
+    /// secret作为 argon2 的secret输入，可以为空，参数无效时(例如内存开销小于8*lanes KiB)返回Error
+    fn gen_passwd(
+        passwd: &str,
+        salt: &[u8],
+        secret: &[u8],
+        params: &KdfParams,
+    ) -> Result<Zeroizing<Vec<u8>>, anyhow::Error> {
+        argon2::hash_raw(passwd.as_bytes(), salt, &params.config(secret))
+            .map(Zeroizing::new)
+            .map_err(|e| anyhow!("invalid argon2 params {}: {}", params, e))
+    }
+
+    fn inner_encrypt(
+        data: &[u8],
+        passwd: &str,
+        secret: &[u8],
+        params: &KdfParams,
+    ) -> Result<Self, anyhow::Error> {
+        let salt = Self::gen_salt_with_len(params.salt_len);
+        let passwd_key = Self::gen_passwd(passwd, &salt, secret, params)?;
+        let mut encrypt_data = Self::cipher_encrypt(data, &passwd_key, salt)?;
+        encrypt_data.k = Some(*params);
+        Ok(encrypt_data)
     }
 
-    fn inner_encrypt(data: Vec<u8>, passwd: &str) -> Result<Self, anyhow::Error> {
-        let salt = Self::gen_salt();
-        let passwd_key = Self::gen_passwd(passwd, &salt);
+    fn inner_decrypt(
+        &self,
+        passwd: &str,
+        secret: &[u8],
+    ) -> Result<Zeroizing<Vec<u8>>, anyhow::Error> {
+        let params = self.k.unwrap_or_else(KdfParams::legacy);
+        let passwd_key = Self::gen_passwd(passwd, &self.s, secret, &params)?;
+        self.cipher_decrypt(&passwd_key)
+    }
 
-        let key = chacha20poly1305::Key::from_slice(&passwd_key);
+    /// 使用密钥加密，salt只是原样保存，用于之后根据密码重新生成密钥
+    fn cipher_encrypt(data: &[u8], key: &[u8], salt: Vec<u8>) -> Result<Self, anyhow::Error> {
+        if key.len() != 32 {
+            return Err(anyhow!("invalid key length: {}", key.len()));
+        }
+        let key = chacha20poly1305::Key::from_slice(key);
         let cipher = ChaCha20Poly1305::new(key);
 
         let nonce = Self::gen_nonce();
         let nonce = chacha20poly1305::Nonce::from_slice(&nonce);
 
         let cipher_ctx = cipher
-            .encrypt(nonce, data.as_ref())
+            .encrypt(nonce, data)
             .map_err(|e| anyhow!("encrypt error: {}", e))?;
         let encrypt_data = EncryptData {
-            s: salt.to_vec(),
+            s: salt,
             n: nonce.to_vec(),
             d: cipher_ctx,
+            k: None,
         };
         Ok(encrypt_data)
     }
 
-    fn inner_decrypt(&self, passwd: &str) -> Result<Vec<u8>, anyhow::Error> {
-        let passwd_key = Self::gen_passwd(passwd, &self.s);
-        let key = chacha20poly1305::Key::from_slice(&passwd_key);
+    /// 解密后的明文在drop时会被清零
+    fn cipher_decrypt(&self, key: &[u8]) -> Result<Zeroizing<Vec<u8>>, anyhow::Error> {
+        if key.len() != 32 {
+            return Err(anyhow!("invalid key length: {}", key.len()));
+        }
+        let key = chacha20poly1305::Key::from_slice(key);
         let cipher = ChaCha20Poly1305::new(key);
 
-        let nonce = &self.n;
-        let nonce = chacha20poly1305::Nonce::from_slice(nonce);
+        // 数据损坏时nonce的长度可能不正确，from_slice在长度不是12字节时会panic
+        if self.n.len() != 12 {
+            return Err(anyhow!("invalid nonce length: {}", self.n.len()));
+        }
+        let nonce = chacha20poly1305::Nonce::from_slice(&self.n);
         cipher
             .decrypt(nonce, self.d.as_ref())
+            .map(Zeroizing::new)
             .map_err(|e| anyhow!("decrypt error: {}", e))
     }
 }
@@ -100,7 +379,7 @@ impl EncryptData {
 mod d {
     use serde::{Deserialize, Serialize};
 
-    use crate::EncryptData;
+    use crate::{EncryptData, KdfParams, KdfVariant};
     #[derive(Debug, Serialize, Deserialize)]
     struct S {
         key: String,
@@ -122,4 +401,51 @@ mod d {
         println!("enc_str: {:?}", enc_data);
         println!("ss: {:?}", ss);
     }
+
+    #[test]
+    fn truncated_nonce() {
+        let key = EncryptData::gen_key();
+        let enc_data = EncryptData::encrypt_with_key(&"data", &key).unwrap();
+        let mut enc = EncryptData::decode(&enc_data).unwrap();
+        enc.n.truncate(8);
+        let enc_data = bincode::serialize(&enc).unwrap();
+        let err = EncryptData::decrypt_with_key::<String>(&enc_data, &key).unwrap_err();
+        assert_eq!(err.to_string(), "invalid nonce length: 8");
+    }
+
+    #[test]
+    fn secret() {
+        let enc_data = EncryptData::encrypt_with_secret(&"data", "passwd", b"keyfile").unwrap();
+        assert!(EncryptData::decrypt::<String>(&enc_data, "passwd").is_err());
+        let data = EncryptData::decrypt_with_secret::<String>(&enc_data, "passwd", b"keyfile");
+        assert_eq!(data.unwrap(), "data");
+    }
+
+    #[test]
+    fn kdf_params() {
+        let params = KdfParams {
+            variant: KdfVariant::Argon2id,
+            mem_cost: 1024,
+            time_cost: 1,
+            lanes: 2,
+            salt_len: 12,
+        };
+        let enc_data = EncryptData::encrypt_with_params(&"data", "passwd", &[], &params).unwrap();
+        assert_eq!(EncryptData::kdf_params(&enc_data).unwrap(), Some(params));
+        let data = EncryptData::decrypt::<String>(&enc_data, "passwd");
+        assert_eq!(data.unwrap(), "data");
+
+        // 旧版本的加密数据：没有保存参数，使用旧版本的参数
+        let salt = EncryptData::gen_salt();
+        let key = EncryptData::derive_key("passwd", &salt);
+        let data = bincode::serialize("data").unwrap();
+        let enc = EncryptData::cipher_encrypt(&data, &key, salt.to_vec()).unwrap();
+        let legacy = bincode::serialize(&(enc.s, enc.n, enc.d)).unwrap();
+        assert_eq!(
+            EncryptData::kdf_params(&legacy).unwrap(),
+            Some(KdfParams::legacy())
+        );
+        let data = EncryptData::decrypt::<String>(&legacy, "passwd");
+        assert_eq!(data.unwrap(), "data");
+    }
 }
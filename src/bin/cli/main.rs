@@ -1,71 +1,267 @@
+use anyhow::anyhow;
+use chrono_ext::{east8, now8, EpochToDateTimeExt};
 use clap::Parser;
 use opts::{
-    DropCmd, ExportCmd, GenPasswdCmd, ImportCmd, ImportSrcType, InsertCmd, QueryCmd, ResetCmd,
-    RmCmd,
+    AttachCmd, AttachCmds, DropCmd, ExportCmd, GenPasswdCmd, HistoryCmd, HistoryCmds,
+    HistoryPolicyCmd, ImportCmd, ImportSrcType, InitCmd, InsertCmd, KdfCmd, KdfCmds, KdfRekeyCmd,
+    KdfTuneCmd, KeyAddCmd, KeyCmd, KeyCmds, KeyKind, KindCmd, LogCmd, MigrateCmd, MvCmd, OtpCmd,
+    PickCmd, QueryCmd, ResetCmd, RmCmd, SortBy, StatusCmd, TagCmd, TagCmds, TrashCmd, TrashCmds,
+    TreeCmd, UndoCmd,
 };
 use pser::{
-    db_file::PserDB, gen_rand::gen_passwd, pser::Pser, DB_FILE_CUR, DB_FILE_HOME, PSER_MAIN_PASSWD,
+    db_file::{PserDB, ReplicaState, SyncDb},
+    entry::{this_month, ApiToken, Card, EntryKind, Identity, Note, SshKey},
+    error::{Error, PserResult},
+    filter::Filter,
+    gen_rand::{gen_keyfile, gen_passwd, gen_recovery_code},
+    journal::Operation,
+    migrate::{self, FORMAT_VERSION},
+    pser::{HistoryPolicy, Pser},
+    vault_paths::VaultPaths,
+    verify_header::{Credential, SlotKind},
+    KdfParams,
 };
-use std::{collections::HashMap, io::Read};
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{IsTerminal, Read, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+use zeroize::{Zeroize, Zeroizing};
 
 pub mod opts;
 
+/// 通过`--keyfile`选项或 PSER_KEYFILE 环境变量指定的密钥文件
+static KEYFILE: OnceLock<PathBuf> = OnceLock::new();
+
 fn main() {
     // add_test_psers();
     // std::process::exit(0);
 
     let opts = opts::Opts::parse();
+    let paths = VaultPaths::resolve(opts.vault.as_deref()).unwrap_or_else(|e| {
+        eprintln!("无法确定密码库位置: {}", e);
+        std::process::exit(1);
+    });
+    if let Some(keyfile) = opts
+        .keyfile
+        .or_else(|| std::env::var_os("PSER_KEYFILE").map(PathBuf::from))
+    {
+        let _ = KEYFILE.set(keyfile);
+    }
     match opts.cmds {
-        opts::Cmds::Init => init(),
-        opts::Cmds::Query(opt) => query(&opt),
-        opts::Cmds::Insert(opt) => add_passwd(&opt),
-        opts::Cmds::Rm(opt) => remove_passwd(&opt),
-        opts::Cmds::Drop(opt) => drop_pser_file(&opt),
-        opts::Cmds::Reset(opt) => reset_main_passwd(&opt),
+        opts::Cmds::Init(opt) => init(&paths, &opt),
+        opts::Cmds::Query(opt) => query(&paths, &opt),
+        opts::Cmds::Pick(opt) => pick(&paths, &opt),
+        opts::Cmds::Insert(opt) => add_passwd(&paths, &opt),
+        opts::Cmds::Rm(opt) => remove_passwd(&paths, &opt),
+        opts::Cmds::Drop(opt) => drop_pser_file(&paths, &opt),
+        opts::Cmds::Reset(opt) => reset_main_passwd(&paths, &opt),
         opts::Cmds::Gen(opt) => {
             let passwds = gen(&opt);
             if !passwds.is_empty() {
                 println!("{}", passwds.join("\n"));
             }
         }
-        opts::Cmds::Import(opt) => import(&opt),
-        opts::Cmds::Export(opt) => export(&opt),
-        opts::Cmds::Path => {
-            println!("the pser password file path:");
-            println!("  1.{}", DB_FILE_HOME.as_os_str().to_str().unwrap());
-            println!("  2.{}", DB_FILE_CUR.as_os_str().to_str().unwrap());
-            println!("these files maybe not exists");
-        }
+        opts::Cmds::Import(opt) => import(&paths, &opt),
+        opts::Cmds::Export(opt) => export(&paths, &opt),
+        opts::Cmds::Path => show_path(&paths),
+        opts::Cmds::Key(opt) => key(&paths, &opt),
+        opts::Cmds::Status(opt) => status(&paths, &opt),
+        opts::Cmds::Kdf(opt) => kdf(&paths, &opt),
+        opts::Cmds::Migrate(opt) => migrate(&paths, &opt),
+        opts::Cmds::Otp(opt) => otp(&paths, &opt),
+        opts::Cmds::Attach(opt) => attach(&paths, &opt),
+        opts::Cmds::Tree(opt) => tree(&paths, &opt),
+        opts::Cmds::Mv(opt) => move_psers(&paths, &opt),
+        opts::Cmds::Tag(opt) => tag(&paths, &opt),
+        opts::Cmds::History(opt) => history(&paths, &opt),
+        opts::Cmds::Log(opt) => log(&paths, &opt),
+        opts::Cmds::Undo(opt) => undo(&paths, &opt),
+        opts::Cmds::Trash(opt) => trash(&paths, &opt),
+    }
+}
+
+/// 各类错误对应的退出码(2为clap的参数错误)
+fn exit_code(e: &Error) -> i32 {
+    match e {
+        Error::WrongPassword => 3,
+        Error::RateLimited { .. } => 4,
+        Error::Corrupt(_) | Error::DecodeError(_) => 6,
+        Error::InvalidUrl(_) => 7,
+        Error::InvalidQuery(_) => 8,
+        Error::StaleReplicas { .. } => 9,
+        _ => 1,
     }
 }
 
-/// 将尝试先读取 PSER_PASSWD 环境变量，如果没有设置该环境变量，将交互式提示输入密码
-fn prompt_password(prompt_msg: &str) -> String {
-    match PSER_MAIN_PASSWD.is_empty() {
+/// 输出错误信息，并以该错误对应的退出码退出
+fn exit_with(e: Error) -> ! {
+    eprintln!("{}", e);
+    std::process::exit(exit_code(&e))
+}
+
+/// 代替`unwrap`：出错时输出错误信息，并以该错误对应的退出码退出
+trait OrExit<T> {
+    fn or_exit(self) -> T;
+}
+
+impl<T> OrExit<T> for PserResult<T> {
+    fn or_exit(self) -> T {
+        self.unwrap_or_else(|e| exit_with(e))
+    }
+}
+
+/// 输出当前生效的各密码库副本的位置及其状态，第一个为主密码库
+fn show_path(paths: &VaultPaths) {
+    println!("the pser password file path:");
+    for (i, (path, state)) in SyncDb::inspect(paths).iter().enumerate() {
+        println!("  {}.{} ({})", i + 1, path.display(), state);
+    }
+}
+
+/// 将尝试先读取 PSER_PASSWD 环境变量，如果没有设置该环境变量，将交互式提示输入密码，
+/// allow_empty为true时允许直接回车(输入空密码)，返回的密码在drop时清零
+fn prompt_password(prompt_msg: &str, allow_empty: bool) -> Zeroizing<String> {
+    let env_passwd = Zeroizing::new(std::env::var("PSER_MAIN_PASSWD").unwrap_or_default());
+    match env_passwd.is_empty() {
         true => {
             let password = dialoguer::Password::new()
                 .with_prompt(prompt_msg)
+                .allow_empty_password(allow_empty)
                 .interact()
                 .unwrap();
 
-            password
+            Zeroizing::new(password)
 
             // let p = rpassword::prompt_password(format!("{}: ", prompt_msg)).unwrap();
             // println!();
             // p
         }
-        false => PSER_MAIN_PASSWD.clone(),
+        false => env_passwd,
+    }
+}
+
+/// 打开密码库所用的凭据：密码(见`prompt_password`)，以及指定了密钥文件时的密钥文件
+///
+/// 指定了密钥文件时允许输入空密码，即只使用密钥文件
+fn credential(prompt_msg: &str) -> Credential {
+    match KEYFILE.get() {
+        Some(f) => {
+            let passwd =
+                prompt_password(&format!("{}(只使用密钥文件时直接回车)", prompt_msg), true);
+            let cred = match passwd.is_empty() {
+                true => Credential::default(),
+                false => Credential::passwd(&passwd),
+            };
+            cred.with_keyfile(read_keyfile(f))
+        }
+        None => Credential::passwd(&prompt_password(prompt_msg, false)),
     }
 }
 
+/// 读取密钥文件，读取失败时退出
+fn read_keyfile(f: &Path) -> Vec<u8> {
+    std::fs::read(f).unwrap_or_else(|e| {
+        exit_with(Error::Other(anyhow!(
+            "无法读取密钥文件{}: {}",
+            f.display(),
+            e
+        )))
+    })
+}
+
 /// 检查密码库是否存在
-fn pser_lib_exists() -> bool {
-    DB_FILE_HOME.exists() || DB_FILE_CUR.exists()
+fn pser_lib_exists(paths: &VaultPaths) -> bool {
+    paths.exists()
 }
 
-fn init() {
-    let main_passwd = prompt_password("输入主密码");
-    PserDB::new(&main_passwd).unwrap();
+/// 打开密码库，提示各个不正常的副本，并交互式解决合并副本时产生的冲突
+fn open_db(paths: &VaultPaths, cred: &Credential) -> PserDB {
+    let mut db = PserDB::open(paths, cred).or_exit();
+    let report = db.unlock_report();
+    if report.tampered {
+        eprintln!("警告: 解锁失败记录或锁定策略被篡改过，锁定策略已恢复为默认值");
+    }
+    if let Some(last) = report.failed.last() {
+        eprintln!(
+            "警告: 上次成功解锁之后有{}次解锁失败，最近一次在{}(使用status子命令查看详情)",
+            report.failures,
+            last.secs_to_dt(east8())
+        );
+    }
+    for (path, state) in db.replicas() {
+        if *state != ReplicaState::Healthy {
+            eprintln!("密码库副本{}: {}", path.display(), state);
+        }
+    }
+    resolve_conflicts(&mut db);
+    db
+}
+
+/// 合并副本时，同一条密码信息在两个副本中的修改时间相同但内容不同，由用户决定保留哪个版本
+fn resolve_conflicts(db: &mut PserDB) {
+    let conflicts = db.conflicts().to_vec();
+    for c in conflicts {
+        println!("合并副本{}时发生冲突:", c.replica.display());
+        if let Some(ours) = db.get_pser(&c.uuid) {
+            println!(
+                "[当前版本]\n{}",
+                ours.verical_display(Some(&c.uuid), false).as_str()
+            );
+        }
+        println!(
+            "[副本版本]\n{}",
+            c.theirs.verical_display(Some(&c.uuid), false).as_str()
+        );
+
+        let choice = dialoguer::Select::new()
+            .with_prompt("保留哪个版本?")
+            .items(&["当前版本", "副本版本"])
+            .default(0)
+            .interact()
+            .unwrap();
+        if choice == 1 {
+            db.update(&c.uuid, c.theirs).or_exit();
+        }
+    }
+}
+
+fn init(paths: &VaultPaths, opt: &InitCmd) {
+    let Some(keyfile) = &opt.gen_keyfile else {
+        open_db(paths, &credential("输入主密码"));
+        return;
+    };
+
+    if pser_lib_exists(paths) {
+        println!("密码库已存在，可使用`reset --new-keyfile`为主密码添加密钥文件");
+        return;
+    }
+    if let Err(e) = write_keyfile(keyfile) {
+        eprintln!("无法创建密钥文件{}: {}", keyfile.display(), e);
+        std::process::exit(1);
+    }
+    let cred = Credential::passwd(&prompt_password("输入主密码", false));
+    open_db(paths, &cred.with_keyfile(read_keyfile(keyfile)));
+    println!(
+        "已生成密钥文件{}，之后需要同时提供主密码和该密钥文件(--keyfile或PSER_KEYFILE)才能打开密码库，请妥善备份",
+        keyfile.display()
+    );
+}
+
+/// 生成随机密钥文件，文件已存在时返回错误，unix下只有当前用户可读写该文件
+fn write_keyfile(file: &Path) -> std::io::Result<()> {
+    write_private_file(file, &gen_keyfile())
+}
+
+/// 写入新文件，文件已存在时返回错误，unix下只有当前用户可读写该文件
+fn write_private_file(file: &Path, data: &[u8]) -> std::io::Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    options.open(file)?.write_all(data)
 }
 
 fn gen(opt: &GenPasswdCmd) -> Vec<String> {
@@ -79,106 +275,785 @@ fn gen(opt: &GenPasswdCmd) -> Vec<String> {
         .collect::<Vec<String>>()
 }
 
-fn reset_main_passwd(opt: &ResetCmd) {
-    if !pser_lib_exists() {
+fn reset_main_passwd(paths: &VaultPaths, opt: &ResetCmd) {
+    if !pser_lib_exists(paths) {
         println!("密码库不存在");
         return;
     }
 
-    let main_passwd = prompt_password("输入旧的主密码");
-    let mut db = PserDB::new(&main_passwd).unwrap();
-    db.change_passwd(&opt.new_passwd).unwrap();
+    let mut db = open_db(paths, &credential("输入旧的主密码"));
+    let res = match (&opt.new_keyfile, opt.no_keyfile) {
+        (Some(f), _) => db.change_main_credential(
+            &Credential::passwd(&opt.new_passwd).with_keyfile(read_keyfile(f)),
+        ),
+        (None, true) => db.change_main_credential(&Credential::passwd(&opt.new_passwd)),
+        (None, false) => db.change_passwd(&opt.new_passwd),
+    };
+    if let Err(e) = res {
+        exit_with(e);
+    }
 }
 
-fn remove_passwd(opt: &RmCmd) {
-    if !pser_lib_exists() {
+fn remove_passwd(paths: &VaultPaths, opt: &RmCmd) {
+    if !pser_lib_exists(paths) {
         println!("密码库不存在");
         return;
     }
 
-    let main_passwd = prompt_password("输入主密码");
+    let mut db = open_db(paths, &credential("输入主密码"));
+    if let Some(folder) = &opt.folder {
+        let uuids = select_uuids(&db, None, Some(folder));
+        if uuids.is_empty() {
+            println!("文件夹{}中没有密码信息", folder);
+            return;
+        }
+        println!("将删除文件夹{}中的{}条密码信息", folder, uuids.len());
+        if yes_dialog() {
+            db.remove_many(&uuids).or_exit();
+            println!("已将{}条密码信息移到回收站", uuids.len());
+        } else {
+            println!("放弃删除密码信息");
+        }
+        return;
+    }
 
-    let mut db = PserDB::new(&main_passwd).unwrap();
     // 如果是all，则清空所有密码信息
-    if opt.uuid.eq_ignore_ascii_case("all") {
+    let uuid = opt.uuid.as_deref().unwrap_or_default();
+    if uuid.eq_ignore_ascii_case("all") {
         if yes_dialog() {
-            db.clear().unwrap();
+            db.clear().or_exit();
+            println!("已将所有密码信息移到回收站");
         } else {
             println!("放弃清空密码信息");
         }
         return;
     }
 
-    for uuid_prefix in opt.uuid.split(',') {
-        let uuids = db.uuid_by_prefix(uuid_prefix);
-        match uuids.len() {
-            1 => db.remove(&uuids[0]).unwrap(),
-            0 => println!("Uuid({})不存在", uuid_prefix),
-            _ => println!("Uuid({})指定位数过少产生歧义", uuid_prefix),
+    let uuids = select_uuids(&db, Some(uuid), None);
+    db.remove_many(&uuids).or_exit();
+    println!("已将{}条密码信息移到回收站", uuids.len());
+}
+
+/// 根据逗号分隔的UUID(前缀)或文件夹(包括其子文件夹)选择密码信息，
+/// 产生歧义的UUID(前缀)将交互式选择，不存在或无法选择时输出提示后跳过
+fn select_uuids(db: &PserDB, uuid: Option<&str>, folder: Option<&str>) -> Vec<String> {
+    if let Some(folder) = folder {
+        return db
+            .all_pser()
+            .into_iter()
+            .filter(|(_, pser)| pser.in_folder(folder))
+            .map(|(uuid, _)| uuid.to_string())
+            .collect();
+    }
+
+    let mut selected = vec![];
+    for uuid_prefix in uuid.unwrap_or_default().split(',') {
+        selected.extend(find_uuid(db, uuid_prefix));
+    }
+    selected
+}
+
+/// 以树状结构列出各文件夹中的密码信息(不包含密码)
+fn tree(paths: &VaultPaths, opt: &TreeCmd) {
+    if !pser_lib_exists(paths) {
+        println!("密码库不存在");
+        return;
+    }
+    let db = open_db(paths, &credential("输入主密码"));
+
+    let folder = opt.folder.as_deref().unwrap_or_default();
+    let mut root = FolderNode::default();
+    for (uuid, pser) in db.all_pser() {
+        if !pser.in_folder(folder) {
+            continue;
+        }
+        let mut node = &mut root;
+        for name in pser.folder.split('/').filter(|s| !s.is_empty()) {
+            node = node.children.entry(name).or_default();
         }
+        let name = pser.title();
+        let short_uuid = uuid.get(..8).unwrap_or(uuid);
+        node.psers
+            .push(format!("{} {} {}", name, pser.username, short_uuid));
     }
+    root.print(0);
 }
 
-/// 不做任何密码验证，直接删除密码库文件
-fn drop_pser_file(opt: &DropCmd) {
-    if yes_dialog() {
-        if opt.main {
-            let _ = std::fs::remove_file(&*DB_FILE_HOME);
+/// `tree`子命令中的一个文件夹
+#[derive(Default)]
+struct FolderNode<'a> {
+    /// 子文件夹，按名称排序
+    children: BTreeMap<&'a str, FolderNode<'a>>,
+    /// 直接位于该文件夹中的密码信息
+    psers: Vec<String>,
+}
+
+impl FolderNode<'_> {
+    /// 该文件夹(包括其子文件夹)中的密码信息数量
+    fn count(&self) -> usize {
+        self.psers.len() + self.children.values().map(Self::count).sum::<usize>()
+    }
+
+    /// 先输出子文件夹，再输出直接位于该文件夹中的密码信息，每深一级缩进两个空格
+    fn print(&mut self, depth: usize) {
+        let indent = "  ".repeat(depth);
+        for (name, child) in self.children.iter_mut() {
+            println!("{}{}/ ({})", indent, name, child.count());
+            child.print(depth + 1);
+        }
+        self.psers.sort();
+        for pser in &self.psers {
+            println!("{}{}", indent, pser);
+        }
+    }
+}
+
+/// 将密码信息或整个文件夹移动到另一个文件夹
+fn move_psers(paths: &VaultPaths, opt: &MvCmd) {
+    if !pser_lib_exists(paths) {
+        println!("密码库不存在");
+        return;
+    }
+    let mut db = open_db(paths, &credential("输入主密码"));
+
+    let uuids = select_uuids(&db, opt.uuid.as_deref(), opt.folder.as_deref());
+    let mut psers = vec![];
+    for uuid in uuids {
+        let Some(mut pser) = db.get_pser(&uuid).cloned() else {
+            continue;
+        };
+        match &opt.folder {
+            Some(from) => pser.move_folder(from, &opt.dest),
+            None => pser.set_folder(&opt.dest),
+        };
+        psers.push((uuid, pser));
+    }
+    let moved = psers.len();
+    db.update_many(psers).or_exit();
+    println!("已移动{}条密码信息", moved);
+}
+
+/// 管理标签
+fn tag(paths: &VaultPaths, opt: &TagCmd) {
+    if !pser_lib_exists(paths) {
+        println!("密码库不存在");
+        return;
+    }
+    let mut db = open_db(paths, &credential("输入主密码"));
+
+    let (edit, add) = match &opt.cmds {
+        TagCmds::List => {
+            let mut counts: BTreeMap<&str, usize> = BTreeMap::new();
+            for (_, pser) in db.all_pser() {
+                for tag in &pser.tags {
+                    *counts.entry(tag).or_default() += 1;
+                }
+            }
+            for (tag, count) in counts {
+                println!("{}  {}", tag, count);
+            }
+            return;
+        }
+        TagCmds::Add(edit) => (edit, true),
+        TagCmds::Rm(edit) => (edit, false),
+    };
+
+    // 只修改标签确实发生变化的密码信息
+    let uuids = select_uuids(&db, edit.uuid.as_deref(), edit.folder.as_deref());
+    let mut psers = vec![];
+    for uuid in uuids {
+        let Some(mut pser) = db.get_pser(&uuid).cloned() else {
+            continue;
+        };
+        if pser.has_tag(&edit.tag) == add {
+            continue;
+        }
+        match add {
+            true => pser.add_tag(&edit.tag),
+            false => pser.remove_tag(&edit.tag),
+        };
+        psers.push((uuid, pser));
+    }
+    let changed = psers.len();
+    db.update_many(psers).or_exit();
+    println!("已修改{}条密码信息的标签", changed);
+}
+
+/// 查看、恢复旧密码，设置旧密码的保留策略
+fn history(paths: &VaultPaths, opt: &HistoryCmd) {
+    if !pser_lib_exists(paths) {
+        println!("密码库不存在");
+        return;
+    }
+    let mut db = open_db(paths, &credential("输入主密码"));
+
+    match &opt.cmds {
+        None => {
+            let Some(uuid) = opt.uuid.as_deref().and_then(|p| find_uuid(&db, p)) else {
+                return;
+            };
+            let pser = db.get_pser(&uuid).unwrap();
+            let history = pser.history_passwds();
+            if history.is_empty() {
+                println!("没有旧密码");
+            }
+            for (i, (changed, passwd)) in history.iter().enumerate() {
+                println!("{:>3}. {}  {}", i + 1, changed, passwd.as_str());
+            }
+            match &pser.history_policy {
+                Some(policy) => println!("保留策略: {}", policy),
+                None => println!("保留策略(密码库): {}", db.history_policy()),
+            }
+        }
+        Some(HistoryCmds::Restore(opt)) => {
+            let Some(uuid) = find_uuid(&db, &opt.uuid) else {
+                return;
+            };
+            let mut pser = db.get_pser(&uuid).unwrap().clone();
+            pser.restore_passwd(opt.n).or_exit();
+            db.update(&uuid, pser).or_exit();
+            println!("已恢复第{}个旧密码", opt.n);
         }
+        Some(HistoryCmds::Policy(opt)) => history_policy(&mut db, opt),
+    }
+}
 
-        if opt.secondary {
-            let _ = std::fs::remove_file(&*DB_FILE_CUR);
+/// 查看或设置密码库(或某条密码信息)的旧密码保留策略
+fn history_policy(db: &mut PserDB, opt: &HistoryPolicyCmd) {
+    let show = opt.keep.is_none() && opt.days.is_none() && !opt.unlimited && !opt.inherit;
+    let policy = HistoryPolicy {
+        keep: opt.keep,
+        max_days: opt.days,
+    };
+
+    let Some(prefix) = &opt.uuid else {
+        if !show {
+            let pruned = db.set_history_policy(policy).or_exit();
+            println!("已删除{}个过期的旧密码", pruned);
         }
+        println!("保留策略(密码库): {}", db.history_policy());
+        return;
+    };
+
+    let Some(uuid) = find_uuid(db, prefix) else {
+        return;
+    };
+    if !show {
+        let mut pser = db.get_pser(&uuid).unwrap().clone();
+        pser.set_history_policy(match opt.inherit {
+            true => None,
+            false => Some(policy),
+        });
+        db.update(&uuid, pser).or_exit();
+    }
+    match &db.get_pser(&uuid).unwrap().history_policy {
+        Some(policy) => println!("保留策略: {}", policy),
+        None => println!("保留策略(密码库): {}", db.history_policy()),
     }
 }
 
-fn query(opt: &QueryCmd) {
-    if !pser_lib_exists() {
+/// 查看或清空操作日志
+fn log(paths: &VaultPaths, opt: &LogCmd) {
+    if !pser_lib_exists(paths) {
         println!("密码库不存在");
         return;
     }
-    let main_passwd = prompt_password("输入主密码");
-    let db = PserDB::new(&main_passwd).unwrap();
+    let mut db = open_db(paths, &credential("输入主密码"));
+    if opt.clear {
+        println!("清空后将无法撤销之前的操作，也无法查看密码信息之前的版本");
+        if yes_dialog() {
+            db.clear_journal().or_exit();
+        }
+        return;
+    }
 
-    // 为None，表示列出密码库中所有信息，而不是搜索
-    let psers = match &opt.str {
-        Some(str) => db.query(str),
-        None => db.all_pser(),
+    let journal = db.journal().or_exit();
+    let Some(prefix) = &opt.uuid else {
+        for op in journal.iter().rev().take(opt.num) {
+            println!("{}", operation_line(op));
+        }
+        return;
     };
 
-    let iter = psers.into_iter();
-    let mut s: Vec<String> = match opt.short {
-        true => iter
-            .map(|(uuid, pser)| pser.simple_display(Some(uuid)))
-            .collect(),
-        false => iter
-            .map(|(uuid, pser)| pser.verical_display(Some(uuid)))
+    // 已删除的密码信息也可以查看，因此在操作日志中查找UUID
+    let mut uuids: Vec<&str> = journal
+        .iter()
+        .flat_map(|op| op.changes.iter().map(|c| c.uuid.as_str()))
+        .filter(|uuid| uuid.starts_with(prefix.as_str()))
+        .collect();
+    uuids.sort();
+    uuids.dedup();
+    let picked;
+    let uuid = match uuids[..] {
+        [uuid] => uuid,
+        [] => {
+            println!("操作日志中没有Uuid({})", prefix);
+            return;
+        }
+        _ => {
+            // 在各密码信息最后的版本中选择
+            let latest: Vec<(&String, &Pser)> = uuids
+                .iter()
+                .filter_map(|uuid| {
+                    let mut changes = journal.iter().rev().flat_map(|op| &op.changes);
+                    let c = changes.find(|c| c.uuid == *uuid)?;
+                    Some((&c.uuid, c.after.as_ref().or(c.before.as_ref())?))
+                })
+                .collect();
+            picked = pick_ambiguous(prefix, &latest);
+            match &picked {
+                Some(uuid) => uuid.as_str(),
+                None => return,
+            }
+        }
+    };
+
+    let changes = journal
+        .iter()
+        .rev()
+        .flat_map(|op| op.changes.iter().map(move |c| (op, c)))
+        .filter(|(_, c)| c.uuid == uuid);
+    for (op, change) in changes.take(opt.num) {
+        let time = op.time.secs_to_dt(east8());
+        match &change.after {
+            Some(pser) => {
+                println!("#{} {} {} 第{}版", op.seq, time, op.desc, pser.revision);
+                if opt.reveal {
+                    print!("{}", pser.verical_display(None, true).as_str());
+                }
+            }
+            None => println!("#{} {} {} (已删除)", op.seq, time, op.desc),
+        }
+    }
+}
+
+/// 一次操作的单行描述
+fn operation_line(op: &Operation) -> String {
+    let mut line = format!("#{} {} {}", op.seq, op.time.secs_to_dt(east8()), op.desc);
+    if !op.undoes.is_empty() {
+        let undoes: Vec<String> = op.undoes.iter().map(|seq| format!("#{}", seq)).collect();
+        line.push_str(&format!("({})", undoes.join(",")));
+    }
+    match &op.changes[..] {
+        [change] => {
+            let pser = change.after.as_ref().or(change.before.as_ref());
+            if let Some(pser) = pser {
+                let short_uuid = change.uuid.get(..8).unwrap_or(&change.uuid);
+                line.push_str(&format!(
+                    " {} {} {}",
+                    pser.title(),
+                    pser.username,
+                    short_uuid
+                ));
+            }
+        }
+        changes => line.push_str(&format!(" {}条密码信息", changes.len())),
+    }
+    line
+}
+
+/// 撤销最近的操作
+fn undo(paths: &VaultPaths, opt: &UndoCmd) {
+    if !pser_lib_exists(paths) {
+        println!("密码库不存在");
+        return;
+    }
+    let mut db = open_db(paths, &credential("输入主密码"));
+    let ops = db.undo(opt.n).or_exit();
+    if ops.is_empty() {
+        println!("没有可撤销的操作");
+    }
+    for op in &ops {
+        println!("已撤销 {}", operation_line(op));
+    }
+}
+
+/// 管理回收站
+fn trash(paths: &VaultPaths, opt: &TrashCmd) {
+    if !pser_lib_exists(paths) {
+        println!("密码库不存在");
+        return;
+    }
+    let mut db = open_db(paths, &credential("输入主密码"));
+
+    match &opt.cmds {
+        TrashCmds::List => {
+            let mut trash = db.trash();
+            trash.sort_by_key(|(_, t)| -t.deleted);
+            for (uuid, t) in trash {
+                println!(
+                    "{} {} {} {}",
+                    uuid,
+                    t.deleted.secs_to_dt(east8()),
+                    t.pser.title(),
+                    t.pser.username
+                );
+            }
+        }
+        TrashCmds::Restore { uuid } => {
+            let trashed: Vec<&String> = db.trash().into_iter().map(|(uuid, _)| uuid).collect();
+            let mut uuids = vec![];
+            match uuid.eq_ignore_ascii_case("all") {
+                true => uuids.extend(trashed.into_iter().cloned()),
+                false => {
+                    for prefix in uuid.split(',').map(str::trim).filter(|s| !s.is_empty()) {
+                        let trash = db.trash().into_iter().map(|(u, t)| (u, &t.pser));
+                        let matched = match_prefix(prefix, trash);
+                        match matched[..] {
+                            [(uuid, _)] => uuids.push(uuid.to_string()),
+                            [] => println!("回收站中没有Uuid({})", prefix),
+                            _ => uuids.extend(pick_ambiguous(prefix, &matched)),
+                        }
+                    }
+                }
+            }
+            db.restore_trashed(&uuids).or_exit();
+            println!("已恢复{}条密码信息", uuids.len());
+        }
+        TrashCmds::Empty => {
+            println!(
+                "回收站中的{}条密码信息及其附件将被永久删除，操作日志中它们的所有版本(包括旧密码)也将被删除",
+                db.trash().len()
+            );
+            if yes_dialog() {
+                let n = db.empty_trash().or_exit();
+                println!("已删除{}条密码信息", n);
+            }
+        }
+    }
+}
+
+/// 根据UUID前缀找到唯一的一条密码信息，有歧义时交互式选择，不存在或无法选择时输出提示并返回None
+fn find_uuid(db: &PserDB, uuid_prefix: &str) -> Option<String> {
+    let matched = match_prefix(uuid_prefix, db.all_pser());
+    match matched[..] {
+        [(uuid, _)] => Some(uuid.to_string()),
+        [] => {
+            println!("Uuid({})不存在", uuid_prefix);
+            None
+        }
+        _ => pick_ambiguous(uuid_prefix, &matched),
+    }
+}
+
+/// 在candidates中查找uuid以prefix开头的密码信息，按uuid排序
+///
+/// prefix就是某个完整的uuid时只返回该密码信息(导入的uuid长度可能不同，完整的uuid也可能是另一个uuid的前缀)
+fn match_prefix<'a>(
+    prefix: &str,
+    candidates: impl IntoIterator<Item = (&'a String, &'a Pser)>,
+) -> Vec<(&'a String, &'a Pser)> {
+    let mut matched: Vec<_> = candidates
+        .into_iter()
+        .filter(|(uuid, _)| uuid.starts_with(prefix))
+        .collect();
+    if let Some(exact) = matched.iter().find(|(uuid, _)| *uuid == prefix) {
+        return vec![*exact];
+    }
+    matched.sort_by_key(|(uuid, _)| *uuid);
+    matched
+}
+
+/// 各uuid对应的密码信息
+fn candidates<'a>(db: &'a PserDB, uuids: &'a [String]) -> Vec<(&'a String, &'a Pser)> {
+    uuids
+        .iter()
+        .filter_map(|uuid| Some((uuid, db.get_pser(uuid)?)))
+        .collect()
+}
+
+/// 在多条密码信息中交互式模糊选择一条，返回其uuid，取消选择(Esc)时返回None，
+/// 无法交互(例如不在终端中)时返回Err
+fn pick_one(candidates: &[(&String, &Pser)]) -> dialoguer::Result<Option<String>> {
+    // 标准输入不是终端时FuzzySelect无法读取按键
+    require_terminal(std::io::stdin().is_terminal(), "标准输入")?;
+    let items: Vec<String> = candidates
+        .iter()
+        .map(|(uuid, pser)| {
+            let short = uuid.get(..8).unwrap_or(uuid);
+            format!("{}  {}  {}", short, pser.title(), pser.username)
+        })
+        .collect();
+    let picked = dialoguer::FuzzySelect::new()
+        .with_prompt("选择密码信息(输入字符过滤，Esc取消)")
+        .items(&items)
+        .default(0)
+        .interact_opt()?;
+    Ok(picked.map(|i| candidates[i].0.to_string()))
+}
+
+/// 需要和终端交互时，is_terminal为false(name为标准输入或标准输出)返回Err
+fn require_terminal(is_terminal: bool, name: &str) -> std::io::Result<()> {
+    match is_terminal {
+        true => Ok(()),
+        false => Err(std::io::Error::new(
+            std::io::ErrorKind::NotConnected,
+            format!("{}不是终端", name),
+        )),
+    }
+}
+
+/// UUID前缀有歧义时，在匹配的密码信息中交互式选择一条，无法交互时输出提示并返回None
+fn pick_ambiguous(uuid_prefix: &str, candidates: &[(&String, &Pser)]) -> Option<String> {
+    pick_one(candidates).unwrap_or_else(|_| {
+        println!("Uuid({})指定位数过少产生歧义", uuid_prefix);
+        None
+    })
+}
+
+/// 交互式选择一条密码信息，然后对它执行选择的操作
+fn pick(paths: &VaultPaths, opt: &PickCmd) {
+    if !pser_lib_exists(paths) {
+        println!("密码库不存在");
+        return;
+    }
+    let filter = opt.str.as_deref().map(|s| Filter::parse(s).or_exit());
+    let mut db = open_db(paths, &credential("输入主密码"));
+
+    // 搜索时按相关度排序，否则按标题排序
+    let candidates: Vec<(&String, &Pser)> = match &filter {
+        Some(filter) => db
+            .rank(filter)
+            .into_iter()
+            .map(|(u, p, _)| (u, p))
             .collect(),
+        None => {
+            let mut all = db.all_pser();
+            all.sort_by(|(ua, a), (ub, b)| a.title().cmp(b.title()).then_with(|| ua.cmp(ub)));
+            all
+        }
+    };
+    let uuid = match candidates[..] {
+        [] => {
+            println!("没有找到密码信息");
+            return;
+        }
+        [(uuid, _)] => uuid.to_string(),
+        _ => match pick_one(&candidates) {
+            Ok(Some(uuid)) => uuid,
+            Ok(None) => return,
+            Err(e) => {
+                eprintln!("无法交互式选择: {}", e);
+                std::process::exit(1);
+            }
+        },
+    };
+    let mut pser = db.get_pser(&uuid).unwrap().clone();
+
+    let mut actions = vec!["显示", "复制密码", "复制用户名", "修改", "删除"];
+    if pser.otp.is_some() {
+        actions.push("一次性密码");
+    }
+    let action = dialoguer::Select::new()
+        .with_prompt(format!("{} {}", pser.title(), pser.username))
+        .items(&actions)
+        .default(0)
+        .interact_opt()
+        .unwrap();
+    match action.map(|i| actions[i]) {
+        Some("显示") => {
+            println!("{}", pser.verical_display(Some(&uuid), false).as_str());
+            db.mark_used(&[uuid]).or_exit();
+        }
+        Some("复制密码") => {
+            copy_to_clipboard(&pser.passwd).unwrap_or_else(|e| exit_with(e.into()));
+            println!("已复制密码到剪贴板");
+            db.mark_used(&[uuid]).or_exit();
+        }
+        Some("复制用户名") => {
+            copy_to_clipboard(&pser.username).unwrap_or_else(|e| exit_with(e.into()));
+            println!("已复制用户名到剪贴板");
+        }
+        Some("修改") if edit_pser(&mut pser) => {
+            db.update(&uuid, pser).or_exit();
+            println!("已修改");
+        }
+        Some("删除") => {
+            println!("{}", pser.verical_display(Some(&uuid), false).as_str());
+            if yes_dialog() {
+                db.remove_many(&[uuid]).or_exit();
+                println!("已将1条密码信息移到回收站");
+            }
+        }
+        Some("一次性密码") => show_otp(&mut db, &uuid),
+        _ => {}
+    }
+}
+
+/// 使用终端的OSC 52转义序列将text写入系统剪贴板，需要终端支持，
+/// 标准输出不是终端(例如被重定向到文件)时返回Err，以免将密码写入文件
+fn copy_to_clipboard(text: &str) -> std::io::Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    require_terminal(stdout.is_terminal(), "标准输出")?;
+    let encoded = Zeroizing::new(data_encoding::BASE64.encode(text.as_bytes()));
+    write!(stdout, "\x1b]52;c;{}\x07", encoded.as_str())?;
+    stdout.flush()
+}
+
+/// 交互式修改pser的一个字段，返回是否修改了
+fn edit_pser(pser: &mut Pser) -> bool {
+    let fields = [
+        "用户名(username)",
+        "URL(url，逗号分隔)",
+        "描述(desc)",
+        "邮箱(email，逗号分隔)",
+        "联系方式(phone，逗号分隔)",
+        "密码(passwd)",
+        "备注(comment)",
+    ];
+    let Some(choice) = dialoguer::Select::new()
+        .with_prompt("修改哪个字段?")
+        .items(&fields)
+        .default(0)
+        .interact_opt()
+        .unwrap()
+    else {
+        return false;
     };
-    s.sort();
-    println!("{}", s.join("\n"));
+
+    if choice == 5 {
+        let passwd = dialoguer::Password::new()
+            .with_prompt("输入新密码")
+            .with_confirmation("再次输入新密码", "两次输入的密码不一致")
+            .interact()
+            .map(Zeroizing::new)
+            .unwrap();
+        pser.set_passwd(&passwd);
+        return true;
+    }
+
+    let current = match choice {
+        0 => pser.username.clone(),
+        1 => pser.url.join(","),
+        2 => pser.desc.clone(),
+        3 => pser.email.join(","),
+        4 => pser.phone.join(","),
+        _ => pser.comment.clone(),
+    };
+    let value: String = dialoguer::Input::new()
+        .with_prompt(fields[choice])
+        .with_initial_text(current)
+        .allow_empty(true)
+        .interact_text()
+        .unwrap();
+    match choice {
+        0 => pser.set_username(&value),
+        1 => {
+            pser.url.clear();
+            for url in value.split(',').map(str::trim).filter(|u| !u.is_empty()) {
+                pser.add_url(url).or_exit();
+            }
+            pser
+        }
+        2 => pser.set_desc(&value),
+        3 => pser.set_email(&value),
+        4 => pser.set_phone(&value),
+        _ => pser.set_comment(&value),
+    };
+    true
+}
+
+/// 不做任何密码验证，直接删除密码库文件
+fn drop_pser_file(paths: &VaultPaths, opt: &DropCmd) {
+    if yes_dialog() {
+        for (i, path) in paths.replicas().iter().enumerate() {
+            let selected = match i {
+                0 => opt.main,
+                _ => opt.secondary,
+            };
+            if selected || opt.nth.contains(&(i + 1)) {
+                let _ = std::fs::remove_file(path);
+            }
+        }
+    }
 }
 
-fn add_passwd(opt: &InsertCmd) {
-    if !pser_lib_exists() {
+fn query(paths: &VaultPaths, opt: &QueryCmd) {
+    if !pser_lib_exists(paths) {
         println!("密码库不存在");
         return;
     }
-    let main_passwd = prompt_password("输主密码");
-    let mut db = PserDB::new(&main_passwd).unwrap();
+    // 先解析查询表达式，表达式无效时不必输入主密码
+    let filter = opt.str.as_deref().map(|s| Filter::parse(s).or_exit());
+    let mut db = open_db(paths, &credential("输入主密码"));
+
+    // 为None，表示列出密码库中所有信息，而不是搜索；搜索时按相关度排序
+    let mut psers: Vec<(&String, &Pser, u32)> = match &filter {
+        Some(filter) => db.rank(filter),
+        None => db.all_pser().into_iter().map(|(u, p)| (u, p, 0)).collect(),
+    };
+
+    let now = now8().timestamp();
+    let days = |d: u32| d as i64 * 24 * 3600;
+    if let Some(d) = opt.modified_within {
+        psers.retain(|(_, p, _)| p.modified >= now - days(d));
+    }
+    if let Some(d) = opt.unused_for {
+        psers.retain(|(_, p, _)| p.last_used < now - days(d));
+    }
+    if let Some(folder) = &opt.folder {
+        psers.retain(|(_, p, _)| p.in_folder(folder));
+    }
+    psers.retain(|(_, p, _)| opt.tag.iter().all(|t| p.has_tag(t)));
+
+    let iter = psers.iter();
+    let mut s: Vec<(i64, u32, &String, Zeroizing<String>)> = iter
+        .map(|(uuid, pser, score)| {
+            let time = match opt.sort {
+                Some(SortBy::Created) => pser.created,
+                Some(SortBy::Modified) => pser.modified,
+                Some(SortBy::Used) => pser.last_used,
+                None => 0,
+            };
+            let display = match opt.short {
+                true => pser.simple_display(Some(uuid)),
+                false => pser.verical_display(Some(uuid), opt.reveal),
+            };
+            (time, *score, *uuid, display)
+        })
+        .collect();
+    // 时间较新的在前，时间相同时相关度高的在前，再按输出内容排序
+    s.sort_by(|(ta, sa, _, a), (tb, sb, _, b)| {
+        tb.cmp(ta)
+            .then_with(|| sb.cmp(sa))
+            .then_with(|| a.as_str().cmp(b.as_str()))
+    });
+    if let Some(limit) = opt.limit {
+        s.truncate(limit);
+    }
+
+    let used: Vec<String> = match opt.str {
+        Some(_) => s.iter().map(|(_, _, uuid, _)| uuid.to_string()).collect(),
+        None => vec![],
+    };
+    // 逐条输出，不再拼接成一个包含所有密码的字符串
+    let mut stdout = std::io::stdout().lock();
+    for (i, (_, _, _, str)) in s.iter().enumerate() {
+        if i > 0 {
+            let _ = writeln!(stdout);
+        }
+        let _ = write!(stdout, "{}", str.as_str());
+    }
+    let _ = writeln!(stdout);
+    drop(stdout);
+
+    db.mark_used(&used).or_exit();
+}
+
+fn add_passwd(paths: &VaultPaths, opt: &InsertCmd) {
+    if !pser_lib_exists(paths) {
+        println!("密码库不存在");
+        return;
+    }
+    let mut db = open_db(paths, &credential("输主密码"));
 
     // 更新pser而不是添加pser
     if let Some(uuid_prefix) = &opt.uuid {
-        let uuids = db.uuid_by_prefix(uuid_prefix);
-        match uuids.len() {
-            // 搜索到了要更新的pser
-            1 => {
-                let mut pser = db.get_pser(&uuids[0]).unwrap().clone();
-                update_pser(&mut pser, opt);
-                db.update(&uuids[0], pser).unwrap();
-            }
-            0 => println!("指定的Uuid({})不存在", uuid_prefix),
-            _ => println!("Uuid({})指定位数过少产生歧义", uuid_prefix),
+        // 搜索到了要更新的pser
+        if let Some(uuid) = find_uuid(&db, uuid_prefix) {
+            let mut pser = db.get_pser(&uuid).unwrap().clone();
+            update_pser(&mut pser, opt);
+            db.update(&uuid, pser).or_exit();
         }
         return;
     }
@@ -186,7 +1061,83 @@ fn add_passwd(opt: &InsertCmd) {
     // 添加新的pser
     let mut pser = Pser::new();
     update_pser(&mut pser, opt);
-    db.insert(pser).unwrap();
+    db.insert(pser).or_exit();
+}
+
+/// 根据insert的类型子命令修改pser的类型，类型不变时只修改指定的字段，类型改变时替换原类型的所有字段
+fn update_kind(pser: &mut Pser, cmd: &KindCmd) {
+    // 有指定值时替换字段
+    fn set(field: &mut String, value: &Option<String>) {
+        if let Some(value) = value {
+            field.zeroize();
+            *field = value.clone();
+        }
+    }
+
+    let kind = match (cmd, pser.kind.clone()) {
+        (KindCmd::Login, _) => EntryKind::Login,
+        (KindCmd::Note(args), kind) => {
+            let mut note = match kind {
+                EntryKind::Note(note) => note,
+                _ => Note::default(),
+            };
+            set(&mut note.text, &args.text);
+            EntryKind::Note(note)
+        }
+        (KindCmd::Card(args), kind) => {
+            let mut card = match kind {
+                EntryKind::Card(card) => card,
+                _ => Card::default(),
+            };
+            set(&mut card.holder, &args.holder);
+            set(&mut card.number, &args.number);
+            set(&mut card.expiry, &args.expiry);
+            set(&mut card.cvv, &args.cvv);
+            set(&mut card.pin, &args.pin);
+            if card.expired(this_month()) {
+                eprintln!("警告: 该卡已过有效期{}", card.expiry);
+            }
+            EntryKind::Card(card)
+        }
+        (KindCmd::Identity(args), kind) => {
+            let mut identity = match kind {
+                EntryKind::Identity(identity) => identity,
+                _ => Identity::default(),
+            };
+            set(&mut identity.name, &args.name);
+            set(&mut identity.birthday, &args.birthday);
+            set(&mut identity.id_number, &args.id_number);
+            set(&mut identity.address, &args.address);
+            EntryKind::Identity(identity)
+        }
+        (KindCmd::SshKey(args), kind) => {
+            let mut key = match kind {
+                EntryKind::SshKey(key) => key,
+                _ => SshKey::default(),
+            };
+            let read = |f: &PathBuf| {
+                Zeroizing::new(std::fs::read_to_string(f).unwrap_or_else(|e| exit_with(e.into())))
+            };
+            if let Some(f) = &args.private_key {
+                key.private_key.zeroize();
+                key.private_key = read(f).to_string();
+            }
+            if let Some(f) = &args.public_key {
+                key.public_key = read(f).trim().to_string();
+            }
+            EntryKind::SshKey(key)
+        }
+        (KindCmd::ApiToken(args), kind) => {
+            let mut token = match kind {
+                EntryKind::ApiToken(token) => token,
+                _ => ApiToken::default(),
+            };
+            set(&mut token.token, &args.token);
+            set(&mut token.expires, &args.expires);
+            EntryKind::ApiToken(token)
+        }
+    };
+    pser.set_kind(kind).or_exit();
 }
 
 /// 根据给定的AddCmd中的选项，更新给定的pser
@@ -196,7 +1147,13 @@ fn update_pser(pser: &mut Pser, opt: &InsertCmd) {
     }
 
     if let Some(url) = &opt.url {
-        pser.set_url(url);
+        pser.set_url(url).or_exit();
+    }
+    for url in &opt.add_url {
+        pser.add_url(url).or_exit();
+    }
+    for url in &opt.rm_url {
+        pser.remove_url(url);
     }
 
     if let Some(desc) = &opt.desc {
@@ -206,28 +1163,38 @@ fn update_pser(pser: &mut Pser, opt: &InsertCmd) {
     // 如果首字符是`+`，则追加，否则覆盖
     if let Some(email) = &opt.email {
         match email.strip_prefix('+') {
-            Some(email) => {
-                pser.email.push(',');
-                pser.email.push_str(email);
-            }
+            Some(email) => email.split(',').for_each(|e| {
+                pser.add_email(e);
+            }),
             None => {
                 pser.set_email(email);
             }
         }
     }
+    for email in &opt.add_email {
+        pser.add_email(email);
+    }
+    for email in &opt.rm_email {
+        pser.remove_email(email);
+    }
 
     // 如果首字符是`+`，则追加，否则覆盖
     if let Some(phone) = &opt.phone {
         match phone.strip_prefix('+') {
-            Some(phone) => {
-                pser.phone.push(',');
-                pser.phone.push_str(phone);
-            }
+            Some(phone) => phone.split(',').for_each(|p| {
+                pser.add_phone(p);
+            }),
             None => {
                 pser.set_phone(phone);
             }
         }
     }
+    for phone in &opt.add_phone {
+        pser.add_phone(phone);
+    }
+    for phone in &opt.rm_phone {
+        pser.remove_phone(phone);
+    }
 
     if let Some(passwd) = &opt.passwd {
         pser.set_passwd(passwd);
@@ -238,7 +1205,9 @@ fn update_pser(pser: &mut Pser, opt: &InsertCmd) {
         // let comment = comment.split([',', ';']).collect::<Vec<_>>().join("\n");
         match comment.strip_prefix('+') {
             Some(comment) => {
-                pser.phone.push(',');
+                if !pser.comment.is_empty() {
+                    pser.comment.push(',');
+                }
                 pser.comment.push_str(comment);
             }
             None => {
@@ -246,24 +1215,52 @@ fn update_pser(pser: &mut Pser, opt: &InsertCmd) {
             }
         }
     }
+
+    if let Some(folder) = &opt.folder {
+        pser.set_folder(folder);
+    }
+    for tag in &opt.tag {
+        pser.add_tag(tag);
+    }
+
+    if let Some(otp) = &opt.otp {
+        pser.set_otp(otp).or_exit();
+    }
+
+    if let Some(kind) = &opt.kind {
+        update_kind(pser, kind);
+    }
+
+    // 值为空时删除该字段
+    let fields = opt.field.iter().map(|f| (f, false));
+    let protected = opt.protected_field.iter().map(|f| (f, true));
+    for ((name, value), protected) in fields.chain(protected) {
+        match value.is_empty() {
+            true => pser.remove_field(name),
+            false => pser.set_field(name, value, protected),
+        };
+    }
 }
 
-fn import(opt: &ImportCmd) {
-    if !pser_lib_exists() {
+fn import(paths: &VaultPaths, opt: &ImportCmd) {
+    if !pser_lib_exists(paths) {
         println!("密码库不存在");
         return;
     }
-    let main_passwd = prompt_password("输主密码");
-    let mut db = PserDB::new(&main_passwd).unwrap();
+    let mut db = open_db(paths, &credential("输主密码"));
 
     // 读取等待导入的数据
     let input_str = match &opt.input {
-        Some(f) => std::fs::read_to_string(f).unwrap(),
+        Some(f) => {
+            Zeroizing::new(std::fs::read_to_string(f).unwrap_or_else(|e| exit_with(e.into())))
+        }
         // 从标准输入中读取等待导入的数据
         None => {
             let mut stdin = std::io::stdin();
-            let mut buf = String::new();
-            stdin.read_to_string(&mut buf).unwrap();
+            let mut buf = Zeroizing::new(String::new());
+            stdin
+                .read_to_string(&mut buf)
+                .unwrap_or_else(|e| exit_with(e.into()));
             buf
         }
     };
@@ -275,14 +1272,27 @@ fn import(opt: &ImportCmd) {
     };
 }
 
+/// 数据格式错误时，输出错误信息并退出
+fn exit_invalid_input(msg: String) -> ! {
+    exit_with(Error::Other(anyhow!(msg)))
+}
+
 fn import_from_json(db: &mut PserDB, json_str: &str) {
-    let s: HashMap<String, Pser> =
-        serde_json::from_str(json_str).unwrap_or_else(|_| panic!("can't decode: {}", json_str));
+    let s: HashMap<String, serde_json::Value> = serde_json::from_str(json_str)
+        .unwrap_or_else(|e| exit_invalid_input(format!("无法解析JSON数据: {}", e)));
 
-    let mut success_insert = 0;
+    // 先解析所有密码信息，有无法解析的密码信息时不导入任何数据
+    let mut psers = vec![];
+    for (uuid, mut value) in s {
+        upgrade_legacy_json(&mut value);
+        let pser: Pser = serde_json::from_value(value)
+            .unwrap_or_else(|e| exit_invalid_input(format!("无法解析密码信息{}: {}", uuid, e)));
+        psers.push((uuid, pser));
+    }
 
-    for (uuid, pser) in s {
-        db.update(&uuid, pser).unwrap();
+    let mut success_insert = 0;
+    for (uuid, pser) in psers {
+        db.update(&uuid, pser).or_exit();
 
         // let uuids = db.uuid_by_prefix(&uuid);
         // match uuids.is_empty() {
@@ -301,11 +1311,41 @@ fn import_from_json(db: &mut PserDB, json_str: &str) {
     println!("成功插入 {} 条密码信息", success_insert);
 }
 
+/// 旧版本导出的JSON中，url、email和phone是字符串(多个邮箱或联系方式使用逗号分隔)，将其转换为列表，
+/// history是以修改时间为key的对象，将其转换为按修改时间排序的列表
+fn upgrade_legacy_json(value: &mut serde_json::Value) {
+    if let Some(serde_json::Value::Object(old)) = value.get("history") {
+        let mut history: Vec<(i64, &serde_json::Value)> = old
+            .iter()
+            .filter_map(|(changed, passwd)| Some((changed.parse().ok()?, passwd)))
+            .collect();
+        history.sort_by_key(|h| h.0);
+        let history: Vec<_> = history
+            .into_iter()
+            .map(|(changed, passwd)| serde_json::json!({"changed": changed, "passwd": passwd}))
+            .collect();
+        value["history"] = serde_json::json!(history);
+    }
+    for key in ["url", "email", "phone"] {
+        if let Some(serde_json::Value::String(old)) = value.get(key) {
+            let list: Vec<&str> = old
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .collect();
+            value[key] = serde_json::json!(list);
+        }
+    }
+}
+
 fn import_from_csv(db: &mut PserDB, csv_str: &str) {
     let mut rdr = csv::Reader::from_reader(csv_str.as_bytes());
 
     // csv文件中的name username url password comment字段可能是乱的，因此先找出各字段在每行记录上的索引
-    let header = rdr.headers().expect("missing csv header").into_iter();
+    let header = rdr
+        .headers()
+        .unwrap_or_else(|e| exit_invalid_input(format!("无法读取CSV表头: {}", e)))
+        .into_iter();
     let name_idx = header
         .clone()
         .position(|x| x == "name")
@@ -317,63 +1357,407 @@ fn import_from_csv(db: &mut PserDB, csv_str: &str) {
     let username_idx = header
         .clone()
         .position(|x| x == "username")
-        .expect("missing username field in csv");
+        .unwrap_or_else(|| exit_invalid_input("CSV中缺少username字段".to_string()));
     let url_idx = header
         .clone()
         .position(|x| x == "url")
-        .expect("missing url field in csv");
+        .unwrap_or_else(|| exit_invalid_input("CSV中缺少url字段".to_string()));
     let passwd_idx = header
         .clone()
         .position(|x| x == "password")
-        .expect("missing password field in csv");
+        .unwrap_or_else(|| exit_invalid_input("CSV中缺少password字段".to_string()));
 
+    // 先解析所有记录，有无法解析的记录时不导入任何数据
     let mut line_num = 1;
+    let mut psers = vec![];
     for result in rdr.records() {
         line_num += 1;
-        let res = result.unwrap();
+        let res = result.unwrap_or_else(|e| exit_invalid_input(format!("第{}行: {}", line_num, e)));
 
         let desc = res.get(name_idx);
         let comment = res.get(comment_idx);
         let username = res
             .get(username_idx)
-            .unwrap_or_else(|| panic!("missing username field at line {}", line_num));
+            .unwrap_or_else(|| exit_invalid_input(format!("第{}行缺少username字段", line_num)));
         let url = res
             .get(url_idx)
-            .unwrap_or_else(|| panic!("missing url field at line {}", line_num));
+            .unwrap_or_else(|| exit_invalid_input(format!("第{}行缺少url字段", line_num)));
         let passwd = res
             .get(passwd_idx)
-            .unwrap_or_else(|| panic!("missing password field at line {}", line_num));
+            .unwrap_or_else(|| exit_invalid_input(format!("第{}行缺少password字段", line_num)));
 
         let mut pser = Pser::new();
-        pser.set_username(username).set_url(url).set_passwd(passwd);
+        if let Err(e) = pser.set_url(url) {
+            eprintln!("第{}行: {}，已跳过", line_num, e);
+            continue;
+        }
+        pser.set_username(username).set_passwd(passwd);
         if let Some(desc) = desc {
             pser.set_desc(desc);
         }
         if let Some(comment) = comment {
             pser.set_comment(comment);
         }
+        psers.push(pser);
+    }
 
-        db.insert(pser).unwrap();
+    let mut success_insert = 0;
+    for pser in psers {
+        db.insert(pser).or_exit();
+        success_insert += 1;
     }
-    println!("成功插入 {} 条密码信息", line_num - 1);
+    println!("成功插入 {} 条密码信息", success_insert);
 }
 
-fn export(opt: &ExportCmd) {
-    if !pser_lib_exists() {
+fn export(paths: &VaultPaths, opt: &ExportCmd) {
+    if !pser_lib_exists(paths) {
         println!("密码库不存在");
         return;
     }
-    let main_passwd = prompt_password("输入主密码");
-    let db = PserDB::new(&main_passwd).unwrap();
+    let db = open_db(paths, &credential("输入主密码"));
 
     let psers: HashMap<&String, &Pser> = db.all_pser().into_iter().collect();
-    let str = serde_json::to_string_pretty(&psers).unwrap();
+    let str = Zeroizing::new(serde_json::to_string_pretty(&psers).unwrap());
 
     match &opt.output {
         Some(f) => {
-            std::fs::write(f, str).unwrap();
+            std::fs::write(f, str.as_bytes()).unwrap();
+        }
+        None => println!("{}", str.as_str()),
+    }
+}
+
+/// 管理密钥槽
+fn key(paths: &VaultPaths, opt: &KeyCmd) {
+    if !pser_lib_exists(paths) {
+        println!("密码库不存在");
+        return;
+    }
+    let mut db = open_db(paths, &credential("输入主密码"));
+
+    match &opt.cmds {
+        KeyCmds::List => {
+            for slot in db.key_slots() {
+                println!(
+                    "{}  {}  {}  {}",
+                    slot.id,
+                    slot.kind,
+                    slot.created.secs_to_dt(east8()),
+                    slot.desc
+                );
+            }
         }
-        None => println!("{}", str),
+        KeyCmds::Add(opt) => add_key(&mut db, opt),
+        KeyCmds::Remove(opt) => {
+            if yes_dialog() {
+                match db.remove_key_slot(opt.id) {
+                    Ok(_) => println!("已删除密钥槽{}", opt.id),
+                    Err(e) => exit_with(e),
+                }
+            }
+        }
+    }
+}
+
+/// 查看最近的解锁失败记录和锁定策略，指定了选项时修改锁定策略
+fn status(paths: &VaultPaths, opt: &StatusCmd) {
+    if !pser_lib_exists(paths) {
+        println!("密码库不存在");
+        return;
+    }
+    let mut db = open_db(paths, &credential("输入主密码"));
+
+    if opt.free.is_some() || opt.delay.is_some() || opt.max.is_some() {
+        let mut policy = *db.throttle().policy();
+        policy.free_attempts = opt.free.unwrap_or(policy.free_attempts);
+        policy.base_delay = opt.delay.unwrap_or(policy.base_delay);
+        policy.max_delay = opt.max.unwrap_or(policy.max_delay);
+        db.set_lockout_policy(policy).or_exit();
+    }
+
+    let policy = db.throttle().policy();
+    println!(
+        "锁定策略: 允许连续失败{}次，之后从等待{}秒开始每次翻倍，最长等待{}秒",
+        policy.free_attempts, policy.base_delay, policy.max_delay
+    );
+    let failed: Vec<_> = db.throttle().history().collect();
+    match failed.is_empty() {
+        true => println!("没有解锁失败记录"),
+        false => {
+            println!("最近{}次解锁失败:", failed.len());
+            for t in failed {
+                println!("  {}", t.secs_to_dt(east8()));
+            }
+        }
+    }
+}
+
+/// 管理 argon2 参数
+fn kdf(paths: &VaultPaths, opt: &KdfCmd) {
+    if let KdfCmds::Tune(opt) = &opt.cmds {
+        if !opt.apply {
+            tune_kdf(opt);
+            return;
+        }
+    }
+    if !pser_lib_exists(paths) {
+        println!("密码库不存在");
+        return;
+    }
+    let mut db = open_db(paths, &credential("输入主密码"));
+
+    match &opt.cmds {
+        KdfCmds::Show => {
+            for slot in db.key_slots() {
+                let params = slot.kdf_params().map(|p| p.to_string());
+                let legacy = match slot.kdf_params() == Some(KdfParams::legacy()) {
+                    true => "(旧版本参数，建议执行kdf rekey升级)",
+                    false => "",
+                };
+                println!(
+                    "{}  {}  {}{}",
+                    slot.id,
+                    slot.kind,
+                    params.unwrap_or_default(),
+                    legacy
+                );
+            }
+        }
+        KdfCmds::Tune(opt) => {
+            let params = tune_kdf(opt);
+            rekey(&mut db, &params);
+        }
+        KdfCmds::Rekey(opt) => rekey(&mut db, &rekey_params(opt)),
+    }
+}
+
+/// 测试本机的速度并输出选择的参数
+fn tune_kdf(opt: &KdfTuneCmd) -> KdfParams {
+    println!("正在测试 argon2 的速度...");
+    let target = Duration::from_millis(opt.target_ms);
+    let (params, elapsed) = KdfParams::tune(target, opt.max_mem.saturating_mul(1024));
+    println!("{} (耗时{}毫秒)", params, elapsed.as_millis());
+    params
+}
+
+fn rekey_params(opt: &KdfRekeyCmd) -> KdfParams {
+    let default = KdfParams::default();
+    KdfParams {
+        mem_cost: opt.mem.unwrap_or(default.mem_cost),
+        time_cost: opt.time.unwrap_or(default.time_cost),
+        lanes: opt.lanes.unwrap_or(default.lanes),
+        ..default
+    }
+}
+
+fn rekey(db: &mut PserDB, params: &KdfParams) {
+    match db.rekey(params) {
+        Ok(_) => println!("已使用新参数重新包装密钥槽{}", db.unlocked_slot()),
+        Err(e) => exit_with(e),
+    }
+}
+
+/// 升级密码库格式，dry_run时只输出各副本需要执行的升级步骤
+fn migrate(paths: &VaultPaths, opt: &MigrateCmd) {
+    if !pser_lib_exists(paths) {
+        println!("密码库不存在");
+        return;
+    }
+    if !opt.dry_run {
+        open_db(paths, &credential("输入主密码"));
+        println!("密码库已是当前格式(版本{})", FORMAT_VERSION);
+        return;
+    }
+
+    for (path, format) in SyncDb::formats(paths) {
+        let format = match format {
+            Ok(format) => format,
+            Err(e) => {
+                println!("{}: 无法读取({})", path.display(), e);
+                continue;
+            }
+        };
+        if format.version == FORMAT_VERSION {
+            println!("{}: 版本{}，已是当前格式", path.display(), format.version);
+            continue;
+        }
+
+        println!(
+            "{}: 版本{}，将升级到版本{}",
+            path.display(),
+            format.version,
+            FORMAT_VERSION
+        );
+        for m in migrate::pending(format.version) {
+            println!("  {} -> {}: {}", m.from, m.from + 1, m.desc);
+        }
+        match migrate::pending(format.version).any(|m| m.reencrypt) {
+            true => println!("  需要输入主密码，使用新生成的数据密钥重新加密所有数据"),
+            false => println!("  将以当前格式重新写入验证头和{}行数据", format.rows),
+        }
+    }
+}
+
+/// 输出一次性密码及其剩余有效秒数，HOTP输出后计数器加1
+fn otp(paths: &VaultPaths, opt: &OtpCmd) {
+    if !pser_lib_exists(paths) {
+        println!("密码库不存在");
+        return;
+    }
+    let filter = Filter::parse(&opt.str).or_exit();
+    let mut db = open_db(paths, &credential("输入主密码"));
+
+    // 先按UUID前缀查找，找不到时再搜索(按相关度排序)
+    let mut uuids: Vec<String> = match_prefix(&opt.str, db.all_pser())
+        .into_iter()
+        .map(|(uuid, _)| uuid.to_string())
+        .collect();
+    if uuids.is_empty() {
+        uuids = db
+            .rank(&filter)
+            .into_iter()
+            .map(|(uuid, _, _)| uuid.to_string())
+            .collect();
+    }
+    uuids.retain(|uuid| db.get_pser(uuid).is_some_and(|p| p.otp.is_some()));
+    if uuids.len() > 1 {
+        let candidates = candidates(&db, &uuids);
+        match pick_one(&candidates) {
+            Ok(picked) => uuids = picked.into_iter().collect(),
+            Err(_) => {
+                println!("匹配到多条配置了一次性密码的密码信息，请指定UUID:");
+                for (uuid, pser) in candidates {
+                    println!("  {} {} {}", uuid, pser.title(), pser.username);
+                }
+                return;
+            }
+        }
+    }
+    match uuids.pop() {
+        Some(uuid) => show_otp(&mut db, &uuid),
+        None => println!("没有找到配置了一次性密码的密码信息"),
+    }
+}
+
+/// 输出uuid对应的密码信息当前的一次性密码，HOTP的计数器加1
+fn show_otp(db: &mut PserDB, uuid: &str) {
+    let Some(otp) = db.get_pser(uuid).and_then(|p| p.otp.as_ref()) else {
+        return;
+    };
+
+    let (code, remaining) = otp.code(now8().timestamp());
+    match remaining {
+        Some(secs) => println!("{} (剩余{}秒)", code.as_str(), secs),
+        None => println!("{}", code.as_str()),
+    }
+    db.mark_used(&[uuid.to_string()]).or_exit();
+    db.advance_hotp(uuid).or_exit();
+}
+
+/// 管理密码信息的附件
+fn attach(paths: &VaultPaths, opt: &AttachCmd) {
+    if !pser_lib_exists(paths) {
+        println!("密码库不存在");
+        return;
+    }
+    let mut db = open_db(paths, &credential("输入主密码"));
+
+    let uuid_prefix = match &opt.cmds {
+        AttachCmds::Add(opt) => &opt.uuid,
+        AttachCmds::List(opt) => &opt.uuid,
+        AttachCmds::Extract(opt) => &opt.uuid,
+        AttachCmds::Rm(opt) => &opt.uuid,
+    };
+    let Some(uuid) = find_uuid(&db, uuid_prefix) else {
+        return;
+    };
+    let uuid = &uuid;
+
+    match &opt.cmds {
+        AttachCmds::Add(opt) => {
+            let name = match &opt.name {
+                Some(name) => name.clone(),
+                None => match opt.file.file_name() {
+                    Some(name) => name.to_string_lossy().to_string(),
+                    None => {
+                        println!(
+                            "无法从{}中获取文件名，请使用-n指定附件名称",
+                            opt.file.display()
+                        );
+                        return;
+                    }
+                },
+            };
+            let data =
+                Zeroizing::new(std::fs::read(&opt.file).unwrap_or_else(|e| exit_with(e.into())));
+            db.add_attachment(uuid, &name, &data).or_exit();
+            println!("已添加附件{}({}字节)", name, data.len());
+        }
+        AttachCmds::List(_) => {
+            let attachments = db.get_pser(uuid).map(|p| p.attachments.as_slice());
+            for a in attachments.unwrap_or_default() {
+                println!(
+                    "{}  {}字节  {}",
+                    a.name,
+                    a.size,
+                    a.added.secs_to_dt(east8())
+                );
+            }
+        }
+        AttachCmds::Extract(opt) => {
+            let data = db.read_attachment(uuid, &opt.file).or_exit();
+            let output = opt
+                .output
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(&opt.file));
+            if output == Path::new("-") {
+                std::io::stdout().write_all(&data).unwrap();
+                return;
+            }
+            match write_private_file(&output, &data) {
+                Ok(_) => println!("已保存到{}", output.display()),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    println!("文件{}已存在，请使用-o指定其它文件", output.display())
+                }
+                Err(e) => exit_with(e.into()),
+            }
+        }
+        AttachCmds::Rm(opt) => db.remove_attachment(uuid, &opt.file).or_exit(),
+    }
+}
+
+fn add_key(db: &mut PserDB, opt: &KeyAddCmd) {
+    let mut recovery_code = None;
+    let (kind, cred) = match opt.kind {
+        KeyKind::Passphrase => {
+            let passwd = dialoguer::Password::new()
+                .with_prompt("输入新口令")
+                .with_confirmation("再次输入新口令", "两次输入的口令不一致")
+                .interact()
+                .map(Zeroizing::new)
+                .unwrap();
+            (SlotKind::Passphrase, Credential::passwd(&passwd))
+        }
+        KeyKind::Keyfile => {
+            // clap已保证keyfile类型必须指定文件
+            let file = opt.file.as_ref().unwrap();
+            (SlotKind::Keyfile, Credential::keyfile(read_keyfile(file)))
+        }
+        KeyKind::Recovery => {
+            let code = Zeroizing::new(gen_recovery_code());
+            let cred = Credential::passwd(&code);
+            recovery_code = Some(code);
+            (SlotKind::Recovery, cred)
+        }
+    };
+
+    let id = db.add_key_slot(kind, &opt.desc, &cred).or_exit();
+    println!("已添加密钥槽{}", id);
+    if let Some(code) = recovery_code {
+        println!(
+            "恢复码(只显示这一次，请打印或抄写后妥善保管): {}",
+            code.as_str()
+        );
     }
 }
 
@@ -386,13 +1770,14 @@ fn yes_dialog() -> bool {
 }
 
 #[allow(dead_code)]
-fn add_test_psers() {
-    let mut db = PserDB::new("helloworld").unwrap();
+fn add_test_psers(paths: &VaultPaths) {
+    let mut db = PserDB::new(paths, "helloworld").unwrap();
 
     let mut pser1 = Pser::new();
     pser1
         .set_username("jma")
         .set_url("google.com")
+        .unwrap()
         .set_desc("google")
         .set_email("jma@hotmail.com")
         .set_phone("18213812341")
@@ -403,6 +1788,7 @@ fn add_test_psers() {
     pser2
         .set_username("kex")
         .set_url("outlook.com")
+        .unwrap()
         .set_email("kex@163.com")
         .set_passwd("passwd");
     db.insert(pser2).unwrap();
@@ -410,6 +1796,34 @@ fn add_test_psers() {
 
 #[cfg(test)]
 mod t {
+    use super::*;
+
+    #[test]
+    fn prefix() {
+        let uuids = ["ab12", "ab", "ab34", "cd56"].map(String::from);
+        let pser = Pser::new();
+        let matched = |prefix: &str| -> Vec<&str> {
+            let candidates = uuids.iter().map(|uuid| (uuid, &pser));
+            match_prefix(prefix, candidates)
+                .into_iter()
+                .map(|(uuid, _)| uuid.as_str())
+                .collect()
+        };
+        assert_eq!(matched("cd"), ["cd56"]);
+        assert_eq!(matched("a"), ["ab", "ab12", "ab34"]);
+        // 完整的uuid不会有歧义
+        assert_eq!(matched("ab"), ["ab"]);
+        assert!(matched("ef").is_empty());
+    }
+
+    #[test]
+    fn not_terminal() {
+        assert!(require_terminal(true, "标准输入").is_ok());
+        let e = require_terminal(false, "标准输入").unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::NotConnected);
+        assert_eq!(e.to_string(), "标准输入不是终端");
+    }
+
     #[test]
     fn tt() {
         let password = dialoguer::Password::new()
@@ -1,4 +1,5 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
 
 /// 添加或修改已有密码
 ///
@@ -10,10 +11,17 @@ use clap::Parser;
 ///        --desc google --email peny@qq.com
 ///        --phone 12343211234 --passwd 'Pass@word'
 ///        --comment 'card:622218291928312'
+///        --protected-field 'card=622218291928312'
+///        --field 'question=first pet'
+///        --folder personal --tag mail
 ///
 /// 修改密码(指定--uuid选项)：只修改密码，其它信息不变
 ///
 /// .    $0 insert --uuid asdksld --passwd 'Pass@word'
+///
+/// 添加其它类型的密码信息(通用选项需放在类型之前)：
+///
+/// .    $0 insert --desc 招商银行 card --number '6225 8812 3456 7890' --expiry 08/27
 #[derive(Debug, Parser)]
 pub struct InsertCmd {
     /// UUID，指定该选项表示修改已有密码而不是添加密码，
@@ -26,28 +34,52 @@ pub struct InsertCmd {
     #[clap(short, long)]
     pub user: Option<String>,
 
-    /// 哪个网站的账户(基于域名)
+    /// 哪个网站的账户(基于域名)，将覆盖已有的所有URL
     #[clap(short = 'U', long)]
     pub url: Option<String>,
 
+    /// 添加URL，同一个账户可用于多个网站，可多次指定
+    #[clap(long, value_name = "URL")]
+    pub add_url: Vec<String>,
+
+    /// 删除URL，可多次指定
+    #[clap(long, value_name = "URL")]
+    pub rm_url: Vec<String>,
+
     /// 什么账户，可以结合url或者和url二选一
-    /// 
+    ///
     /// 例如google的账户，可以记录为`url:google.com`或者`desc:google`，或者两者都记录
     #[clap(short, long)]
     pub desc: Option<String>,
 
-    /// 邮箱，如果有第二邮箱，可使用备注保存，或者使用逗号分隔多个邮箱，
-    /// 
-    /// 如果是修改操作，首字符使用`+`，将表示追加邮箱信息，否则将覆盖已有邮箱信息。
+    /// 邮箱，可以使用逗号分隔多个邮箱，将覆盖已有的所有邮箱
+    ///
+    /// 首字符使用`+`时等同于`--add-email`
     #[clap(short, long)]
     pub email: Option<String>,
 
-    /// 联系方式，如果有第二联系方式，可使用备注保存，或者使用逗号分隔多个联系方式，
-    /// 
-    /// 如果是修改操作，首字符使用`+`，将表示追加联系方式，否则将覆盖已有联系方式。
+    /// 添加邮箱，可多次指定
+    #[clap(long, value_name = "EMAIL")]
+    pub add_email: Vec<String>,
+
+    /// 删除邮箱，可多次指定
+    #[clap(long, value_name = "EMAIL")]
+    pub rm_email: Vec<String>,
+
+    /// 联系方式，可以使用逗号分隔多个联系方式，将覆盖已有的所有联系方式
+    ///
+    /// 首字符使用`+`时等同于`--add-phone`
     #[clap(short = 'P', long)]
     pub phone: Option<String>,
 
+    /// 添加联系方式，可多次指定
+    #[clap(long, value_name = "PHONE")]
+    pub add_phone: Vec<String>,
+
+    /// 删除联系方式，可多次指定
+    #[clap(long, value_name = "PHONE")]
+    pub rm_phone: Vec<String>,
+
     /// 密码，可以是单个密码，也可以是逗号分隔的多个单词助记词
     #[clap(short, long)]
     pub passwd: Option<String>,
@@ -55,8 +87,126 @@ pub struct InsertCmd {
     /// 备注，记录额外信息。
     ///
     /// 如果是修改操作，且首字符使用`+`，将追加备注信息，否则将覆盖已有备注信息。
-    /// 
+    ///
     /// comment中的英文逗号`,`和英文分号`;`在**输出显示**时它们都将显示为换行符
     #[clap(short, long)]
     pub comment: Option<String>,
+
+    /// 自定义字段，格式为`名称=值`，可多次指定
+    ///
+    /// 已存在同名字段时替换其值，值为空(`名称=`)时删除该字段
+    #[clap(short, long, value_name = "NAME=VALUE", value_parser = parse_field)]
+    pub field: Vec<(String, String)>,
+
+    /// 受保护的自定义字段(例如卡号、安全问题的答案)，格式同`--field`，
+    /// 查询时默认隐藏其值(见`query --reveal`)，也不参与搜索
+    #[clap(long, value_name = "NAME=VALUE", value_parser = parse_field)]
+    pub protected_field: Vec<(String, String)>,
+
+    /// 所在的文件夹，以`/`分隔的路径，例如`work/aws/prod`，`/`表示根目录
+    #[clap(long)]
+    pub folder: Option<String>,
+
+    /// 添加标签，可多次指定，删除标签见`tag rm`
+    #[clap(short, long)]
+    pub tag: Vec<String>,
+
+    /// 一次性密码(2FA)：`otpauth://`URI(可从二维码中获取)，或base32编码的密钥(SHA1，6位，每30秒)
+    ///
+    /// 之后可使用`otp`子命令查看当前的一次性密码
+    #[clap(long, value_name = "URI")]
+    pub otp: Option<String>,
+
+    /// 类型及该类型的字段，添加时省略表示账户，修改时省略表示类型不变
+    #[clap(subcommand)]
+    pub kind: Option<KindCmd>,
+}
+
+/// 密码信息的类型，修改时类型不变则只修改指定的字段，类型改变则替换原类型的所有字段
+#[derive(Debug, Subcommand)]
+pub enum KindCmd {
+    /// 网站或应用的账户，只使用通用选项
+    Login,
+    Note(NoteArgs),
+    Card(CardArgs),
+    Identity(IdentityArgs),
+    SshKey(SshKeyArgs),
+    ApiToken(ApiTokenArgs),
+}
+
+/// 安全笔记
+#[derive(Debug, Parser)]
+pub struct NoteArgs {
+    /// 笔记内容
+    #[clap(long)]
+    pub text: Option<String>,
+}
+
+/// 银行卡、信用卡，卡号需通过Luhn校验
+#[derive(Debug, Parser)]
+pub struct CardArgs {
+    /// 持卡人
+    #[clap(long)]
+    pub holder: Option<String>,
+    /// 卡号，可以包含空格或`-`
+    #[clap(long)]
+    pub number: Option<String>,
+    /// 有效期，`MM/YY`或`MM/YYYY`
+    #[clap(long)]
+    pub expiry: Option<String>,
+    /// 安全码，查询时默认隐藏
+    #[clap(long)]
+    pub cvv: Option<String>,
+    /// 卡密码，查询时默认隐藏
+    #[clap(long)]
+    pub pin: Option<String>,
+}
+
+/// 身份信息
+#[derive(Debug, Parser)]
+pub struct IdentityArgs {
+    /// 姓名
+    #[clap(long)]
+    pub name: Option<String>,
+    /// 出生日期，`YYYY-MM-DD`
+    #[clap(long)]
+    pub birthday: Option<String>,
+    /// 证件号码，查询时默认隐藏
+    #[clap(long)]
+    pub id_number: Option<String>,
+    /// 地址
+    #[clap(long)]
+    pub address: Option<String>,
+}
+
+/// SSH密钥，查询时显示公钥指纹，私钥默认隐藏
+#[derive(Debug, Parser)]
+pub struct SshKeyArgs {
+    /// 私钥文件，例如~/.ssh/id_ed25519
+    #[clap(long, value_name = "FILE")]
+    pub private_key: Option<PathBuf>,
+    /// 公钥文件，例如~/.ssh/id_ed25519.pub
+    #[clap(long, value_name = "FILE")]
+    pub public_key: Option<PathBuf>,
+}
+
+/// API令牌
+#[derive(Debug, Parser)]
+pub struct ApiTokenArgs {
+    /// 令牌
+    #[clap(long)]
+    pub token: Option<String>,
+    /// 过期日期，`YYYY-MM-DD`
+    #[clap(long)]
+    pub expires: Option<String>,
+}
+
+/// 解析`名称=值`格式的自定义字段
+fn parse_field(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((name, value)) if !name.trim().is_empty() => {
+            Ok((name.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format!("自定义字段的格式应为`名称=值`: {}", s)),
+    }
 }
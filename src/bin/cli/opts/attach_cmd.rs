@@ -0,0 +1,63 @@
+use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+
+/// 管理密码信息的附件，例如恢复码PDF、SSH私钥、授权文件
+///
+/// 附件内容使用数据密钥加密后单独保存，查询密码信息时不会读取附件内容。
+/// 导出的json中只包含附件的文件名和大小，不包含附件内容
+///
+/// 例如，为某条密码信息添加附件：$0 attach add d5963ef ~/.ssh/id_ed25519
+#[derive(Debug, Parser)]
+pub struct AttachCmd {
+    #[clap(subcommand)]
+    pub cmds: AttachCmds,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum AttachCmds {
+    Add(AttachAddCmd),
+    /// 列出某条密码信息的所有附件
+    #[clap(visible_alias("ls"))]
+    List(AttachListCmd),
+    Extract(AttachExtractCmd),
+    Rm(AttachRmCmd),
+}
+
+/// 添加附件，已存在同名附件时替换
+#[derive(Debug, Parser)]
+pub struct AttachAddCmd {
+    /// UUID(前缀)
+    pub uuid: String,
+    /// 要添加的文件
+    pub file: PathBuf,
+    /// 附件名称，默认为文件名
+    #[clap(short, long)]
+    pub name: Option<String>,
+}
+
+#[derive(Debug, Parser)]
+pub struct AttachListCmd {
+    /// UUID(前缀)
+    pub uuid: String,
+}
+
+/// 将附件解密后保存为文件
+#[derive(Debug, Parser)]
+pub struct AttachExtractCmd {
+    /// UUID(前缀)
+    pub uuid: String,
+    /// 附件名称
+    pub file: String,
+    /// 保存到指定的文件，默认保存为当前目录下的同名文件，`-`表示输出到标准输出
+    #[clap(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+/// 删除附件
+#[derive(Debug, Parser)]
+pub struct AttachRmCmd {
+    /// UUID(前缀)
+    pub uuid: String,
+    /// 附件名称
+    pub file: String,
+}
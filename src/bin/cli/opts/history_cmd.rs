@@ -0,0 +1,58 @@
+use clap::{Parser, Subcommand};
+
+/// 查看、恢复旧密码，设置旧密码的保留策略
+///
+/// 例如，列出某条密码信息的旧密码(最近被修改的为第1个)：$0 history d5963ef，
+/// 恢复其中的第2个旧密码：$0 history restore d5963ef 2
+#[derive(Debug, Parser)]
+#[clap(args_conflicts_with_subcommands = true, arg_required_else_help = true)]
+pub struct HistoryCmd {
+    /// UUID(前缀)，列出该密码信息的所有旧密码
+    pub uuid: Option<String>,
+
+    #[clap(subcommand)]
+    pub cmds: Option<HistoryCmds>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum HistoryCmds {
+    Restore(HistoryRestoreCmd),
+    Policy(HistoryPolicyCmd),
+}
+
+/// 恢复旧密码，当前密码将保存为旧密码
+#[derive(Debug, Parser)]
+pub struct HistoryRestoreCmd {
+    /// UUID(前缀)
+    pub uuid: String,
+    /// 恢复第几个旧密码(最近被修改的为第1个)
+    pub n: usize,
+}
+
+/// 查看或设置旧密码的保留策略，超出限制的旧密码在修改密码信息时删除
+///
+/// 默认设置密码库的保留策略(立即删除各密码信息中超出限制的旧密码)，
+/// 指定`--uuid`时只设置该密码信息的保留策略，它优先于密码库的保留策略。
+/// 不指定`--keep`、`--days`、`--unlimited`和`--inherit`时只显示当前的保留策略
+#[derive(Debug, Parser)]
+pub struct HistoryPolicyCmd {
+    /// UUID(前缀)，只设置该密码信息的保留策略
+    #[clap(short = 'i', long)]
+    pub uuid: Option<String>,
+
+    /// 最多保留最近的几个旧密码，0表示不保留旧密码
+    #[clap(short, long)]
+    pub keep: Option<u32>,
+
+    /// 最多保留最近几天内被修改的旧密码
+    #[clap(short, long)]
+    pub days: Option<u32>,
+
+    /// 不限制旧密码的数量和天数(保留所有旧密码)
+    #[clap(long, conflicts_with_all = ["keep", "days"])]
+    pub unlimited: bool,
+
+    /// 删除该密码信息自己的保留策略，改为使用密码库的保留策略
+    #[clap(long, requires = "uuid", conflicts_with_all = ["keep", "days", "unlimited"])]
+    pub inherit: bool,
+}
@@ -0,0 +1,55 @@
+use clap::{Parser, Subcommand};
+
+/// 管理根据密码生成密钥时使用的 argon2 参数
+///
+/// 参数保存在每个密钥槽中，修改参数不影响使用其它密钥槽打开密码库。
+/// 旧版本创建的密码库使用的是较弱的参数，可通过`kdf rekey`或`kdf tune --apply`升级
+#[derive(Debug, Parser)]
+pub struct KdfCmd {
+    #[clap(subcommand)]
+    pub cmds: KdfCmds,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KdfCmds {
+    /// 查看各密钥槽使用的 argon2 参数
+    Show,
+    Tune(KdfTuneCmd),
+    Rekey(KdfRekeyCmd),
+}
+
+/// 测试本机的速度，选择使得打开密码库大约耗时指定时间的参数
+///
+/// 例如，选择耗时约2秒、内存不超过512MiB的参数并应用：$0 kdf tune -t 2000 -m 512 --apply
+#[derive(Debug, Parser)]
+pub struct KdfTuneCmd {
+    /// 期望的耗时(毫秒)
+    #[clap(short, long, default_value_t = 1000)]
+    pub target_ms: u64,
+
+    /// 最多使用的内存(MiB)
+    #[clap(short, long, default_value_t = 256)]
+    pub max_mem: u32,
+
+    /// 使用选择的参数重新包装打开密码库时解开的密钥槽
+    #[clap(long)]
+    pub apply: bool,
+}
+
+/// 使用指定的参数(省略时使用默认参数)重新包装打开密码库时解开的密钥槽
+///
+/// 使用主密码打开密码库时，之后添加的密钥槽也将使用新参数
+#[derive(Debug, Parser)]
+pub struct KdfRekeyCmd {
+    /// 内存开销(KiB)
+    #[clap(long)]
+    pub mem: Option<u32>,
+
+    /// 迭代次数
+    #[clap(long)]
+    pub time: Option<u32>,
+
+    /// 并行度
+    #[clap(long)]
+    pub lanes: Option<u32>,
+}
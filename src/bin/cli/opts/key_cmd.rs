@@ -0,0 +1,62 @@
+use clap::{Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
+
+/// 管理密钥槽
+///
+/// 每个密钥槽都保存了被某个凭据加密的数据密钥，使用任意一个密钥槽对应的凭据都能打开密码库。
+/// 可为家庭成员添加各自的口令，或者添加密钥文件、恢复码，忘记主密码时用它们打开密码库后重置主密码。
+///
+/// 使用密钥文件打开密码库：$0 --keyfile /media/usb/pser.key query google
+#[derive(Debug, Parser)]
+pub struct KeyCmd {
+    #[clap(subcommand)]
+    pub cmds: KeyCmds,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum KeyCmds {
+    /// 列出所有密钥槽
+    #[clap(visible_alias("ls"))]
+    List,
+    Add(KeyAddCmd),
+    #[clap(visible_alias("rm"))]
+    Remove(KeyRmCmd),
+}
+
+/// 添加密钥槽
+#[derive(Debug, Parser)]
+pub struct KeyAddCmd {
+    /// 密钥槽类型：
+    ///
+    /// - passphrase: 交互式输入一个新口令
+    ///
+    /// - keyfile: 使用-f选项指定的文件作为密钥文件
+    ///
+    /// - recovery: 随机生成恢复码并输出，恢复码只会显示这一次
+    #[clap(value_enum)]
+    pub kind: KeyKind,
+
+    /// 密钥文件，添加keyfile类型的密钥槽时必须指定
+    #[clap(short, long, required_if_eq("kind", "keyfile"))]
+    pub file: Option<PathBuf>,
+
+    /// 密钥槽的描述信息，例如谁持有该凭据
+    #[clap(short, long, default_value = "")]
+    pub desc: String,
+}
+
+#[derive(Debug, ValueEnum, Copy, Clone)]
+pub enum KeyKind {
+    Passphrase,
+    Keyfile,
+    Recovery,
+}
+
+/// 删除密钥槽，之后无法再使用该密钥槽对应的凭据打开密码库
+///
+/// 主密码密钥槽不能删除
+#[derive(Debug, Parser)]
+pub struct KeyRmCmd {
+    /// 密钥槽编号(见`key list`的输出)
+    pub id: u32,
+}
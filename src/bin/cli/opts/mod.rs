@@ -1,17 +1,52 @@
 pub mod add_cmd;
+pub mod attach_cmd;
 pub mod gen_cmd;
+pub mod history_cmd;
+pub mod kdf_cmd;
+pub mod key_cmd;
+pub mod tag_cmd;
+pub mod trash_cmd;
 
-use clap::{Parser, Subcommand, ValueEnum};
+use clap::{ArgGroup, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
 
-pub use add_cmd::InsertCmd;
+pub use add_cmd::{InsertCmd, KindCmd};
+pub use attach_cmd::{
+    AttachAddCmd, AttachCmd, AttachCmds, AttachExtractCmd, AttachListCmd, AttachRmCmd,
+};
 pub use gen_cmd::GenPasswdCmd;
+pub use history_cmd::{HistoryCmd, HistoryCmds, HistoryPolicyCmd, HistoryRestoreCmd};
+pub use kdf_cmd::{KdfCmd, KdfCmds, KdfRekeyCmd, KdfTuneCmd};
+pub use key_cmd::{KeyAddCmd, KeyCmd, KeyCmds, KeyKind, KeyRmCmd};
+pub use tag_cmd::{TagCmd, TagCmds, TagEditCmd};
+pub use trash_cmd::{TrashCmd, TrashCmds};
 
 /// 管理密码、生成随机密码、导入导出密码
 ///
 /// 在各个子命令中，任何一个需要提供主密码的地方，都可以通过设置环境变量`PSER_PASSWD`来提供，
 /// 如果没有提供，在需要主密码的地方，将交互式提示你输入密码
+///
+/// 密码库的位置可通过`--vault`选项、环境变量`PSER_DB_DIR`/`PSER_REPLICA`或配置文件指定，
+/// 使用`path`子命令可查看当前生效的密码库位置
+///
+/// 除主密码外，还可以使用`key`子命令添加的口令、恢复码(在提示输入密码时输入)或密钥文件(`--keyfile`)打开密码库
+///
+/// 退出码：1 其它错误，2 参数错误，3 密码错误，4 解锁失败次数过多需要等待，
+/// 6 密码库数据损坏，7 无法识别的URL，8 无效的查询表达式，
+/// 9 修改已保存但部分副本写入失败
 #[derive(Debug, Parser)]
 pub struct Opts {
+    /// 指定主密码库文件，优先级高于环境变量`PSER_DB_DIR`和配置文件
+    #[clap(long, global = true)]
+    pub vault: Option<PathBuf>,
+
+    /// 指定密钥文件，也可以通过环境变量`PSER_KEYFILE`指定
+    ///
+    /// 如果主密码需要配合密钥文件使用(见`init --gen-keyfile`)，则需同时提供主密码和密钥文件；
+    /// 如果通过`key add keyfile`添加了该密钥文件，则提示输入密码时直接回车即可
+    #[clap(long, global = true)]
+    pub keyfile: Option<PathBuf>,
+
     #[clap(subcommand)]
     pub cmds: Cmds,
 }
@@ -20,13 +55,13 @@ pub struct Opts {
 pub enum Cmds {
     #[clap(visible_alias("g"))]
     Gen(GenPasswdCmd),
-    /// 初始化(创建)密码库，
-    /// 如果当前已经存在密码库，则仅仅只是验证输入的密码是否正确
-    Init,
+    Init(InitCmd),
     #[clap(visible_alias("q"))]
     Query(QueryCmd),
+    #[clap(visible_alias("p"))]
+    Pick(PickCmd),
     #[clap(visible_alias("i"))]
-    Insert(InsertCmd),
+    Insert(Box<InsertCmd>),
     #[clap(visible_alias("r"))]
     Rm(RmCmd),
     #[clap(visible_alias("d"))]
@@ -34,27 +69,99 @@ pub enum Cmds {
     Reset(ResetCmd),
     Import(ImportCmd),
     Export(ExportCmd),
-    #[clap(hide(true))]
-    Path
+    /// 查看当前生效的各密码库文件位置及其状态
+    Path,
+    Key(KeyCmd),
+    Status(StatusCmd),
+    Kdf(KdfCmd),
+    Migrate(MigrateCmd),
+    Otp(OtpCmd),
+    Attach(AttachCmd),
+    Tree(TreeCmd),
+    Mv(MvCmd),
+    Tag(TagCmd),
+    History(HistoryCmd),
+    Log(LogCmd),
+    Undo(UndoCmd),
+    Trash(TrashCmd),
+}
+
+/// 初始化(创建)密码库，
+/// 如果当前已经存在密码库，则仅仅只是验证输入的密码是否正确
+#[derive(Debug, Parser)]
+pub struct InitCmd {
+    /// 生成一个随机密钥文件，之后需要同时提供主密码和该密钥文件才能打开密码库，
+    /// 即使密码库文件被盗且主密码泄露，没有密钥文件也无法打开密码库
+    #[clap(long, value_name = "FILE")]
+    pub gen_keyfile: Option<PathBuf>,
 }
 
 /// 搜索密码库中的密码信息。
 ///
-/// 搜索时，只根据密码的所属url或所属desc进行搜索(忽略大小写)
+/// 搜索关键字为查询表达式：只写值时模糊搜索url、desc、username、email、tag、comment和自定义字段
+/// (按顺序包含值中的各个字符即可，忽略大小写)，结果按相关度排序，
+/// `字段:值`搜索指定字段(user、url、desc、email、phone、comment、tag、folder、kind、field)，
+/// 值可以使用通配符`*`、`?`或`/正则表达式/`；`has:otp`等只输出有该项内容的密码信息；
+/// `modified<30d`、`used>1y`等按时间过滤(单位h、d、w、m、y)；
+/// 多个条件之间默认为AND，也可以使用OR、NOT(或`-`)和括号
+///
+/// 例如, 搜索google的账号信息：$0 query "google"，
+/// 搜索公司邮箱注册的github账号：$0 query "url:github email:*@corp.com -tag:old"
 ///
-/// 例如, 搜索google的账号信息：$0 query "google"
+/// 指定了搜索关键字时，搜索出的密码信息将记录为在当前时间被使用
 #[derive(Debug, Parser)]
 pub struct QueryCmd {
     /// 简略输出搜索结果：只输出uuid、url、username、password
     #[clap(short, long)]
     pub short: bool,
-    /// 指定搜索关键字，如果省略，则列出密码库中所有信息
+    /// 显示受保护的自定义字段的值
+    #[clap(long)]
+    pub reveal: bool,
+    /// 按指定的时间排序输出，较新的在前，默认按相关度(不搜索时按输出内容)排序
+    #[clap(long, value_enum)]
+    pub sort: Option<SortBy>,
+    /// 只输出最近DAYS天内修改过的密码信息
+    #[clap(long, value_name = "DAYS")]
+    pub modified_within: Option<u32>,
+    /// 只输出DAYS天内没有使用过(包括从未使用过)的密码信息
+    #[clap(long, value_name = "DAYS")]
+    pub unused_for: Option<u32>,
+    /// 只输出带有该标签的密码信息，多次指定时需带有所有标签
+    #[clap(short, long)]
+    pub tag: Vec<String>,
+    /// 只输出该文件夹(包括其子文件夹)中的密码信息
+    #[clap(short, long)]
+    pub folder: Option<String>,
+    /// 最多输出N条密码信息(排序后的前N条)
+    #[clap(short = 'n', long, value_name = "N")]
+    pub limit: Option<usize>,
+    /// 指定搜索关键字(查询表达式)，如果省略，则列出密码库中所有信息
     pub str: Option<String>,
 }
 
-/// 删除或清空密码信息
+/// 搜索结果的排序方式
+#[derive(Debug, Clone, Copy, ValueEnum)]
+pub enum SortBy {
+    /// 创建时间
+    Created,
+    /// 修改时间
+    Modified,
+    /// 最近使用时间
+    Used,
+}
+
+/// 查看当前的一次性密码(2FA)及其剩余有效秒数
+///
+/// HOTP每查看一次，计数器加1
+#[derive(Debug, Parser)]
+pub struct OtpCmd {
+    /// UUID(前缀)或查询表达式(见query子命令)，匹配到多条配置了一次性密码的密码信息时交互式选择
+    pub str: String,
+}
+
+/// 删除或清空密码信息，删除的密码信息将移到回收站(见trash子命令)
 ///
-/// 需指定UUID，如果不知道UUID，可先通过query子命令查询
+/// 需指定UUID或文件夹，如果不知道UUID，可先通过query子命令查询
 #[derive(Debug, Parser)]
 pub struct RmCmd {
     /// 指定UUID(前缀)来选择删除哪个密码，
@@ -62,7 +169,80 @@ pub struct RmCmd {
     /// - 可通过逗号分隔多个UUID(前缀)
     ///
     /// - 如果UUID指定为特殊值`all`(不区分大小写)，则清空密码库中的所有密码信息
-    pub uuid: String,
+    #[clap(required_unless_present = "folder")]
+    pub uuid: Option<String>,
+
+    /// 删除该文件夹(包括其子文件夹)中的所有密码信息
+    #[clap(short, long, conflicts_with = "uuid")]
+    pub folder: Option<String>,
+}
+
+/// 以树状结构列出各文件夹中的密码信息
+#[derive(Debug, Parser)]
+pub struct TreeCmd {
+    /// 只列出该文件夹(包括其子文件夹)，省略时列出所有密码信息
+    pub folder: Option<String>,
+}
+
+/// 将密码信息移动到另一个文件夹
+///
+/// 例如，将两条密码信息移动到work/aws/prod：$0 mv -i d5963ef,a1b2c3 work/aws/prod
+///
+/// 将文件夹work/aws(包括其子文件夹)移动到cloud下，成为cloud/aws：$0 mv -f work/aws cloud
+#[derive(Debug, Parser)]
+#[clap(group(ArgGroup::new("src").required(true).args(["uuid", "folder"])))]
+pub struct MvCmd {
+    /// 要移动的密码信息的UUID(前缀)，可通过逗号分隔多个UUID(前缀)
+    #[clap(short = 'i', long)]
+    pub uuid: Option<String>,
+
+    /// 要移动的文件夹
+    #[clap(short, long)]
+    pub folder: Option<String>,
+
+    /// 目标文件夹，`/`表示根目录
+    pub dest: String,
+}
+
+/// 查看操作日志，最近的操作在前
+///
+/// 每次添加、修改、删除密码信息都会记录该密码信息在操作前后的完整状态，最多保留最近的1000个操作
+#[derive(Debug, Parser)]
+pub struct LogCmd {
+    /// UUID(前缀)，只列出修改了该密码信息的操作，并显示每次操作后的版本
+    pub uuid: Option<String>,
+
+    /// 最多列出几个操作
+    #[clap(short, long, default_value_t = 20)]
+    pub num: usize,
+
+    /// 显示各版本中的密码等敏感信息(只在指定UUID时有效)
+    #[clap(short, long, requires = "uuid")]
+    pub reveal: bool,
+
+    /// 清空操作日志，之后无法再撤销之前的操作
+    #[clap(long, conflicts_with_all = ["uuid", "reveal"])]
+    pub clear: bool,
+}
+
+/// 撤销最近的操作，撤销后密码信息恢复为操作前的状态，操作前不存在的密码信息将移到回收站
+///
+/// 撤销本身不能被撤销，已被撤销的操作将被跳过
+#[derive(Debug, Parser)]
+pub struct UndoCmd {
+    /// 撤销最近的几个操作
+    #[clap(default_value_t = 1)]
+    pub n: usize,
+}
+
+/// 交互式选择一条密码信息，然后显示、复制、修改、删除它或查看其一次性密码
+///
+/// 例如，在github的账号中选择：$0 pick github，选择时输入字符可进一步过滤列表。
+/// 复制时使用终端的OSC 52转义序列写入剪贴板，需要终端支持
+#[derive(Debug, Parser)]
+pub struct PickCmd {
+    /// 查询表达式(见query子命令)，如果省略，则在所有密码信息中选择
+    pub str: Option<String>,
 }
 
 /// 删除密码库文件
@@ -72,17 +252,62 @@ pub struct DropCmd {
     #[clap(short, long)]
     pub main: bool,
 
-    /// 删除从密码库文件，同时指定-m选项将同时删除主从密码库文件
+    /// 删除所有从密码库文件，同时指定-m选项将同时删除主从密码库文件
     #[clap(short, long = "sec")]
     pub secondary: bool,
+
+    /// 删除第N个密码库文件(序号参考path子命令的输出，主密码库为1)，可多次指定
+    #[clap(short, long)]
+    pub nth: Vec<usize>,
 }
 
 /// 重置主密码
+///
+/// 如果主密码原本需要配合密钥文件使用，默认继续使用当前的密钥文件(通过`--keyfile`提供)
 #[derive(Debug, Parser)]
 pub struct ResetCmd {
     /// 指定新密码
     #[clap(short, long = "new")]
     pub new_passwd: String,
+
+    /// 之后需要同时提供新主密码和该密钥文件才能打开密码库
+    #[clap(long, value_name = "FILE")]
+    pub new_keyfile: Option<PathBuf>,
+
+    /// 之后只需提供主密码即可打开密码库，不再需要密钥文件
+    #[clap(long, conflicts_with = "new_keyfile")]
+    pub no_keyfile: bool,
+}
+
+/// 查看最近的解锁失败记录和锁定策略，指定选项时修改锁定策略
+///
+/// 连续失败的次数超过允许的次数后，每次尝试解锁前都需要等待，等待时间随失败次数成倍增长，
+/// 成功解锁后重新计数
+///
+/// 例如，允许连续失败5次，之后从等待10秒开始，最长等待1天：$0 status --free 5 --delay 10 --max 86400
+#[derive(Debug, Parser)]
+pub struct StatusCmd {
+    /// 允许连续失败的次数
+    #[clap(long)]
+    pub free: Option<u32>,
+
+    /// 超过允许的次数后第一次需要等待的秒数，之后每失败一次翻倍
+    #[clap(long)]
+    pub delay: Option<u64>,
+
+    /// 最长等待秒数
+    #[clap(long)]
+    pub max: Option<u64>,
+}
+
+/// 将旧版本的密码库升级为当前格式
+///
+/// 使用新版本的程序打开旧版本的密码库时会自动升级，可先通过`--dry-run`查看升级时将修改什么
+#[derive(Debug, Parser)]
+pub struct MigrateCmd {
+    /// 只输出各密码库文件的格式版本以及需要执行的升级步骤，不修改密码库，也无需输入主密码
+    #[clap(long)]
+    pub dry_run: bool,
 }
 
 /// 导入密码信息
@@ -101,7 +326,7 @@ pub struct ImportCmd {
     /// 如果不是字段名称不对，应修改csv文件第一行的csv头部，将其对应为这几个字段名。
     ///
     /// 2.如果导入的是json文件格式，则是来自本程序 export 子命令的导出数据，只要导出后未曾修改过文件，则没有格式限制。
-    /// 
+    ///
     /// 且如果某条导入密码数据的uuid和当前库中某密码信息的uuid重复时，将覆盖当前密码库中的密码信息。
     #[clap(short, long)]
     pub input: Option<String>,
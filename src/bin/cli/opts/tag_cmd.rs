@@ -0,0 +1,36 @@
+use clap::{ArgGroup, Parser, Subcommand};
+
+/// 管理密码信息的标签
+///
+/// 例如，为文件夹work中的所有密码信息添加标签company：$0 tag add company -f work
+#[derive(Debug, Parser)]
+pub struct TagCmd {
+    #[clap(subcommand)]
+    pub cmds: TagCmds,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TagCmds {
+    /// 列出所有标签及带有该标签的密码信息数量
+    #[clap(visible_alias("ls"))]
+    List,
+    /// 添加标签
+    Add(TagEditCmd),
+    /// 删除标签
+    Rm(TagEditCmd),
+}
+
+#[derive(Debug, Parser)]
+#[clap(group(ArgGroup::new("target").required(true).args(["uuid", "folder"])))]
+pub struct TagEditCmd {
+    /// 标签
+    pub tag: String,
+
+    /// UUID(前缀)，可通过逗号分隔多个UUID(前缀)
+    #[clap(short = 'i', long)]
+    pub uuid: Option<String>,
+
+    /// 文件夹(包括其子文件夹)中的所有密码信息
+    #[clap(short, long)]
+    pub folder: Option<String>,
+}
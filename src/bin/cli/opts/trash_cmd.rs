@@ -0,0 +1,24 @@
+use clap::{Parser, Subcommand};
+
+/// 管理回收站，rm子命令删除的密码信息(及其附件)先移到回收站
+///
+/// 例如，恢复回收站中的某条密码信息：$0 trash restore d5963ef
+#[derive(Debug, Parser)]
+pub struct TrashCmd {
+    #[clap(subcommand)]
+    pub cmds: TrashCmds,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TrashCmds {
+    /// 列出回收站中的密码信息
+    #[clap(visible_alias("ls"))]
+    List,
+    /// 将密码信息恢复到密码库中
+    Restore {
+        /// UUID(前缀)，可通过逗号分隔多个UUID(前缀)，`all`表示恢复所有
+        uuid: String,
+    },
+    /// 清空回收站，回收站中的密码信息及其附件将被永久删除
+    Empty,
+}
@@ -1,77 +1,422 @@
 //! 处理加密数据库文件
 //!
-//! 格式：验证头大小(u16) + 验证头 + 加密数据
-//!   验证头大小：VerifyHeader Type 的 bincode 序列化后的长度
-//!   验证头：VerifyHeader Type 的 bincode 序列化
-//!   加密数据：密码数据被加密后的 EncryptData 的 bincode 格式
+//! 每个密码库文件都是一个redb数据库，包含以下几个表：
+//!   passwd表：key为&str，保存格式版本(`format`，见[`migrate`](crate::migrate))、
+//!     验证头(`header`，VerifyHeader Type 的 bincode 序列化)和旧密码的保留策略(`history_policy`)，
+//!     验证头的各密钥槽中保存了被主密码、密钥文件等凭据分别加密的数据密钥
+//!   entries表：key为uuid，每条密码信息一行，value为使用数据密钥加密后的 EncryptData 的 bincode 格式
+//!   attachments表：key为附件id，value为使用数据密钥加密后的附件内容，密码信息中只保存附件的元数据，
+//!     读取密码信息时不需要读取附件内容
+//!   journal表：key为操作的序号，value为使用数据密钥加密后的操作记录(见[`journal`](crate::journal))
+//!
+//! 旧版本的密码库打开时会自动升级为当前格式
 
 use crate::{
     error::{Error, PserResult},
-    pser::{Pser, Psers},
-    verify_header::VerifyHeader,
-    DB_FILE_CUR, DB_FILE_HOME,
+    filter::Filter,
+    journal::{Change, Operation, Trashed},
+    merge::{self, Conflict, SyncMeta},
+    migrate::{self, FORMAT_VERSION},
+    otp::OtpKind,
+    pser::{Attachment, HistoryPolicy, Pser, Psers},
+    throttle::{LockoutPolicy, Throttle, UnlockReport},
+    vault_paths::VaultPaths,
+    verify_header::{Credential, Denied, KeySlot, SlotKind, Unlocked, VerifyHeader},
 };
-use crypt::EncryptData;
-use redb::{Database, ReadableTableMetadata, TableDefinition};
+use anyhow::anyhow;
+use chrono_ext::now8;
+use crypt::{EncryptData, KdfParams};
+use redb::{Database, ReadableTable, ReadableTableMetadata, TableDefinition};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha512};
-use std::{io, path::Path};
+use std::{
+    collections::{HashMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+};
 use uuid::Uuid;
+use zeroize::{Zeroize, Zeroizing};
 
 /// 表名(该表的key为&str，value为bincode编码后的字节数据)
 const TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("passwd");
+/// 保存各密码信息的表(key为uuid，value为使用数据密钥加密后的`Row`)
+const ENTRY_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("entries");
+/// 保存附件内容的表(key为附件id，value为使用数据密钥加密后的附件内容)
+const ATTACH_TABLE: TableDefinition<&str, &[u8]> = TableDefinition::new("attachments");
+/// 保存操作日志的表(key为操作的序号，value为使用数据密钥加密后的`JournalRecord`)
+const JOURNAL_TABLE: TableDefinition<u64, &[u8]> = TableDefinition::new("journal");
+/// 操作日志最多保留的操作数量，超出时删除最早的操作
+const JOURNAL_CAPACITY: u64 = 1000;
+/// TABLE表中代表格式版本的key
+const FORMAT_KEY: &str = "format";
 /// TABLE表中代表验证头数据的key
 const HEADER_KEY: &str = "header";
-/// TABLE表中代表数据部分的key
+/// TABLE表中代表旧密码保留策略的key，未设置时保留所有旧密码
+const HISTORY_POLICY_KEY: &str = "history_policy";
+/// 旧版本中，TABLE表中代表数据部分(整个Psers加密后的数据)的key
 const DATA_KEY: &str = "data";
 
+/// ENTRY_TABLE表中每一行加密前的数据
+#[derive(Debug, Serialize, Deserialize)]
+enum Row {
+    /// 密码信息(包含其修改时间和修订版本号)
+    Pser(Box<Pser>),
+    /// 已删除的密码信息(墓碑)及其删除的时间点(秒级Epoch)，合并副本时使用
+    Tombstone { deleted: i64 },
+    /// 回收站中的密码信息及其移到回收站的时间点，合并副本时视为墓碑
+    Trashed { deleted: i64, pser: Box<Pser> },
+}
+
+/// 操作日志中一次操作加密前的数据
+#[derive(Debug, Serialize, Deserialize)]
+struct JournalRecord {
+    /// 写入时的格式版本，changes中的各状态按该版本的布局编码，读取时转换为当前布局
+    version: u32,
+    time: i64,
+    desc: String,
+    undoes: Vec<u64>,
+    /// (uuid, 操作前的状态, 操作后的状态)，状态为`Row`的bincode数据，不存在时为墓碑
+    changes: Vec<(String, Vec<u8>, Vec<u8>)>,
+}
+
+impl Drop for JournalRecord {
+    fn drop(&mut self) {
+        for (_, before, after) in &mut self.changes {
+            before.zeroize();
+            after.zeroize();
+        }
+    }
+}
+
+/// 回收站，key为uuid
+type Trash = HashMap<String, Trashed>;
+
+/// 从密码库中读取的数据：密码信息及其同步信息、回收站
+type VaultData = (Psers, SyncMeta, Trash);
+
+/// 数据密钥，旧版本的密码库没有数据密钥时为None
+type DataKey = Option<Zeroizing<Vec<u8>>>;
+
+/// 某个副本的格式信息，见`SyncDb::formats`
+#[derive(Debug, Clone)]
+pub struct FormatInfo {
+    /// 格式版本
+    pub version: u32,
+    /// entries表中的行数
+    pub rows: usize,
+}
+
+/// 在一个事务中写入各副本的数据
+#[derive(Debug, Default)]
+pub struct Batch {
+    /// TABLE表中要写入(Some)或删除(None)的数据
+    meta: Vec<(String, Option<Vec<u8>>)>,
+    /// ENTRY_TABLE表中要写入的行
+    entries: Vec<(String, Vec<u8>)>,
+    /// ATTACH_TABLE表中要写入(Some)或删除(None)的附件
+    attachments: Vec<(String, Option<Vec<u8>>)>,
+    /// JOURNAL_TABLE表中要写入(Some)或删除(None)的操作记录
+    journal: Vec<(u64, Option<Vec<u8>>)>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 向TABLE表中写入数据
+    pub fn put_meta(&mut self, key: &str, data: Vec<u8>) -> &mut Self {
+        self.meta.push((key.to_string(), Some(data)));
+        self
+    }
+
+    /// 从TABLE表中删除数据
+    pub fn remove_meta(&mut self, key: &str) -> &mut Self {
+        self.meta.push((key.to_string(), None));
+        self
+    }
+
+    /// 向ENTRY_TABLE表中写入一行
+    pub fn put_entry(&mut self, uuid: &str, data: Vec<u8>) -> &mut Self {
+        self.entries.push((uuid.to_string(), data));
+        self
+    }
+
+    /// 向ATTACH_TABLE表中写入附件
+    pub fn put_attachment(&mut self, id: &str, data: Vec<u8>) -> &mut Self {
+        self.attachments.push((id.to_string(), Some(data)));
+        self
+    }
+
+    /// 从ATTACH_TABLE表中删除附件
+    pub fn remove_attachment(&mut self, id: &str) -> &mut Self {
+        self.attachments.push((id.to_string(), None));
+        self
+    }
+
+    /// 向JOURNAL_TABLE表中写入操作记录
+    pub fn put_journal(&mut self, seq: u64, data: Vec<u8>) -> &mut Self {
+        self.journal.push((seq, Some(data)));
+        self
+    }
+
+    /// 从JOURNAL_TABLE表中删除操作记录
+    pub fn remove_journal(&mut self, seq: u64) -> &mut Self {
+        self.journal.push((seq, None));
+        self
+    }
+}
+
+/// 副本(即某个密码库文件)的状态
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReplicaState {
+    /// 正常，且和其它正常副本的数据一致
+    Healthy,
+    /// 副本文件不存在
+    Missing,
+    /// 副本文件原本不存在，已根据正常的副本重新创建
+    Created,
+    /// 副本数据和其它正常副本的数据不一致，且尚未合并
+    Stale,
+    /// 副本数据原本和其它正常副本的数据不一致，已合并
+    Merged,
+    /// 副本文件存在但无法读取或无法打开
+    Unreadable(String),
+}
+
+impl std::fmt::Display for ReplicaState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplicaState::Healthy => write!(f, "正常"),
+            ReplicaState::Missing => write!(f, "不存在"),
+            ReplicaState::Created => write!(f, "已重新创建"),
+            ReplicaState::Stale => write!(f, "数据不一致(未合并)"),
+            ReplicaState::Merged => write!(f, "已合并"),
+            ReplicaState::Unreadable(e) => write!(f, "无法读取({})", e),
+        }
+    }
+}
+
+/// 一个副本(密码库文件)
+struct Replica {
+    path: PathBuf,
+    state: ReplicaState,
+    /// 无法打开的副本为None，读写时将跳过该副本
+    db: Option<Database>,
+}
+
+impl Replica {
+    /// 可写入的副本：已打开，且不是尚未合并的副本
+    fn writable(&self) -> Option<&Database> {
+        match self.state {
+            ReplicaState::Stale => None,
+            _ => self.db.as_ref(),
+        }
+    }
+}
+
+/// 管理多个互相同步的密码库副本
+///
+/// 写数据时写入每一个可用的副本，读数据时从第一个可用的副本中读取。
+/// 数据不一致的副本(Stale)也会被打开，但在合并(见[`PserDB`])之前不会写入该副本
 pub struct SyncDb {
-    /// 家目录下的加密数据库`$HOME/.pser/pser.db`
-    db_home: Database,
-    /// 可执行程序所在目录下的加密数据库`<DIR>/pser.db`
-    db_cur: Database,
+    replicas: Vec<Replica>,
+    /// 读取数据时使用的副本在replicas中的索引
+    source: usize,
 }
 
 impl SyncDb {
-    pub fn new() -> PserResult<Self> {
-        let s = match (DB_FILE_HOME.exists(), DB_FILE_CUR.exists()) {
-            (true, true) => {
-                // 检查主从密码库文件的sha2值，判断主从密码库的数据是否一致
-                check_db_sha2();
-
-                std::fs::copy(&*DB_FILE_HOME, &*DB_FILE_CUR)?;
-                let db_home = redb::Database::create(&*DB_FILE_HOME).map_err(redb::Error::from)?;
-                let db_cur = redb::Database::create(&*DB_FILE_CUR).map_err(redb::Error::from)?;
-                Self { db_home, db_cur }
-            }
-            (true, false) => {
-                std::fs::copy(&*DB_FILE_HOME, &*DB_FILE_CUR)?;
-                let db_home = redb::Database::create(&*DB_FILE_HOME).map_err(redb::Error::from)?;
-                let db_cur = redb::Database::create(&*DB_FILE_CUR).map_err(redb::Error::from)?;
-                Self { db_home, db_cur }
-            }
-            (false, true) => {
-                std::fs::copy(&*DB_FILE_CUR, &*DB_FILE_HOME)?;
-                let db_cur = redb::Database::create(&*DB_FILE_CUR).map_err(redb::Error::from)?;
-                let db_home = redb::Database::create(&*DB_FILE_HOME).map_err(redb::Error::from)?;
-                Self { db_home, db_cur }
-            }
-            // 都不存在，则创建两个，同时创建TABLE表
-            (false, false) => {
-                let db_home = redb::Database::create(&*DB_FILE_HOME).map_err(redb::Error::from)?;
-                let db_cur = redb::Database::create(&*DB_FILE_CUR).map_err(redb::Error::from)?;
-                Self::create_table(&db_home).map_err(redb::Error::from)?;
-                Self::create_table(&db_cur).map_err(redb::Error::from)?;
-
-                Self { db_home, db_cur }
+    /// 打开所有副本，缺失的副本将根据第一个正常的副本重新创建，
+    /// 如果所有副本都不存在，则创建所有副本，同时创建TABLE表
+    pub fn new(paths: &VaultPaths) -> PserResult<Self> {
+        let states = Self::inspect(paths);
+
+        let source_path = states
+            .iter()
+            .find(|(_, s)| *s == ReplicaState::Healthy)
+            .map(|(p, _)| p.clone());
+        // 没有正常的副本，但存在无法读取的副本时，不能新建密码库，以免覆盖原有数据
+        if source_path.is_none()
+            && states
+                .iter()
+                .any(|(_, s)| matches!(s, ReplicaState::Unreadable(_)))
+        {
+            return Err(Error::Other(anyhow!("所有密码库副本都无法读取")));
+        }
+
+        let mut replicas = vec![];
+        for (path, state) in states {
+            let state = match state {
+                // 无法创建的副本(例如所在的移动硬盘未挂载)视为无法读取，不影响其它副本
+                ReplicaState::Missing => match create_replica(&path, source_path.as_deref()) {
+                    Ok(_) if source_path.is_some() => ReplicaState::Created,
+                    // 新建的密码库
+                    Ok(_) => ReplicaState::Healthy,
+                    Err(e) => ReplicaState::Unreadable(e.to_string()),
+                },
+                state => state,
+            };
+
+            let (state, db) = match state {
+                ReplicaState::Unreadable(e) => (ReplicaState::Unreadable(e), None),
+                state => match Database::create(&path) {
+                    Ok(db) => (state, Some(db)),
+                    Err(e) => (ReplicaState::Unreadable(e.to_string()), None),
+                },
+            };
+            replicas.push(Replica { path, state, db });
+        }
+
+        let source = replicas
+            .iter()
+            .position(|r| r.writable().is_some())
+            .ok_or_else(|| Error::Other(anyhow!("所有密码库副本都无法打开")))?;
+
+        // 都不存在，则为每个副本创建TABLE表
+        if source_path.is_none() {
+            for db in replicas.iter().filter_map(|r| r.db.as_ref()) {
+                Self::create_table(db)?;
             }
+        }
+
+        Ok(Self { replicas, source })
+    }
+
+    /// 检查各副本的状态，不会创建或修改任何副本文件
+    ///
+    /// 第一个能读取的副本作为基准，数据和基准一致的副本为Healthy，不一致的为Stale
+    pub fn inspect(paths: &VaultPaths) -> Vec<(PathBuf, ReplicaState)> {
+        let mut base_sha2: Option<Vec<u8>> = None;
+        let mut states = vec![];
+        for path in paths.replicas() {
+            let state = match path.exists() {
+                false => ReplicaState::Missing,
+                true => match file_sha2(path) {
+                    Err(e) => ReplicaState::Unreadable(e.to_string()),
+                    Ok(sha2) => match &base_sha2 {
+                        None => {
+                            base_sha2 = Some(sha2);
+                            ReplicaState::Healthy
+                        }
+                        Some(base) if *base == sha2 => ReplicaState::Healthy,
+                        Some(_) => ReplicaState::Stale,
+                    },
+                },
+            };
+            states.push((path.clone(), state));
+        }
+        states
+    }
+
+    /// 读取各副本的格式信息，只读取，不会创建或修改任何副本文件(不存在的副本不包含在内)
+    pub fn formats(paths: &VaultPaths) -> Vec<(PathBuf, PserResult<FormatInfo>)> {
+        let format = |path: &Path| -> PserResult<FormatInfo> {
+            let db = Database::open(path)?;
+            Ok(FormatInfo {
+                version: read_version(|key| Self::_read_db(&db, key))?,
+                rows: Self::_read_entries(&db)?.len(),
+            })
         };
+        paths
+            .replicas()
+            .iter()
+            .filter(|path| path.exists())
+            .map(|path| (path.clone(), format(path)))
+            .collect()
+    }
 
-        Ok(s)
+    /// 各副本的路径及其状态
+    pub fn replicas(&self) -> Vec<(&Path, &ReplicaState)> {
+        self.replicas
+            .iter()
+            .map(|r| (r.path.as_path(), &r.state))
+            .collect()
+    }
+
+    /// 数据不一致且尚未合并的副本的索引
+    pub fn stale_replicas(&self) -> Vec<usize> {
+        (0..self.replicas.len())
+            .filter(|&i| {
+                self.replicas[i].state == ReplicaState::Stale && self.replicas[i].db.is_some()
+            })
+            .collect()
+    }
+
+    /// 第idx个副本的路径
+    pub fn replica_path(&self, idx: usize) -> &Path {
+        &self.replicas[idx].path
+    }
+
+    /// 从第idx个副本的TABLE表中读取指定key的数据，用于读取尚未合并的副本
+    pub fn read_replica(&self, idx: usize, key: &str) -> PserResult<Option<Vec<u8>>> {
+        match &self.replicas[idx].db {
+            Some(db) => Self::_read_db(db, key),
+            None => Ok(None),
+        }
+    }
+
+    /// 读取第idx个副本的ENTRY_TABLE表中的所有行，用于读取尚未合并的副本
+    pub fn read_replica_entries(&self, idx: usize) -> PserResult<Vec<(String, Vec<u8>)>> {
+        match &self.replicas[idx].db {
+            Some(db) => Self::_read_entries(db),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// 从第idx个副本的ATTACH_TABLE表中读取附件，用于读取尚未合并的副本
+    pub fn read_replica_attachment(&self, idx: usize, id: &str) -> PserResult<Option<Vec<u8>>> {
+        match &self.replicas[idx].db {
+            Some(db) => Self::_read_attachment(db, id),
+            None => Ok(None),
+        }
+    }
+
+    /// 第idx个副本中的数据无法读取或解密时，将其标记为无法读取并关闭，之后读写时都将跳过该副本
+    pub fn set_unreadable(&mut self, idx: usize, reason: String) {
+        self.replicas[idx].state = ReplicaState::Unreadable(reason);
+        self.replicas[idx].db = None;
+    }
+
+    /// 写入失败的副本重新标记为Stale并关闭，之后读写时都将跳过这些副本，下次打开密码库时再合并
+    pub fn set_stale(&mut self, paths: &[PathBuf]) {
+        for replica in self.replicas.iter_mut().filter(|r| paths.contains(&r.path)) {
+            replica.state = ReplicaState::Stale;
+            replica.db = None;
+        }
+    }
+
+    /// 第idx个副本合并完成后，使用读取数据的副本覆盖它，使各副本的数据保持一致
+    pub fn resync(&mut self, idx: usize) -> PserResult<()> {
+        // 先关闭两个副本再覆盖，关闭时redb会写入文件的状态信息，因此不能在打开状态下复制
+        self.replicas[self.source].db = None;
+        self.replicas[idx].db = None;
+
+        let src = self.replicas[self.source].path.clone();
+        let dst = self.replicas[idx].path.clone();
+        let copied = std::fs::copy(&src, &dst);
+
+        // 复制失败时也要重新打开，读取数据的副本必须处于打开状态
+        self.replicas[self.source].db = Some(Database::create(&src)?);
+        self.replicas[idx].db = Some(Database::create(&dst)?);
+        copied?;
+        self.replicas[idx].state = ReplicaState::Merged;
+        Ok(())
+    }
+
+    fn source_db(&self) -> &Database {
+        self.replicas[self.source]
+            .db
+            .as_ref()
+            .expect("source replica must be opened")
     }
 
     /// 从Self::TABLE表中读取指定key的数据，返回字节数据(Vec格式)
-    pub fn read_db(&self, key: &str) -> Result<Option<Vec<u8>>, redb::Error> {
-        let open_trx = self.db_home.begin_read()?;
+    pub fn read_db(&self, key: &str) -> PserResult<Option<Vec<u8>>> {
+        Self::_read_db(self.source_db(), key)
+    }
+
+    fn _read_db(db: &Database, key: &str) -> PserResult<Option<Vec<u8>>> {
+        let open_trx = db.begin_read()?;
         let table = open_trx.open_table(TABLE)?;
         let res = table.get(key)?;
         match res {
@@ -80,35 +425,180 @@ impl SyncDb {
         }
     }
 
-    pub fn write_db(&self, key: &str, data: &[u8]) -> Result<(), redb::Error> {
-        Self::_write_db(&self.db_home, key, data)?;
-        Self::_write_db(&self.db_cur, key, data)?;
-        Ok(())
+    /// 读取ENTRY_TABLE表中的所有行(uuid, 加密数据)
+    pub fn read_entries(&self) -> PserResult<Vec<(String, Vec<u8>)>> {
+        Self::_read_entries(self.source_db())
+    }
+
+    fn _read_entries(db: &Database) -> PserResult<Vec<(String, Vec<u8>)>> {
+        let open_trx = db.begin_read()?;
+        // 旧版本的密码库中没有该表
+        let table = match open_trx.open_table(ENTRY_TABLE) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut rows = vec![];
+        for row in table.iter()? {
+            let (k, v) = row?;
+            rows.push((k.value().to_string(), v.value().to_vec()));
+        }
+        Ok(rows)
+    }
+
+    /// 从ATTACH_TABLE表中读取附件(加密数据)
+    pub fn read_attachment(&self, id: &str) -> PserResult<Option<Vec<u8>>> {
+        Self::_read_attachment(self.source_db(), id)
+    }
+
+    fn _read_attachment(db: &Database, id: &str) -> PserResult<Option<Vec<u8>>> {
+        let open_trx = db.begin_read()?;
+        // 没有添加过附件的密码库中没有该表
+        let table = match open_trx.open_table(ATTACH_TABLE) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let res = table.get(id)?;
+        Ok(res.map(|v| v.value().to_vec()))
+    }
+
+    /// 读取JOURNAL_TABLE表中的所有操作记录(序号, 加密数据)，按序号排列
+    pub fn read_journal(&self) -> PserResult<Vec<(u64, Vec<u8>)>> {
+        let open_trx = self.source_db().begin_read()?;
+        // 旧版本的密码库中没有该表
+        let table = match open_trx.open_table(JOURNAL_TABLE) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(vec![]),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut records = vec![];
+        for row in table.iter()? {
+            let (k, v) = row?;
+            records.push((k.value(), v.value().to_vec()));
+        }
+        Ok(records)
+    }
+
+    /// JOURNAL_TABLE表中最后一个操作记录的序号，没有操作记录时为0
+    pub fn last_journal_seq(&self) -> PserResult<u64> {
+        let open_trx = self.source_db().begin_read()?;
+        let table = match open_trx.open_table(JOURNAL_TABLE) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(0),
+            Err(e) => return Err(e.into()),
+        };
+        let last = table.last()?.map(|(k, _)| k.value());
+        Ok(last.unwrap_or_default())
+    }
+
+    /// ATTACH_TABLE表中所有附件的id
+    pub fn attachment_ids(&self) -> PserResult<HashSet<String>> {
+        let open_trx = self.source_db().begin_read()?;
+        let table = match open_trx.open_table(ATTACH_TABLE) {
+            Ok(table) => table,
+            Err(redb::TableError::TableDoesNotExist(_)) => return Ok(HashSet::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut ids = HashSet::new();
+        for row in table.iter()? {
+            let (k, _) = row?;
+            ids.insert(k.value().to_string());
+        }
+        Ok(ids)
+    }
+
+    /// 写入每一个可用的副本(和`commit`相同，各副本只使用一个事务)
+    pub fn write_db(&self, key: &str, data: &[u8]) -> PserResult<()> {
+        self.commit(Batch::new().put_meta(key, data.to_vec()))
+    }
+
+    /// 将batch写入每一个可用的副本，每个副本只使用一个事务
+    ///
+    /// 先在所有副本中写入数据，全部成功后才依次提交，尽量避免只有部分副本被修改。
+    /// 首先提交读取数据的副本，它提交失败时其它副本的事务都将回滚，各副本仍然一致；
+    /// 之后其它副本提交失败时，仍继续提交剩余的副本，最后返回`Error::StaleReplicas`，
+    /// 列出未能写入的副本，它们的数据和读取数据的副本不再一致，下次打开密码库时将作为Stale副本重新合并
+    pub fn commit(&self, batch: &Batch) -> PserResult<()> {
+        let mut trxs = vec![];
+        for (idx, replica) in self.replicas.iter().enumerate() {
+            let Some(db) = replica.writable() else {
+                continue;
+            };
+            let open_trx = db.begin_write()?;
+            {
+                let mut table = open_trx.open_table(TABLE)?;
+                for (key, data) in &batch.meta {
+                    match data {
+                        Some(data) => table.insert(key.as_str(), data.as_slice())?,
+                        None => table.remove(key.as_str())?,
+                    };
+                }
+                let mut entries = open_trx.open_table(ENTRY_TABLE)?;
+                for (uuid, data) in &batch.entries {
+                    entries.insert(uuid.as_str(), data.as_slice())?;
+                }
+                if !batch.attachments.is_empty() {
+                    let mut attachments = open_trx.open_table(ATTACH_TABLE)?;
+                    for (id, data) in &batch.attachments {
+                        match data {
+                            Some(data) => attachments.insert(id.as_str(), data.as_slice())?,
+                            None => attachments.remove(id.as_str())?,
+                        };
+                    }
+                }
+                if !batch.journal.is_empty() {
+                    let mut journal = open_trx.open_table(JOURNAL_TABLE)?;
+                    for (seq, data) in &batch.journal {
+                        match data {
+                            Some(data) => journal.insert(seq, data.as_slice())?,
+                            None => journal.remove(seq)?,
+                        };
+                    }
+                }
+            }
+            trxs.push((idx, open_trx));
+        }
+
+        let pos = trxs
+            .iter()
+            .position(|(idx, _)| *idx == self.source)
+            .expect("source replica must be writable");
+        trxs.remove(pos).1.commit()?;
+
+        let mut stale = vec![];
+        let mut reason = String::new();
+        for (idx, open_trx) in trxs {
+            if let Err(e) = open_trx.commit() {
+                stale.push(self.replicas[idx].path.clone());
+                reason = e.to_string();
+            }
+        }
+        match stale.is_empty() {
+            true => Ok(()),
+            false => Err(Error::StaleReplicas {
+                replicas: stale,
+                reason,
+            }),
+        }
     }
 
     /// 表是否空
-    pub fn is_empty(&self) -> Result<bool, redb::Error> {
-        let read_trx = self.db_home.begin_read()?;
+    pub fn is_empty(&self) -> PserResult<bool> {
+        let read_trx = self.source_db().begin_read()?;
         let tab = read_trx.open_table(TABLE)?;
         Ok(tab.is_empty()?)
     }
 
     /// 建表，初始化时调用
-    fn create_table(db: &Database) -> Result<(), redb::Error> {
+    fn create_table(db: &Database) -> PserResult<()> {
         let open_trx = db.begin_write()?;
         {
             let _ = open_trx.open_table(TABLE)?;
-        }
-        open_trx.commit()?;
-
-        Ok(())
-    }
-
-    fn _write_db(db: &Database, key: &str, data: &[u8]) -> Result<(), redb::Error> {
-        let open_trx = db.begin_write()?;
-        {
-            let mut table = open_trx.open_table(TABLE)?;
-            table.insert(key, data)?;
+            let _ = open_trx.open_table(ENTRY_TABLE)?;
         }
         open_trx.commit()?;
 
@@ -118,12 +608,26 @@ impl SyncDb {
 
 pub struct PserDB {
     db: SyncDb,
-    /// 主密码：解密整个程序的明文密码
-    main_passwd: String,
-    /// 验证头(验证主密码是否正确)
+    /// 打开密码库时使用的凭据(主密码、密钥文件等)
+    cred: Credential,
+    /// 数据密钥：随机生成，用于加密每一条密码信息，它被各凭据加密后保存在验证头的密钥槽中，drop时清零
+    key: Zeroizing<Vec<u8>>,
+    /// 打开密码库时解开的密钥槽编号
+    slot: u32,
+    /// 验证头(验证凭据是否正确)
     header: VerifyHeader,
-    /// 保存或等待保存的各个密码(这些密码通过主密码加密)
+    /// 保存或等待保存的各个密码(这些密码通过主密钥加密)
     psers: Psers,
+    /// 各密码信息的修改时间和删除记录，合并副本时使用
+    meta: SyncMeta,
+    /// 回收站中的密码信息(它们同时也是墓碑)
+    trash: Trash,
+    /// 合并副本时无法自动决定的冲突
+    conflicts: Vec<Conflict>,
+    /// 本次解锁时的报告(上次成功解锁之后的失败记录)
+    report: UnlockReport,
+    /// 旧密码的保留策略，密码信息没有设置自己的保留策略时使用
+    history_policy: HistoryPolicy,
 }
 
 impl PserDB {
@@ -132,102 +636,659 @@ impl PserDB {
     ///
     /// 以下几种情况返回Error：
     /// - 无法打开数据库
-    /// - 读验证头失败
-    /// - 验证失败(包括超出了单位时间内的验证限制次数以及密码验证失败)
+    /// - 读验证头失败(`Error::Corrupt`)
+    /// - 验证失败(连续失败次数过多需要等待时为`Error::RateLimited`，密码验证失败时为`Error::WrongPassword`)
     /// - 读取数据失败
-    pub fn new(main_passwd: &str) -> PserResult<Self> {
-        let db = SyncDb::new()?;
+    pub fn new(paths: &VaultPaths, main_passwd: &str) -> PserResult<Self> {
+        Self::open(paths, &Credential::passwd(main_passwd))
+    }
+
+    /// 和`new`相同，但可以使用任意一个密钥槽对应的凭据(主密码、口令、密钥文件或恢复码)打开密码库
+    ///
+    /// 创建新库时凭据中必须包含主密码，如果凭据中还包含密钥文件，则之后需要同时提供主密码和密钥文件
+    pub fn open(paths: &VaultPaths, cred: &Credential) -> PserResult<Self> {
+        let db = SyncDb::new(paths)?;
         if db.is_empty()? {
-            let s = Self {
+            if cred.passwd.is_none() {
+                return Err(Error::Other(anyhow!("创建密码库需要提供主密码")));
+            }
+            let key = EncryptData::gen_key();
+            let mut s = Self {
                 db,
-                main_passwd: main_passwd.to_string(),
-                header: VerifyHeader::new(main_passwd),
+                cred: cred.clone(),
+                header: VerifyHeader::new(cred, &key)?,
+                key,
+                slot: 0,
                 psers: Psers::default(),
+                meta: SyncMeta::default(),
+                trash: Trash::default(),
+                conflicts: vec![],
+                report: UnlockReport::default(),
+                history_policy: HistoryPolicy::default(),
             };
 
-            s.sync_header()?;
+            // 验证头和格式版本在同一个事务中写入，没有格式版本的密码库视为旧版本
+            let mut batch = Batch::new();
+            batch
+                .put_meta(HEADER_KEY, s.header.encode())
+                .put_meta(FORMAT_KEY, migrate::encode_format());
+            let res = s.db.commit(&batch);
+            s.skip_stale(res)?;
             return Ok(s);
         }
 
-        let header = Self::load_header(&db)?.ok_or(Error::HeaderError)?;
+        let (version, header) =
+            Self::load_header(&db)?.ok_or_else(|| Error::Corrupt("缺少验证头".to_string()))?;
         let mut s = Self {
             db,
-            main_passwd: main_passwd.to_string(),
+            cred: cred.clone(),
+            key: Zeroizing::new(vec![]),
+            slot: 0,
             header,
             psers: Psers::default(),
+            meta: SyncMeta::default(),
+            trash: Trash::default(),
+            conflicts: vec![],
+            report: UnlockReport::default(),
+            history_policy: HistoryPolicy::default(),
         };
-        // 每次都验证头(包括验证主密码是否正确，以及是否超出验证次数限制)，并将验证更新后的验证头入库
-        {
-            let verify_flag = s.header.verify_header(main_passwd);
-            s.sync_header()?;
-            if !verify_flag {
-                eprintln!("密码错误");
-                std::process::exit(1);
-                // return Err(Error::HeaderError);
+        // 每次都验证头(包括验证凭据是否正确，以及是否需要等待)，失败时将记录了本次失败的验证头入库
+        let unlocked = match s.header.unlock(cred) {
+            Ok(unlocked) => unlocked,
+            Err(denied) => {
+                // 失败记录未能写入时仍然返回验证失败的原因；
+                // 验证头只能以当前布局写入，旧版本的密码库在升级之前不保存失败记录，以免无法再读取
+                if version == FORMAT_VERSION {
+                    let _ = s.sync_header();
+                }
+                return Err(match denied {
+                    Denied::WrongCredential => Error::WrongPassword,
+                    Denied::Throttled { retry_after } => Error::RateLimited { retry_after },
+                });
             }
+        };
+
+        (s.psers, s.meta, s.trash) = load_vault(&s.db, None, version, &unlocked, cred)?;
+        if let Some(policy) = s.db.read_db(HISTORY_POLICY_KEY)? {
+            s.history_policy = bincode::deserialize(&policy)?;
+        }
+        match unlocked {
+            Unlocked::Key { slot, key } => (s.slot, s.key) = (slot, key),
+            // 旧版本的密码库没有数据密钥
+            Unlocked::Legacy => s.gen_data_key()?,
         }
+        // 确认之前的失败记录并设置检查点(同时清除旧版本验证头中保存的主密码Sha512)
+        s.report = s.header.checkpoint(&s.key);
+        let res = match version < FORMAT_VERSION {
+            true => s.upgrade(),
+            false => s.sync_header(),
+        };
+        s.skip_stale(res)?;
 
-        let pswd = s.load_psers()?.unwrap_or_default();
-        s.psers = pswd;
+        s.reconcile()?;
 
         Ok(s)
     }
 
-    /// 从数据库中读取验证头
-    pub fn load_header(db: &SyncDb) -> PserResult<Option<VerifyHeader>> {
-        if let Some(bytes) = db.read_db(HEADER_KEY)? {
-            let header = VerifyHeader::decode(&bytes)?;
-            return Ok(Some(header));
+    /// 各副本的路径及其状态
+    pub fn replicas(&self) -> Vec<(&Path, &ReplicaState)> {
+        self.db.replicas()
+    }
+
+    /// 本次解锁时的报告：上次成功解锁之后的失败记录，以及失败记录或锁定策略是否被篡改
+    pub fn unlock_report(&self) -> &UnlockReport {
+        &self.report
+    }
+
+    /// 最近的解锁失败记录和锁定策略
+    pub fn throttle(&self) -> &Throttle {
+        self.header.throttle()
+    }
+
+    /// 修改锁定策略
+    pub fn set_lockout_policy(&mut self, policy: LockoutPolicy) -> PserResult<()> {
+        self.header.set_lockout_policy(policy, &self.key);
+        self.sync_header()?;
+        Ok(())
+    }
+
+    /// 密码库的旧密码保留策略
+    pub fn history_policy(&self) -> &HistoryPolicy {
+        &self.history_policy
+    }
+
+    /// 修改密码库的旧密码保留策略，并按新策略删除各密码信息中过期的旧密码(设置了自己的保留策略的密码信息除外)，
+    /// 返回删除的旧密码数量
+    pub fn set_history_policy(&mut self, policy: HistoryPolicy) -> PserResult<usize> {
+        let now = now8().timestamp();
+        let mut pruned = 0;
+        let mut changed = vec![];
+        for (uuid, pser) in self.psers.inner() {
+            let mut pser = pser.clone();
+            match pser.prune_history(&policy, now) {
+                0 => continue,
+                n => pruned += n,
+            }
+            changed.push((uuid.clone(), pser));
+        }
+
+        self.history_policy = policy;
+        let mut batch = Batch::new();
+        batch.put_meta(HISTORY_POLICY_KEY, bincode::serialize(&policy)?);
+        let changes = self.stage_updates(changed, &mut batch)?;
+        self.stage_journal("修改保留策略", vec![], changes, &mut batch)?;
+        self.db.commit(&batch)?;
+        Ok(pruned)
+    }
+
+    /// 合并副本时无法自动决定的冲突，冲突时保留的是当前版本，
+    /// 如果要采用冲突中的另一个版本，可调用`update`
+    pub fn conflicts(&self) -> &[Conflict] {
+        &self.conflicts
+    }
+
+    /// 旧版本的密码库没有数据密钥：生成数据密钥并使用主密码包装后保存在验证头中
+    fn gen_data_key(&mut self) -> PserResult<()> {
+        let main_passwd = self
+            .cred
+            .passwd
+            .as_deref()
+            .ok_or_else(|| anyhow!("旧版本的密码库需要使用主密码打开"))?;
+        self.key = EncryptData::gen_key();
+        self.header
+            .change_passwd(&Credential::passwd(main_passwd), &self.key)?;
+        self.slot = main_slot_id(&self.header)?;
+        Ok(())
+    }
+
+    /// 将旧版本的密码库升级为当前格式：在一个事务中以当前格式重新写入验证头和每一行(使用数据密钥加密)，
+    /// 记录格式版本，并删除旧版本的数据
+    fn upgrade(&mut self) -> PserResult<()> {
+        let mut batch = self.rows_batch()?;
+        batch
+            .put_meta(HEADER_KEY, self.header.encode())
+            .put_meta(FORMAT_KEY, migrate::encode_format())
+            .remove_meta(DATA_KEY);
+        self.db.commit(&batch)?;
+        Ok(())
+    }
+
+    /// 打开密码库时部分副本写入失败(`Error::StaleReplicas`)不影响打开，
+    /// 这些副本被标记为Stale，本次不再读写，下次打开密码库时重新合并；其它错误原样返回
+    fn skip_stale(&mut self, res: PserResult<()>) -> PserResult<()> {
+        match res {
+            Err(Error::StaleReplicas { replicas, .. }) => {
+                self.db.set_stale(&replicas);
+                Ok(())
+            }
+            res => res,
         }
-        Ok(None)
+    }
+
+    /// 将数据不一致的副本合并到当前数据中，并将合并结果写入每一个副本
+    ///
+    /// 无法使用当前凭据解开的副本不会被合并，其状态仍为Stale，也不会被写入；
+    /// 数据(包括附件)无法读取或解密的副本不会被合并，其状态变为Unreadable，之后不再读写该副本
+    ///
+    /// 合并后的密码信息(包括冲突中的副本版本)引用的附件只存在于该副本中时，复制到各副本中，
+    /// 不再被引用的附件将被删除
+    fn reconcile(&mut self) -> PserResult<()> {
+        let stale = self.db.stale_replicas();
+        if stale.is_empty() {
+            return Ok(());
+        }
+
+        let mut present = self.db.attachment_ids()?;
+        let mut batch = Batch::new();
+        let mut merged = vec![];
+        for idx in stale {
+            // 合并之前先复制该副本引用的附件，附件无法解密时整个副本都不合并
+            let loaded = self.load_replica(idx).and_then(|loaded| match loaded {
+                Some(((psers, meta, trash), Some(key))) => {
+                    let ids = psers
+                        .inner()
+                        .values()
+                        .chain(trash.values().map(|t| &t.pser))
+                        .flat_map(|pser| pser.attachments.iter().map(|a| a.id.clone()))
+                        .collect();
+                    self.copy_attachments(idx, &key, ids, &mut present, &mut batch)?;
+                    Ok(Some((psers, meta, trash)))
+                }
+                Some((data, None)) => Ok(Some(data)),
+                None => Ok(None),
+            });
+            let (psers, meta, trash) = match loaded {
+                Ok(Some(data)) => data,
+                Ok(None) => continue,
+                Err(e) => {
+                    self.db.set_unreadable(idx, e.to_string());
+                    continue;
+                }
+            };
+
+            let replica = self.db.replica_path(idx).to_path_buf();
+            let ours = self.psers.inner().clone();
+            let conflicts = merge::merge(&mut self.psers, &mut self.meta, psers, meta);
+            self.merge_trash(ours, trash);
+            self.conflicts
+                .extend(conflicts.into_iter().map(|(uuid, theirs)| Conflict {
+                    uuid,
+                    replica: replica.clone(),
+                    theirs,
+                }));
+            merged.push(idx);
+        }
+
+        let referenced = self.referenced_attachments();
+        for id in present.iter().filter(|id| !referenced.contains(*id)) {
+            batch.remove_attachment(id);
+        }
+        for uuid in self.psers.inner().keys().chain(self.meta.tombstones()) {
+            batch.put_entry(uuid, self.encode_row(uuid)?);
+        }
+        let res = self.db.commit(&batch);
+        self.skip_stale(res)?;
+        for idx in merged {
+            self.db.resync(idx)?;
+        }
+        Ok(())
+    }
+
+    /// 合并副本后整理回收站：被另一个副本删除的密码信息移到回收站(ours为合并前的密码信息)，
+    /// 另一个副本回收站中的密码信息(theirs)在合并后仍被删除时也移到回收站，
+    /// 被另一个副本"复活"的密码信息从回收站中移除
+    fn merge_trash(&mut self, ours: HashMap<String, Pser>, theirs: Trash) {
+        for (uuid, pser) in ours {
+            if !self.psers.inner().contains_key(&uuid) {
+                let deleted = self.meta.deleted_at(&uuid).unwrap_or_default();
+                self.trash.entry(uuid).or_insert(Trashed { deleted, pser });
+            }
+        }
+        for (uuid, trashed) in theirs {
+            if self.meta.deleted_at(&uuid).is_some() {
+                self.trash.entry(uuid).or_insert(trashed);
+            }
+        }
+        let psers = self.psers.inner();
+        self.trash.retain(|uuid, _| !psers.contains_key(uuid));
+    }
+
+    /// 所有密码信息、回收站中的密码信息及冲突中的副本版本引用的附件id
+    fn referenced_attachments(&self) -> HashSet<String> {
+        let psers = self.psers.inner().values();
+        psers
+            .chain(self.trash.values().map(|t| &t.pser))
+            .chain(self.conflicts.iter().map(|c| &c.theirs))
+            .flat_map(|pser| pser.attachments.iter().map(|a| a.id.clone()))
+            .collect()
+    }
+
+    /// 将ids中只存在于第idx个副本(数据密钥为key)中的附件使用当前的数据密钥重新加密后加入batch，
+    /// present为已存在的附件id
+    fn copy_attachments(
+        &self,
+        idx: usize,
+        key: &[u8],
+        ids: HashSet<String>,
+        present: &mut HashSet<String>,
+        batch: &mut Batch,
+    ) -> PserResult<()> {
+        for id in ids {
+            if present.contains(&id) {
+                continue;
+            }
+            if let Some(blob) = self.db.read_replica_attachment(idx, &id)? {
+                let data = decode_attachment(&blob, key)?;
+                batch.put_attachment(&id, encode_attachment(&data, &self.key)?);
+                present.insert(id);
+            }
+        }
+        Ok(())
+    }
+
+    /// 读取第idx个副本中的数据及其数据密钥(旧版本的密码库没有数据密钥)，
+    /// 如果该副本无法使用当前凭据解开，返回None
+    fn load_replica(&self, idx: usize) -> PserResult<Option<(VaultData, DataKey)>> {
+        let Some((version, header)) = read_header(|key| self.db.read_replica(idx, key))? else {
+            return Ok(None);
+        };
+        let Some(unlocked) = header.unlock_slots(&self.cred) else {
+            return Ok(None);
+        };
+
+        let data = load_vault(&self.db, Some(idx), version, &unlocked, &self.cred)?;
+        let key = match unlocked {
+            Unlocked::Key { key, .. } => Some(key),
+            Unlocked::Legacy => None,
+        };
+        Ok(Some((data, key)))
+    }
+
+    /// 从数据库中读取格式版本和验证头(旧版本的验证头将转换为当前布局)
+    pub fn load_header(db: &SyncDb) -> PserResult<Option<(u32, VerifyHeader)>> {
+        read_header(|key| db.read_db(key))
     }
 
     /// 向数据库中写入验证头
-    pub fn sync_header(&self) -> Result<(), redb::Error> {
+    pub fn sync_header(&self) -> PserResult<()> {
         let header_bytes = self.header.encode();
         self.db.write_db(HEADER_KEY, &header_bytes)?;
         Ok(())
     }
 
-    /// 从数据库中读取加密数据并解密
-    pub fn load_psers(&self) -> PserResult<Option<Psers>> {
-        if let Some(bytes) = self.db.read_db(DATA_KEY)? {
-            let pser = EncryptData::decrypt::<Psers>(&bytes, &self.main_passwd)?;
-            return Ok(Some(pser));
+    /// 使用数据密钥加密uuid对应的行，已删除的密码信息加密为墓碑
+    fn encode_row(&self, uuid: &str) -> PserResult<Vec<u8>> {
+        let row = match (self.psers.inner().get(uuid), self.trash.get(uuid)) {
+            (Some(pser), _) => Row::Pser(Box::new(pser.clone())),
+            (None, Some(trashed)) => Row::Trashed {
+                deleted: trashed.deleted,
+                pser: Box::new(trashed.pser.clone()),
+            },
+            (None, None) => Row::Tombstone {
+                deleted: self.meta.deleted_at(uuid).unwrap_or_default(),
+            },
+        };
+        Ok(EncryptData::encrypt_with_key(&row, &self.key)?)
+    }
+
+    /// 包含所有密码信息和墓碑的行
+    fn rows_batch(&self) -> PserResult<Batch> {
+        let mut batch = Batch::new();
+        let uuids = self.psers.inner().keys().chain(self.meta.tombstones());
+        for uuid in uuids {
+            batch.put_entry(uuid, self.encode_row(uuid)?);
         }
-        Ok(None)
+        Ok(batch)
     }
 
-    /// 将密码数据进行加密，然后写入数据库
+    /// 将uuid对应的密码信息(或墓碑)进行加密，然后写入数据库，只会修改这一行
+    ///
+    /// 只保存内存中的当前状态，不记录操作日志，修改密码信息应使用`update`
+    pub fn sync_pser(&self, uuid: &str) -> PserResult<()> {
+        let mut batch = Batch::new();
+        batch.put_entry(uuid, self.encode_row(uuid)?);
+        self.db.commit(&batch)?;
+        Ok(())
+    }
+
+    /// 将所有密码信息进行加密，然后在一个事务中写入数据库
+    ///
+    /// 只保存内存中的当前状态，不记录操作日志，修改密码信息应使用`update_many`
     pub fn sync_psers(&self) -> PserResult<()> {
-        let encrypt_data = EncryptData::encrypt(&self.psers, &self.main_passwd)?;
-        self.db.write_db(DATA_KEY, &encrypt_data)?;
+        self.db.commit(&self.rows_batch()?)?;
         Ok(())
     }
 }
 
 impl PserDB {
-    /// 修改解密程序的明文主密码
-    // 除了需要修改并保存验证头，还需要将当前的密码数据用新密码全部重新加密并保存
+    /// 修改解密程序的明文主密码，如果主密码需要配合密钥文件使用，则继续使用当前的密钥文件
     pub fn change_passwd(&mut self, plain_passwd: &str) -> PserResult<()> {
-        self.header = VerifyHeader::new(plain_passwd);
-        self.main_passwd = plain_passwd.to_string();
+        let mut main_cred = Credential::passwd(plain_passwd);
+        if let Some(SlotKind::PasswordWithKeyfile) = self.header.main_slot().map(|s| s.kind) {
+            let keyfile = self.cred.keyfile.clone().ok_or_else(|| {
+                anyhow!("主密码需要配合密钥文件使用，但打开密码库时没有提供密钥文件")
+            })?;
+            main_cred = main_cred.with_keyfile(keyfile);
+        }
+        self.change_main_credential(&main_cred)
+    }
+
+    /// 修改主密码，main_cred中包含密钥文件时，之后需要同时提供主密码和该密钥文件，
+    /// 否则只需提供主密码
+    // 数据密钥不变，只需使用新凭据重新包装数据密钥，并保存验证头
+    pub fn change_main_credential(&mut self, main_cred: &Credential) -> PserResult<()> {
+        self.header.change_passwd(main_cred, &self.key)?;
+        self.cred = main_cred.clone();
+        self.slot = main_slot_id(&self.header)?;
         self.sync_header()?;
-        self.sync_psers()
+        Ok(())
     }
 
-    /// 添加Pser并保存(将自动生成一个Uuid)
-    pub fn insert(&mut self, pser: Pser) -> PserResult<()> {
-        self.psers
-            .inner_mut()
-            .insert(Uuid::new_v4().as_simple().to_string(), pser);
-        self.sync_psers()
+    /// 所有密钥槽
+    pub fn key_slots(&self) -> &[KeySlot] {
+        self.header.slots()
+    }
+
+    /// 添加一个密钥槽：使用给定凭据包装数据密钥，之后可使用该凭据打开密码库，返回新密钥槽的编号
+    pub fn add_key_slot(
+        &mut self,
+        kind: SlotKind,
+        desc: &str,
+        cred: &Credential,
+    ) -> PserResult<u32> {
+        let id = self.header.add_slot(kind, desc, cred, &self.key)?;
+        self.sync_header()?;
+        Ok(id)
+    }
+
+    /// 打开密码库时解开的密钥槽编号
+    pub fn unlocked_slot(&self) -> u32 {
+        self.slot
+    }
+
+    /// 使用新的 argon2 参数重新包装打开密码库时解开的密钥槽，旧版本的密码库可借此升级 argon2 参数
+    ///
+    /// 其它密钥槽仍使用原来的参数，解开主密码密钥槽后重新包装时，之后添加的密钥槽也将使用新参数
+    pub fn rekey(&mut self, params: &KdfParams) -> PserResult<()> {
+        self.header
+            .rekey(self.slot, &self.cred, &self.key, params)?;
+        self.sync_header()?;
+        Ok(())
+    }
+
+    /// 删除编号为id的密钥槽，之后无法再使用该密钥槽对应的凭据打开密码库(主密码密钥槽不能删除)
+    pub fn remove_key_slot(&mut self, id: u32) -> PserResult<()> {
+        self.header.remove_slot(id)?;
+        self.sync_header()?;
+        Ok(())
+    }
+
+    /// 添加Pser并保存(将自动生成一个Uuid)，记录其创建时间和修改时间
+    pub fn insert(&mut self, mut pser: Pser) -> PserResult<()> {
+        let uuid = Uuid::new_v4().as_simple().to_string();
+        self.meta.touch(&uuid);
+        pser.touch(now8().timestamp());
+        self.psers.inner_mut().insert(uuid.clone(), pser);
+
+        let mut batch = Batch::new();
+        batch.put_entry(&uuid, self.encode_row(&uuid)?);
+        let change = Change {
+            after: self.get_pser(&uuid).cloned(),
+            before: None,
+            uuid,
+        };
+        self.stage_journal("添加", vec![], vec![change], &mut batch)?;
+        self.db.commit(&batch)
     }
 
     /// 替换已存在的Pser并保存(如果uuid不存在，则新创建)
+    ///
+    /// 保留被替换版本的创建时间，修订版本号在两者中较大的一个的基础上加1
+    ///
+    /// pser中引用的附件在密码库中不存在时(例如从其它密码库导出的数据)将被忽略，
+    /// 被替换版本中不再被引用的附件将被删除，过期的旧密码将按保留策略被删除
     pub fn update(&mut self, uuid: &str, pser: Pser) -> PserResult<()> {
+        self.update_many(vec![(uuid.to_string(), pser)])
+    }
+
+    /// 和`update`相同，但在一个事务中替换多条密码信息，用于移动文件夹等批量操作
+    pub fn update_many(&mut self, psers: Vec<(String, Pser)>) -> PserResult<()> {
+        let mut batch = Batch::new();
+        let changes = self.stage_updates(psers, &mut batch)?;
+        self.stage_journal("修改", vec![], changes, &mut batch)?;
+        self.db.commit(&batch)
+    }
+
+    /// 替换多条密码信息(忽略不存在的附件)，并将需要写入的数据加入batch，返回各密码信息的修改
+    fn stage_updates(
+        &mut self,
+        psers: Vec<(String, Pser)>,
+        batch: &mut Batch,
+    ) -> PserResult<Vec<Change>> {
+        let present = match psers.iter().any(|(_, p)| !p.attachments.is_empty()) {
+            true => self.db.attachment_ids()?,
+            false => HashSet::new(),
+        };
+        let mut changes = vec![];
+        for (uuid, mut pser) in psers {
+            pser.attachments.retain(|a| present.contains(&a.id));
+            let before = self.get_pser(&uuid).cloned();
+            self.stage_update(&uuid, pser, batch)?;
+            changes.push(Change {
+                after: self.get_pser(&uuid).cloned(),
+                before,
+                uuid,
+            });
+        }
+        Ok(changes)
+    }
+
+    /// 替换uuid对应的密码信息(按保留策略删除过期的旧密码)，并将需要写入的数据加入batch，
+    /// 回收站中的密码信息被替换后从回收站中移除
+    fn stage_update(&mut self, uuid: &str, mut pser: Pser, batch: &mut Batch) -> PserResult<()> {
+        if let Some(old) = self.psers.inner().get(uuid) {
+            pser.created = old.created;
+            pser.revision = pser.revision.max(old.revision);
+            pser.last_used = pser.last_used.max(old.last_used);
+            pser.keep_hotp_counter(old);
+            for a in &old.attachments {
+                if !pser.attachments.iter().any(|b| b.id == a.id) {
+                    batch.remove_attachment(&a.id);
+                }
+            }
+        }
+        let now = now8().timestamp();
+        pser.prune_history(&self.history_policy, now);
+        self.meta.touch(uuid);
+        self.trash.remove(uuid);
+        pser.touch(now);
         self.psers.inner_mut().insert(uuid.to_string(), pser);
-        self.sync_psers()
+        batch.put_entry(uuid, self.encode_row(uuid)?);
+        Ok(())
+    }
+
+    /// 为uuid对应的密码信息添加附件，已存在同名附件时替换(位置不变)，视为修改了该密码信息
+    ///
+    /// 附件内容使用数据密钥加密后保存在ATTACH_TABLE表中，和密码信息在同一个事务中写入
+    pub fn add_attachment(&mut self, uuid: &str, name: &str, data: &[u8]) -> PserResult<()> {
+        let now = now8().timestamp();
+        let attachment = Attachment {
+            id: Uuid::new_v4().as_simple().to_string(),
+            name: name.to_string(),
+            size: data.len() as u64,
+            added: now,
+        };
+        let mut batch = Batch::new();
+        batch.put_attachment(&attachment.id, encode_attachment(data, &self.key)?);
+
+        let before = self.get_pser(uuid).cloned();
+        let pser = self
+            .psers
+            .inner_mut()
+            .get_mut(uuid)
+            .ok_or_else(|| anyhow!("密码信息{}不存在", uuid))?;
+        match pser.attachments.iter_mut().find(|a| a.name == name) {
+            Some(old) => {
+                batch.remove_attachment(&old.id);
+                *old = attachment;
+            }
+            None => pser.attachments.push(attachment),
+        }
+        pser.touch(now);
+        self.meta.touch(uuid);
+
+        batch.put_entry(uuid, self.encode_row(uuid)?);
+        let change = Change {
+            uuid: uuid.to_string(),
+            before,
+            after: self.get_pser(uuid).cloned(),
+        };
+        self.stage_journal("添加附件", vec![], vec![change], &mut batch)?;
+        self.db.commit(&batch)
+    }
+
+    /// 读取uuid对应的密码信息中名为name的附件的内容，返回的数据在drop时清零
+    pub fn read_attachment(&self, uuid: &str, name: &str) -> PserResult<Zeroizing<Vec<u8>>> {
+        let attachment = self
+            .get_pser(uuid)
+            .and_then(|pser| pser.attachment(name))
+            .ok_or_else(|| anyhow!("附件{}不存在", name))?;
+        let blob = self
+            .db
+            .read_attachment(&attachment.id)?
+            .ok_or_else(|| Error::Corrupt(format!("缺少附件{}的内容", name)))?;
+        decode_attachment(&blob, &self.key)
+    }
+
+    /// 删除uuid对应的密码信息中名为name的附件，视为修改了该密码信息
+    ///
+    /// 附件内容将被删除，撤销该操作时只能恢复附件的元数据，因此撤销后该附件会被忽略
+    pub fn remove_attachment(&mut self, uuid: &str, name: &str) -> PserResult<()> {
+        let before = self.get_pser(uuid).cloned();
+        let pser = self
+            .psers
+            .inner_mut()
+            .get_mut(uuid)
+            .ok_or_else(|| anyhow!("密码信息{}不存在", uuid))?;
+        let idx = pser
+            .attachments
+            .iter()
+            .position(|a| a.name == name)
+            .ok_or_else(|| anyhow!("附件{}不存在", name))?;
+        let attachment = pser.attachments.remove(idx);
+        pser.touch(now8().timestamp());
+        self.meta.touch(uuid);
+
+        let mut batch = Batch::new();
+        batch
+            .remove_attachment(&attachment.id)
+            .put_entry(uuid, self.encode_row(uuid)?);
+        let change = Change {
+            uuid: uuid.to_string(),
+            before,
+            after: self.get_pser(uuid).cloned(),
+        };
+        self.stage_journal("删除附件", vec![], vec![change], &mut batch)?;
+        self.db.commit(&batch)
+    }
+
+    /// 记录各uuid对应的密码信息在当前时间被使用(查询出密码)，不视为修改
+    pub fn mark_used(&mut self, uuids: &[String]) -> PserResult<()> {
+        let now = now8().timestamp();
+        let mut batch = Batch::new();
+        for uuid in uuids {
+            if let Some(pser) = self.psers.inner_mut().get_mut(uuid) {
+                pser.last_used = now;
+                batch.put_entry(uuid, self.encode_row(uuid)?);
+            }
+        }
+        self.db.commit(&batch)?;
+        Ok(())
+    }
+
+    /// 将uuid对应的密码信息的HOTP计数器加1(生成一次HOTP一次性密码之后调用)，返回加1后的计数器，
+    /// 没有配置HOTP时返回None
+    ///
+    /// 和`mark_used`一样不视为修改：不记录操作日志，也不改变修改时间和修订版本号
+    pub fn advance_hotp(&mut self, uuid: &str) -> PserResult<Option<u64>> {
+        let Some(otp) = self
+            .psers
+            .inner_mut()
+            .get_mut(uuid)
+            .and_then(|p| p.otp.as_mut())
+        else {
+            return Ok(None);
+        };
+        let OtpKind::Hotp { counter } = &mut otp.kind else {
+            return Ok(None);
+        };
+        *counter += 1;
+        let counter = *counter;
+
+        let mut batch = Batch::new();
+        batch.put_entry(uuid, self.encode_row(uuid)?);
+        self.db.commit(&batch)?;
+        Ok(Some(counter))
     }
 
     /// 所有已保存的密码信息
@@ -249,6 +1310,27 @@ impl PserDB {
         psers
     }
 
+    /// 搜索满足查询表达式的所有密码信息，见[`Filter`]
+    pub fn filter(&self, filter: &Filter) -> Vec<(&String, &Pser)> {
+        filter.select(&self.psers, now8().timestamp())
+    }
+
+    /// 搜索满足查询表达式的所有密码信息，并按相关度(见[`Filter::score`])从高到低排序，
+    /// 相关度相同时按标题(见[`Pser::title`])和uuid排序
+    pub fn rank(&self, filter: &Filter) -> Vec<(&String, &Pser, u32)> {
+        let mut ranked: Vec<_> = self
+            .filter(filter)
+            .into_iter()
+            .map(|(uuid, pser)| (uuid, pser, filter.score(pser)))
+            .collect();
+        ranked.sort_by(|(ua, a, sa), (ub, b, sb)| {
+            sb.cmp(sa)
+                .then_with(|| a.title().cmp(b.title()))
+                .then_with(|| ua.cmp(ub))
+        });
+        ranked
+    }
+
     /// 根据uuid前缀，搜索uuid key，有可能搜索出多个
     pub fn uuid_by_prefix(&self, uuid_prefix: &str) -> Vec<String> {
         self.psers
@@ -259,16 +1341,183 @@ impl PserDB {
             .collect()
     }
 
-    /// 根据uuid删除密码库中的密码信息
+    /// 根据uuid将密码库中的密码信息(及其附件)移到回收站
     pub fn remove(&mut self, uuid: &str) -> PserResult<()> {
-        self.psers.inner_mut().remove(uuid);
-        self.sync_psers()
+        self.remove_many(&[uuid.to_string()])
     }
 
-    /// 清空密码库中的所有密码信息
+    /// 在一个事务中将多条密码信息(及其附件)移到回收站，不存在的uuid将被忽略
+    pub fn remove_many(&mut self, uuids: &[String]) -> PserResult<()> {
+        let mut batch = Batch::new();
+        let changes = self.stage_trash(uuids, &mut batch)?;
+        self.stage_journal("删除", vec![], changes, &mut batch)?;
+        self.db.commit(&batch)
+    }
+
+    /// 将密码库中的所有密码信息(及其附件)移到回收站
     pub fn clear(&mut self) -> PserResult<()> {
-        self.psers.inner_mut().clear();
-        self.sync_psers()
+        let uuids: Vec<String> = self.psers.inner().keys().cloned().collect();
+        let mut batch = Batch::new();
+        let changes = self.stage_trash(&uuids, &mut batch)?;
+        self.stage_journal("清空", vec![], changes, &mut batch)?;
+        self.db.commit(&batch)
+    }
+
+    /// 将多条密码信息移到回收站，并将需要写入的数据加入batch，返回各密码信息的修改
+    fn stage_trash(&mut self, uuids: &[String], batch: &mut Batch) -> PserResult<Vec<Change>> {
+        let now = now8().timestamp();
+        let mut changes = vec![];
+        for uuid in uuids {
+            let Some(pser) = self.psers.inner_mut().remove(uuid) else {
+                continue;
+            };
+            self.meta.bury(uuid);
+            self.trash.insert(
+                uuid.clone(),
+                Trashed {
+                    deleted: now,
+                    pser: pser.clone(),
+                },
+            );
+            batch.put_entry(uuid, self.encode_row(uuid)?);
+            changes.push(Change {
+                uuid: uuid.clone(),
+                before: Some(pser),
+                after: None,
+            });
+        }
+        Ok(changes)
+    }
+
+    /// 回收站中的所有密码信息
+    pub fn trash(&self) -> Vec<(&String, &Trashed)> {
+        self.trash.iter().collect()
+    }
+
+    /// 将回收站中的密码信息恢复到密码库中，视为修改了这些密码信息，不在回收站中的uuid将被忽略
+    pub fn restore_trashed(&mut self, uuids: &[String]) -> PserResult<()> {
+        let psers = uuids
+            .iter()
+            .filter_map(|uuid| Some((uuid.clone(), self.trash.get(uuid)?.pser.clone())))
+            .collect();
+        let mut batch = Batch::new();
+        let changes = self.stage_updates(psers, &mut batch)?;
+        self.stage_journal("从回收站恢复", vec![], changes, &mut batch)?;
+        self.db.commit(&batch)
+    }
+
+    /// 清空回收站：删除回收站中的密码信息及其附件(只保留墓碑)，返回删除的数量
+    ///
+    /// 同时从操作日志中删除这些密码信息的所有版本(只修改了它们的操作将被整个删除)，
+    /// 之后无法再查看或撤销对它们的操作，其中的密码等敏感信息也无法再被恢复
+    pub fn empty_trash(&mut self) -> PserResult<usize> {
+        let trash = std::mem::take(&mut self.trash);
+        let mut batch = Batch::new();
+        for (uuid, trashed) in &trash {
+            for a in &trashed.pser.attachments {
+                batch.remove_attachment(&a.id);
+            }
+            batch.put_entry(uuid, self.encode_row(uuid)?);
+        }
+        for mut op in self.journal()? {
+            if !op.changes.iter().any(|c| trash.contains_key(&c.uuid)) {
+                continue;
+            }
+            op.changes.retain(|c| !trash.contains_key(&c.uuid));
+            match op.changes.is_empty() {
+                true => batch.remove_journal(op.seq),
+                false => batch.put_journal(op.seq, encode_operation(&op, &self.key)?),
+            };
+        }
+        self.db.commit(&batch)?;
+        Ok(trash.len())
+    }
+
+    /// 操作日志中的所有操作，按操作的先后顺序排列
+    pub fn journal(&self) -> PserResult<Vec<Operation>> {
+        self.db
+            .read_journal()?
+            .into_iter()
+            .map(|(seq, bytes)| decode_operation(seq, &bytes, &self.key))
+            .collect()
+    }
+
+    /// 清空操作日志，之后无法再撤销之前的操作，也无法查看密码信息之前的版本
+    pub fn clear_journal(&mut self) -> PserResult<()> {
+        let mut batch = Batch::new();
+        for (seq, _) in self.db.read_journal()? {
+            batch.remove_journal(seq);
+        }
+        self.db.commit(&batch)
+    }
+
+    /// 撤销最近的n个操作(撤销操作本身和已被撤销的操作除外)，返回被撤销的操作，最近的在前
+    ///
+    /// 各密码信息恢复为操作前的状态：操作前不存在的密码信息移到回收站，操作前存在的密码信息恢复为当时的版本
+    /// (如果在回收站中，则从回收站中移除)，视为修改了这些密码信息。撤销本身也记录在操作日志中
+    pub fn undo(&mut self, n: usize) -> PserResult<Vec<Operation>> {
+        let journal = self.journal()?;
+        let undone: HashSet<u64> = journal.iter().flat_map(|op| op.undoes.clone()).collect();
+        let ops: Vec<Operation> = journal
+            .into_iter()
+            .rev()
+            .filter(|op| op.undoes.is_empty() && !undone.contains(&op.seq))
+            .take(n)
+            .collect();
+        if ops.is_empty() {
+            return Ok(ops);
+        }
+
+        let mut batch = Batch::new();
+        let mut changes = vec![];
+        for change in ops.iter().flat_map(|op| op.changes.iter().rev()) {
+            match &change.before {
+                Some(pser) => {
+                    let psers = vec![(change.uuid.clone(), pser.clone())];
+                    changes.extend(self.stage_updates(psers, &mut batch)?);
+                }
+                None => changes
+                    .extend(self.stage_trash(std::slice::from_ref(&change.uuid), &mut batch)?),
+            }
+        }
+        // 同一条密码信息被撤销多次时，只记录最初和最终的状态
+        let mut merged: Vec<Change> = vec![];
+        for change in changes {
+            match merged.iter_mut().find(|c| c.uuid == change.uuid) {
+                Some(c) => c.after = change.after,
+                None => merged.push(change),
+            }
+        }
+        let undoes = ops.iter().map(|op| op.seq).collect();
+        self.stage_journal("撤销", undoes, merged, &mut batch)?;
+        self.db.commit(&batch)?;
+        Ok(ops)
+    }
+
+    /// 将一次操作(没有修改任何密码信息时忽略)加密后加入batch，超出容量时删除最早的操作
+    fn stage_journal(
+        &self,
+        desc: &str,
+        undoes: Vec<u64>,
+        changes: Vec<Change>,
+        batch: &mut Batch,
+    ) -> PserResult<()> {
+        if changes.is_empty() {
+            return Ok(());
+        }
+        let seq = self.db.last_journal_seq()? + 1;
+        let op = Operation {
+            seq,
+            time: now8().timestamp(),
+            desc: desc.to_string(),
+            undoes,
+            changes,
+        };
+        batch.put_journal(seq, encode_operation(&op, &self.key)?);
+        if seq > JOURNAL_CAPACITY {
+            batch.remove_journal(seq - JOURNAL_CAPACITY);
+        }
+        Ok(())
     }
 
     /// 根据uuid，返回Pser的引用
@@ -277,11 +1526,192 @@ impl PserDB {
     }
 
     /// 根据uuid，返回Pser的可变引用
+    ///
+    /// 通过该引用的修改只存在于内存中，不会更新修改时间、修订版本号和删除记录，也不会记录操作日志，
+    /// 修改密码信息应复制一份(`get_pser`)，修改后使用`update`或`update_many`保存
+    #[deprecated(note = "使用`get_pser`复制一份，修改后通过`update`或`update_many`保存")]
     pub fn get_pser_mut(&mut self, uuid: &str) -> Option<&mut Pser> {
         self.psers.inner_mut().get_mut(uuid)
     }
 }
 
+/// 主密码密钥槽的编号，每个验证头中都应该有主密码密钥槽
+fn main_slot_id(header: &VerifyHeader) -> PserResult<u32> {
+    header
+        .main_slot()
+        .map(|s| s.id)
+        .ok_or_else(|| Error::Corrupt("验证头中缺少主密码密钥槽".to_string()))
+}
+
+/// 读取格式版本，read为读取TABLE表中某个key的函数
+///
+/// 没有记录格式版本的密码库为版本1，空密码库视为当前版本
+fn read_version<F>(read: F) -> PserResult<u32>
+where
+    F: Fn(&str) -> PserResult<Option<Vec<u8>>>,
+{
+    if let Some(bytes) = read(FORMAT_KEY)? {
+        return migrate::decode_format(&bytes);
+    }
+    match read(HEADER_KEY)? {
+        Some(_) => Ok(1),
+        None => Ok(FORMAT_VERSION),
+    }
+}
+
+/// 读取格式版本和验证头，read为读取TABLE表中某个key的函数
+fn read_header<F>(read: F) -> PserResult<Option<(u32, VerifyHeader)>>
+where
+    F: Fn(&str) -> PserResult<Option<Vec<u8>>>,
+{
+    let version = read_version(&read)?;
+    match read(HEADER_KEY)? {
+        Some(bytes) => {
+            let bytes = migrate::migrate_header(bytes, version)?;
+            Ok(Some((version, VerifyHeader::decode(&bytes)?)))
+        }
+        None => Ok(None),
+    }
+}
+
+/// 使用数据密钥解密ENTRY_TABLE表中的各行，version为各行的格式版本
+fn decode_rows(rows: Vec<(String, Vec<u8>)>, key: &[u8], version: u32) -> PserResult<VaultData> {
+    let mut plains = vec![];
+    for (uuid, bytes) in rows {
+        // 数据密钥已经通过验证头的校验，解密失败说明数据被损坏
+        let plain = EncryptData::decrypt_raw_with_key(&bytes, key)
+            .map_err(|e| Error::Corrupt(format!("无法解密密码信息{}: {}", uuid, e)))?;
+        plains.push((uuid, plain));
+    }
+    collect_rows(plains, version)
+}
+
+/// 将解密后的各行转换为当前布局后读取，version为各行的格式版本
+fn collect_rows(rows: Vec<(String, Zeroizing<Vec<u8>>)>, version: u32) -> PserResult<VaultData> {
+    let mut psers = Psers::default();
+    let mut meta = SyncMeta::default();
+    let mut trash = Trash::default();
+    for (uuid, plain) in rows {
+        let plain = migrate::migrate_row(plain, version)?;
+        match bincode::deserialize::<Row>(&plain)? {
+            Row::Pser(pser) => {
+                psers.inner_mut().insert(uuid, *pser);
+            }
+            Row::Tombstone { deleted } => meta.set_deleted(&uuid, deleted),
+            Row::Trashed { deleted, pser } => {
+                meta.set_deleted(&uuid, deleted);
+                trash.insert(
+                    uuid,
+                    Trashed {
+                        deleted,
+                        pser: *pser,
+                    },
+                );
+            }
+        }
+    }
+    Ok((psers, meta, trash))
+}
+
+/// 使用解开验证头的结果读取副本中的数据(idx为None时读取读取数据的副本)，version为副本的格式版本
+///
+/// 版本1的密码库没有数据密钥，整个Psers使用主密码加密后保存在DATA_KEY中，只能使用主密码读取
+fn load_vault(
+    db: &SyncDb,
+    idx: Option<usize>,
+    version: u32,
+    unlocked: &Unlocked,
+    cred: &Credential,
+) -> PserResult<VaultData> {
+    if let Unlocked::Key { key, .. } = unlocked {
+        let entries = match idx {
+            Some(idx) => db.read_replica_entries(idx)?,
+            None => db.read_entries()?,
+        };
+        return decode_rows(entries, key, version);
+    }
+
+    let main_passwd = cred
+        .passwd
+        .as_deref()
+        .ok_or_else(|| anyhow!("旧版本的密码库需要使用主密码打开"))?;
+    let data = match idx {
+        Some(idx) => db.read_replica(idx, DATA_KEY)?,
+        None => db.read_db(DATA_KEY)?,
+    };
+    let mut psers = Psers::default();
+    if let Some(bytes) = data {
+        let legacy = EncryptData::decrypt::<migrate::PsersV1>(&bytes, main_passwd)?;
+        psers.inner_mut().extend(migrate::upgrade_legacy(legacy));
+    }
+    Ok((psers, SyncMeta::default(), Trash::default()))
+}
+
+/// 使用数据密钥加密操作记录，其中的各状态按当前布局编码
+fn encode_operation(op: &Operation, key: &[u8]) -> PserResult<Vec<u8>> {
+    let state = |pser: &Option<Pser>| -> PserResult<Vec<u8>> {
+        let row = match pser {
+            Some(pser) => Row::Pser(Box::new(pser.clone())),
+            None => Row::Tombstone { deleted: op.time },
+        };
+        Ok(bincode::serialize(&row)?)
+    };
+    let mut record = JournalRecord {
+        version: FORMAT_VERSION,
+        time: op.time,
+        desc: op.desc.clone(),
+        undoes: op.undoes.clone(),
+        changes: vec![],
+    };
+    for change in &op.changes {
+        let (before, after) = (state(&change.before)?, state(&change.after)?);
+        record.changes.push((change.uuid.clone(), before, after));
+    }
+    Ok(EncryptData::encrypt_with_key(&record, key)?)
+}
+
+/// 使用数据密钥解密操作记录，并将其中的各状态转换为当前布局
+fn decode_operation(seq: u64, bytes: &[u8], key: &[u8]) -> PserResult<Operation> {
+    let plain = EncryptData::decrypt_raw_with_key(bytes, key)
+        .map_err(|e| Error::Corrupt(format!("无法解密操作记录{}: {}", seq, e)))?;
+    let mut record = bincode::deserialize::<JournalRecord>(&plain)?;
+    let state = |bytes: &mut Vec<u8>| -> PserResult<Option<Pser>> {
+        let plain = migrate::migrate_row(Zeroizing::new(std::mem::take(bytes)), record.version)?;
+        Ok(match bincode::deserialize::<Row>(&plain)? {
+            Row::Pser(pser) => Some(*pser),
+            _ => None,
+        })
+    };
+
+    let mut changes = vec![];
+    for (uuid, before, after) in &mut record.changes {
+        changes.push(Change {
+            uuid: uuid.clone(),
+            before: state(before)?,
+            after: state(after)?,
+        });
+    }
+    Ok(Operation {
+        seq,
+        time: record.time,
+        desc: std::mem::take(&mut record.desc),
+        undoes: std::mem::take(&mut record.undoes),
+        changes,
+    })
+}
+
+/// 使用数据密钥加密附件内容
+fn encode_attachment(data: &[u8], key: &[u8]) -> PserResult<Vec<u8>> {
+    Ok(EncryptData::encrypt_with_key(&data, key)?)
+}
+
+/// 使用数据密钥解密附件内容，返回的数据在drop时清零
+fn decode_attachment(blob: &[u8], key: &[u8]) -> PserResult<Zeroizing<Vec<u8>>> {
+    let plain = EncryptData::decrypt_raw_with_key(blob, key)
+        .map_err(|e| Error::Corrupt(format!("无法解密附件: {}", e)))?;
+    Ok(Zeroizing::new(bincode::deserialize(&plain)?))
+}
+
 fn file_sha2<T: AsRef<Path>>(file: T) -> io::Result<Vec<u8>> {
     let data = std::fs::read(file.as_ref())?;
     let mut hasher: Sha512 = Sha512::new();
@@ -290,16 +1720,494 @@ fn file_sha2<T: AsRef<Path>>(file: T) -> io::Result<Vec<u8>> {
     Ok(res.to_vec())
 }
 
-/// 检查主从密码库文件的sha2值，判断主从密码库的数据是否一致
-fn check_db_sha2() {
-    let home_sha2 = file_sha2(&*DB_FILE_HOME).unwrap_or_else(|e| panic!("无法读取主密码库: {}", e));
-    let cur_sha2 = file_sha2(&*DB_FILE_CUR).unwrap_or_else(|e| panic!("无法读取从密码库: {}", e));
-    if home_sha2 != cur_sha2 {
-        let mut str = String::new();
-        str.push_str("主、从密码库文件数据不一致.\n");
-        str.push_str("如果要采取主密码库，则通过该程序删除从密码库文件，然后再次运行.\n");
-        str.push_str("如果要采取从密码库，则通过该程序删除主密码库文件，然后再次运行");
-        eprintln!("{}", str);
-        std::process::exit(1);
+/// 创建密码库文件所在的目录，如果给定了源副本，则复制源副本
+fn create_replica(file: &Path, source: Option<&Path>) -> io::Result<()> {
+    if let Some(dir) = file.parent() {
+        if !dir.as_os_str().is_empty() {
+            std::fs::create_dir_all(dir)?;
+        }
+    }
+    if let Some(src) = source {
+        std::fs::copy(src, file)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// 在临时目录中创建n个副本文件的位置(文件本身不存在)
+    fn temp_vault(n: usize) -> (TempDir, VaultPaths) {
+        let dir = TempDir::new().unwrap();
+        let files: Vec<PathBuf> = (0..n).map(|i| dir.path().join(format!("{i}.db"))).collect();
+        let paths = VaultPaths::new(files[0].clone(), files[1..].to_vec());
+        (dir, paths)
+    }
+
+    /// 添加一条密码信息，返回其uuid
+    fn add(db: &mut PserDB, username: &str) -> String {
+        let mut pser = Pser::new();
+        pser.set_username(username).set_passwd("secret");
+        db.insert(pser).unwrap();
+        let (uuid, _) = db
+            .all_pser()
+            .into_iter()
+            .find(|(_, p)| p.username == username)
+            .unwrap();
+        uuid.clone()
+    }
+
+    /// 将密码信息的用户名改为username
+    fn rename(db: &mut PserDB, uuid: &str, username: &str) {
+        let mut pser = db.get_pser(uuid).unwrap().clone();
+        pser.set_username(username);
+        db.update(uuid, pser).unwrap();
+    }
+
+    #[test]
+    fn trash_round_trip() {
+        let (_dir, paths) = temp_vault(1);
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        let uuid = add(&mut db, "alice");
+
+        db.remove(&uuid).unwrap();
+        assert!(db.get_pser(&uuid).is_none());
+        assert_eq!(db.trash()[0].1.pser.username, "alice");
+
+        db.restore_trashed(std::slice::from_ref(&uuid)).unwrap();
+        assert!(db.trash().is_empty());
+        drop(db);
+
+        let db = PserDB::new(&paths, "pw").unwrap();
+        assert_eq!(db.get_pser(&uuid).unwrap().username, "alice");
+        assert!(db.trash().is_empty());
+        let descs: Vec<String> = db
+            .journal()
+            .unwrap()
+            .into_iter()
+            .map(|op| op.desc)
+            .collect();
+        assert_eq!(descs, ["添加", "删除", "从回收站恢复"]);
+    }
+
+    #[test]
+    fn undo() {
+        let (_dir, paths) = temp_vault(1);
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        let uuid = add(&mut db, "alice");
+        rename(&mut db, &uuid, "bob");
+        db.remove(&uuid).unwrap();
+
+        // 撤销删除
+        let undone = db.undo(1).unwrap();
+        assert_eq!((undone[0].seq, undone[0].desc.as_str()), (3, "删除"));
+        assert_eq!(db.get_pser(&uuid).unwrap().username, "bob");
+        assert!(db.trash().is_empty());
+        let last = db.journal().unwrap().pop().unwrap();
+        assert_eq!((last.desc.as_str(), last.undoes), ("撤销", vec![3]));
+
+        // 撤销操作本身和已被撤销的操作被跳过，接着撤销修改
+        let undone = db.undo(1).unwrap();
+        assert_eq!((undone[0].seq, undone[0].desc.as_str()), (2, "修改"));
+        assert_eq!(db.get_pser(&uuid).unwrap().username, "alice");
+        let last = db.journal().unwrap().pop().unwrap();
+        assert_eq!((last.seq, last.undoes), (5, vec![2]));
+        drop(db);
+
+        // 撤销添加：移到回收站，之后没有可撤销的操作
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        db.undo(5).unwrap();
+        assert!(db.get_pser(&uuid).is_none());
+        assert_eq!(db.trash().len(), 1);
+        assert!(db.undo(1).unwrap().is_empty());
+        assert_eq!(db.journal().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn journal_capacity() {
+        let (_dir, paths) = temp_vault(1);
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        let uuid = add(&mut db, "alice");
+        for i in 0..JOURNAL_CAPACITY {
+            rename(&mut db, &uuid, &i.to_string());
+        }
+
+        let journal = db.journal().unwrap();
+        assert_eq!(journal.len() as u64, JOURNAL_CAPACITY);
+        assert_eq!(journal[0].seq, 2);
+        assert_eq!(
+            journal[0].changes[0].before.as_ref().unwrap().username,
+            "alice"
+        );
+        assert_eq!(journal.last().unwrap().seq, JOURNAL_CAPACITY + 1);
+    }
+
+    #[test]
+    fn undo_after_empty_trash() {
+        let (_dir, paths) = temp_vault(1);
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        let kept = add(&mut db, "alice");
+        let uuid = add(&mut db, "bob");
+        db.add_attachment(&uuid, "id_rsa", b"private key").unwrap();
+        rename(&mut db, &uuid, "carol");
+        db.remove(&uuid).unwrap();
+        assert_eq!(db.empty_trash().unwrap(), 1);
+        assert!(db.db.attachment_ids().unwrap().is_empty());
+
+        // 被清空的密码信息的所有版本都已从操作日志中删除，撤销时只会撤销对其它密码信息的操作
+        let journal = db.journal().unwrap();
+        assert!(journal.iter().all(|op| !op.touches(&uuid)));
+        assert_eq!(journal.len(), 1);
+        let undone = db.undo(JOURNAL_CAPACITY as usize).unwrap();
+        assert_eq!(undone.len(), 1);
+        assert_eq!(undone[0].changes[0].uuid, kept);
+        assert!(db.get_pser(&uuid).is_none());
+        assert!(db.trash().iter().all(|(id, _)| **id == kept));
+        assert!(db.db.attachment_ids().unwrap().is_empty());
+    }
+
+    /// 只包含第idx个副本的密码库位置
+    fn single(paths: &VaultPaths, idx: usize) -> VaultPaths {
+        VaultPaths::new(paths.replicas()[idx].clone(), vec![])
+    }
+
+    #[test]
+    fn recreate_missing_replica() {
+        let (_dir, paths) = temp_vault(2);
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        let uuid = add(&mut db, "alice");
+        drop(db);
+
+        std::fs::remove_file(&paths.replicas()[1]).unwrap();
+        assert_eq!(SyncDb::inspect(&paths)[1].1, ReplicaState::Missing);
+        let db = PserDB::new(&paths, "pw").unwrap();
+        assert_eq!(*db.db.replicas()[1].1, ReplicaState::Created);
+        drop(db);
+
+        let db = PserDB::new(&single(&paths, 1), "pw").unwrap();
+        assert_eq!(db.get_pser(&uuid).unwrap().username, "alice");
+    }
+
+    #[test]
+    fn resync_stale_replica() {
+        let (_dir, paths) = temp_vault(2);
+        drop(PserDB::new(&paths, "pw").unwrap());
+        // 只修改第一个副本
+        let mut db = PserDB::new(&single(&paths, 0), "pw").unwrap();
+        let uuid = add(&mut db, "alice");
+        drop(db);
+
+        let states: Vec<ReplicaState> = SyncDb::inspect(&paths)
+            .into_iter()
+            .map(|(_, s)| s)
+            .collect();
+        assert_eq!(states, [ReplicaState::Healthy, ReplicaState::Stale]);
+        let db = PserDB::new(&paths, "pw").unwrap();
+        assert_eq!(*db.db.replicas()[1].1, ReplicaState::Merged);
+        drop(db);
+
+        // 合并后各副本的数据一致
+        assert!(SyncDb::inspect(&paths)
+            .iter()
+            .all(|(_, s)| *s == ReplicaState::Healthy));
+        let db = PserDB::new(&single(&paths, 1), "pw").unwrap();
+        assert_eq!(db.get_pser(&uuid).unwrap().username, "alice");
+    }
+
+    #[test]
+    fn skip_stale_replica() {
+        let (_dir, paths) = temp_vault(2);
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        let failed = Err(Error::StaleReplicas {
+            replicas: vec![paths.replicas()[1].clone()],
+            reason: "disk full".to_string(),
+        });
+        db.skip_stale(failed).unwrap();
+        assert_eq!(*db.replicas()[1].1, ReplicaState::Stale);
+
+        // 之后不再写入该副本，下次打开密码库时合并
+        let uuid = add(&mut db, "alice");
+        drop(db);
+        let db = PserDB::new(&paths, "pw").unwrap();
+        assert_eq!(*db.replicas()[1].1, ReplicaState::Merged);
+        drop(db);
+        let db = PserDB::new(&single(&paths, 1), "pw").unwrap();
+        assert_eq!(db.get_pser(&uuid).unwrap().username, "alice");
+    }
+
+    #[test]
+    fn corrupt_stale_replica() {
+        let (_dir, paths) = temp_vault(2);
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        let uuid = add(&mut db, "alice");
+        drop(db);
+        // 只修改第二个副本，并损坏其中新增的行
+        let mut db = PserDB::new(&single(&paths, 1), "pw").unwrap();
+        let bob = add(&mut db, "bob");
+        let (_, mut row) = db
+            .db
+            .read_entries()
+            .unwrap()
+            .into_iter()
+            .find(|(id, _)| *id == bob)
+            .unwrap();
+        let idx = row.len() - 2;
+        row[idx] ^= 1;
+        db.db.commit(Batch::new().put_entry(&bob, row)).unwrap();
+        drop(db);
+
+        // 无法解密的副本不合并，从其它副本打开密码库
+        let db = PserDB::new(&paths, "pw").unwrap();
+        assert!(matches!(db.db.replicas()[1].1, ReplicaState::Unreadable(_)));
+        assert_eq!(db.get_pser(&uuid).unwrap().username, "alice");
+        assert!(db.get_pser(&bob).is_none());
+    }
+
+    #[test]
+    fn unreadable_replica() {
+        let (_dir, paths) = temp_vault(2);
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        let uuid = add(&mut db, "alice");
+        drop(db);
+        std::fs::write(&paths.replicas()[1], b"not a database").unwrap();
+
+        // 无法读取的副本被跳过，不影响读写其它副本，也不会被覆盖
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        assert!(matches!(db.db.replicas()[1].1, ReplicaState::Unreadable(_)));
+        assert_eq!(db.get_pser(&uuid).unwrap().username, "alice");
+        add(&mut db, "bob");
+        drop(db);
+        assert_eq!(
+            std::fs::read(&paths.replicas()[1]).unwrap(),
+            b"not a database"
+        );
+
+        // 所有副本都无法读取时不会新建密码库
+        std::fs::write(&paths.replicas()[0], b"not a database").unwrap();
+        assert!(SyncDb::new(&paths).is_err());
+    }
+
+    #[test]
+    fn row_round_trip() {
+        let (_dir, paths) = temp_vault(1);
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        let uuid = add(&mut db, "alice");
+        let pser = db.get_pser(&uuid).unwrap().clone();
+        drop(db);
+
+        // 每条密码信息单独保存为一行
+        let db = PserDB::new(&paths, "pw").unwrap();
+        assert_eq!(db.get_pser(&uuid), Some(&pser));
+        let rows = db.db.read_entries().unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].0, uuid);
+    }
+
+    #[test]
+    fn corrupt_row() {
+        let (_dir, paths) = temp_vault(1);
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        let uuid = add(&mut db, "alice");
+        add(&mut db, "bob");
+
+        // 翻转认证标签(密文之后只有一个字节的argon2参数)中的一个字节，解密时认证失败
+        let (_, mut row) = db
+            .db
+            .read_entries()
+            .unwrap()
+            .into_iter()
+            .find(|(id, _)| *id == uuid)
+            .unwrap();
+        let idx = row.len() - 2;
+        row[idx] ^= 1;
+        db.db.commit(Batch::new().put_entry(&uuid, row)).unwrap();
+        drop(db);
+
+        let err = PserDB::new(&paths, "pw").err().unwrap();
+        assert!(matches!(err, Error::Corrupt(msg) if msg.contains(&uuid)));
+    }
+
+    #[test]
+    fn attachment_round_trip() {
+        let (_dir, paths) = temp_vault(1);
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        let uuid = add(&mut db, "alice");
+        db.add_attachment(&uuid, "id_rsa", b"old key").unwrap();
+        // 同名附件被替换，旧附件的内容被删除
+        db.add_attachment(&uuid, "id_rsa", b"new key").unwrap();
+        db.add_attachment(&uuid, "codes.txt", b"123456").unwrap();
+        drop(db);
+
+        let db = PserDB::new(&paths, "pw").unwrap();
+        let names: Vec<&str> = db
+            .get_pser(&uuid)
+            .unwrap()
+            .attachments
+            .iter()
+            .map(|a| a.name.as_str())
+            .collect();
+        assert_eq!(names, ["id_rsa", "codes.txt"]);
+        assert_eq!(*db.read_attachment(&uuid, "id_rsa").unwrap(), b"new key");
+        assert_eq!(*db.read_attachment(&uuid, "codes.txt").unwrap(), b"123456");
+        assert_eq!(db.db.attachment_ids().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn remove_attachment() {
+        let (_dir, paths) = temp_vault(1);
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        let uuid = add(&mut db, "alice");
+        db.add_attachment(&uuid, "id_rsa", b"private key").unwrap();
+
+        db.remove_attachment(&uuid, "id_rsa").unwrap();
+        assert!(db.read_attachment(&uuid, "id_rsa").is_err());
+        assert!(db.remove_attachment(&uuid, "id_rsa").is_err());
+        assert!(db.db.attachment_ids().unwrap().is_empty());
+
+        // 附件内容已被删除，撤销时忽略该附件
+        db.undo(1).unwrap();
+        assert!(db.get_pser(&uuid).unwrap().attachments.is_empty());
+    }
+
+    #[test]
+    fn remove_attachment_owner() {
+        let (_dir, paths) = temp_vault(1);
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        let uuid = add(&mut db, "alice");
+        db.add_attachment(&uuid, "id_rsa", b"private key").unwrap();
+
+        // 移到回收站时保留附件，恢复后仍可读取
+        db.remove(&uuid).unwrap();
+        assert!(db.read_attachment(&uuid, "id_rsa").is_err());
+        assert_eq!(db.db.attachment_ids().unwrap().len(), 1);
+        db.restore_trashed(std::slice::from_ref(&uuid)).unwrap();
+        assert_eq!(
+            *db.read_attachment(&uuid, "id_rsa").unwrap(),
+            b"private key"
+        );
+
+        // 清空回收站时删除附件
+        db.remove(&uuid).unwrap();
+        db.empty_trash().unwrap();
+        assert!(db.db.attachment_ids().unwrap().is_empty());
+    }
+
+    #[test]
+    fn advance_hotp() {
+        let (_dir, paths) = temp_vault(1);
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        let mut pser = Pser::new();
+        pser.set_otp("otpauth://hotp/x?secret=GEZDGNBV&counter=3")
+            .unwrap();
+        db.insert(pser).unwrap();
+        let totp = add(&mut db, "alice");
+        let (uuid, pser) = db
+            .all_pser()
+            .into_iter()
+            .find(|(u, _)| **u != totp)
+            .unwrap();
+        let (uuid, pser) = (uuid.clone(), pser.clone());
+
+        assert_eq!(db.advance_hotp(&uuid).unwrap(), Some(4));
+        assert_eq!(db.advance_hotp(&totp).unwrap(), None);
+        drop(db);
+
+        // 计数器已保存，但不视为修改
+        let mut db = PserDB::new(&paths, "pw").unwrap();
+        let advanced = db.get_pser(&uuid).unwrap();
+        assert_eq!(
+            advanced.otp.as_ref().unwrap().kind,
+            OtpKind::Hotp { counter: 4 }
+        );
+        assert_eq!(
+            (advanced.modified, advanced.revision),
+            (pser.modified, pser.revision)
+        );
+        assert_eq!(db.journal().unwrap().len(), 2);
+
+        // 撤销之前的修改时计数器不会回退
+        rename(&mut db, &uuid, "bob");
+        assert_eq!(db.advance_hotp(&uuid).unwrap(), Some(5));
+        db.undo(1).unwrap();
+        let undone = db.get_pser(&uuid).unwrap();
+        assert_eq!(undone.username, "");
+        assert_eq!(
+            undone.otp.as_ref().unwrap().kind,
+            OtpKind::Hotp { counter: 5 }
+        );
+    }
+
+    #[test]
+    fn upgrade_baseline_vault() {
+        /// 最初版本的密码信息
+        #[derive(Serialize)]
+        struct BaselinePser {
+            username: String,
+            url: String,
+            desc: String,
+            email: String,
+            phone: String,
+            passwd: String,
+            comment: String,
+            history: HashMap<i64, String>,
+        }
+
+        // 最初版本的密码库：验证头为(分钟起始时间, 尝试次数, 主密码的Sha512)，整个Psers使用主密码加密
+        let (_dir, paths) = temp_vault(1);
+        let uuid = Uuid::new_v4().as_simple().to_string();
+        let pser = BaselinePser {
+            username: "alice".to_string(),
+            url: "github.com".to_string(),
+            desc: "github".to_string(),
+            email: String::new(),
+            phone: String::new(),
+            passwd: "secret".to_string(),
+            comment: String::new(),
+            history: HashMap::from([(1, "old".to_string())]),
+        };
+        let header = (0i64, 0u16, Sha512::digest("pw").to_vec());
+        let data = EncryptData::encrypt(&HashMap::from([(uuid.clone(), pser)]), "pw").unwrap();
+        let db = Database::create(&paths.replicas()[0]).unwrap();
+        let trx = db.begin_write().unwrap();
+        {
+            let mut table = trx.open_table(TABLE).unwrap();
+            table
+                .insert(HEADER_KEY, bincode::serialize(&header).unwrap().as_slice())
+                .unwrap();
+            table.insert(DATA_KEY, data.as_slice()).unwrap();
+        }
+        trx.commit().unwrap();
+        drop(db);
+
+        // 密码错误时不会破坏旧版本的数据
+        assert!(matches!(
+            PserDB::new(&paths, "wrong").err().unwrap(),
+            Error::WrongPassword
+        ));
+
+        let db = PserDB::new(&paths, "pw").unwrap();
+        let pser = db.get_pser(&uuid).unwrap();
+        assert_eq!(
+            (pser.username.as_str(), pser.passwd.as_str()),
+            ("alice", "secret")
+        );
+        assert_eq!(pser.url, ["github.com"]);
+        assert_eq!(pser.history.len(), 1);
+        assert!(db.db.read_db(DATA_KEY).unwrap().is_none());
+        assert_eq!(
+            read_version(|key| db.db.read_db(key)).unwrap(),
+            FORMAT_VERSION
+        );
+        assert!(db.db.read_db(FORMAT_KEY).unwrap().is_some());
+        drop(db);
+
+        // 升级后使用错误的密码
+        assert!(matches!(
+            PserDB::new(&paths, "wrong").err().unwrap(),
+            Error::WrongPassword
+        ));
+        assert!(PserDB::new(&paths, "pw").is_ok());
     }
 }
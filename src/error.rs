@@ -1,14 +1,43 @@
+use std::path::PathBuf;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
-    #[error("invalid header")]
-    HeaderError,
+    /// 凭据(主密码、口令、密钥文件或恢复码)错误
+    #[error("密码错误")]
+    WrongPassword,
+
+    /// 连续解锁失败次数过多，需要等待retry_after秒后才能再次尝试
+    #[error("解锁失败次数过多，请在{retry_after}秒后重试")]
+    RateLimited { retry_after: u64 },
+
+    /// 修改已保存到读取数据的副本中，但未能写入其它部分副本，这些副本将在下次打开密码库时重新合并
+    #[error(
+        "修改已保存，但写入密码库副本{}失败(下次打开密码库时将重新合并): {reason}",
+        display_paths(replicas)
+    )]
+    StaleReplicas {
+        replicas: Vec<PathBuf>,
+        reason: String,
+    },
+
+    /// 无法从URL中解析出域名
+    #[error("无法识别的URL: {0}")]
+    InvalidUrl(String),
+
+    /// 查询表达式无效，见[`Filter`](crate::filter::Filter)
+    #[error("无效的查询表达式: {0}")]
+    InvalidQuery(String),
+
+    /// 密码库中的数据损坏(缺失验证头、验证头格式错误、使用正确的数据密钥也无法解密等)
+    #[error("密码库数据损坏: {0}")]
+    Corrupt(String),
 
     #[error(transparent)]
     DecodeError(#[from] bincode::Error),
 
-    /// 数据库相关错误
+    /// 数据库相关错误，redb::Error较大，装箱以免所有`PserResult`都随之变大
     #[error(transparent)]
-    DBError(#[from] redb::Error),
+    DBError(Box<redb::Error>),
 
     /// io错误
     #[error(transparent)]
@@ -18,4 +47,30 @@ pub enum Error {
     Other(#[from] anyhow::Error),
 }
 
+/// redb的各类错误都可以直接使用`?`转换为Error
+macro_rules! from_redb {
+    ($($e:ty),*) => {$(
+        impl From<$e> for Error {
+            fn from(e: $e) -> Self {
+                Self::DBError(Box::new(e.into()))
+            }
+        }
+    )*};
+}
+
+from_redb!(
+    redb::Error,
+    redb::DatabaseError,
+    redb::TransactionError,
+    redb::TableError,
+    redb::StorageError,
+    redb::CommitError
+);
+
+/// 以逗号分隔的多个路径
+fn display_paths(paths: &[PathBuf]) -> String {
+    let paths: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+    paths.join(", ")
+}
+
 pub type PserResult<T> = Result<T, Error>;
@@ -0,0 +1,600 @@
+//! 搜索密码信息的查询表达式
+//!
+//! 查询表达式由若干个条件组成，空格分隔的条件需同时满足(AND)，`OR`连接的条件满足其一即可，
+//! `-`或`NOT`表示不满足该条件，括号用于分组。例如：`url:github.com -tag:old`、`(user:alice OR user:bob) modified<30d`
+//!
+//! 条件：
+//! - `值`：模糊搜索url、desc、username、email、tag、comment、自定义字段的名称以及未受保护的自定义字段的值，
+//!   按顺序包含值中的各个字符即可(忽略大小写)，例如`gthub`可以搜索到`github.com`，见[`fuzzy_score`]
+//! - `字段:值`：搜索指定的字段，字段为`user`、`url`、`desc`、`email`、`phone`、`comment`、`tag`、`folder`、
+//!   `kind`(类型，例如`card`或`银行卡`)、`field`(自定义字段的名称或未受保护的值)
+//! - `has:xxx`：有该项内容，xxx为`otp`、`attachment`、`field`、`history`、`email`、`phone`、`url`、`tag`
+//! - `时间<N单位`、`时间>N单位`：N个单位之内、之前，时间为`created`、`modified`、`used`，
+//!   单位为`h`(小时)、`d`(天)、`w`(周)、`m`(30天)、`y`(365天)，例如`modified<30d`为30天内修改过，
+//!   `used>1y`为一年以上没有使用过(包括从未使用过)
+//!
+//! 值默认为忽略大小写的子串匹配，包含`*`或`?`时为忽略大小写的通配符匹配(需匹配整个值)，例如`email:*@corp.com`，
+//! `/.../`为正则表达式，例如`user:/^a.*e$/`，包含空格的值可使用双引号
+//!
+//! `folder:`匹配该文件夹及其子文件夹，`tag:`匹配整个标签(忽略大小写)
+
+use crate::{
+    entry::EntryKind,
+    error::{Error, PserResult},
+    pser::{Pser, Psers},
+};
+use regex::{Regex, RegexBuilder};
+use std::str::FromStr;
+
+/// 解析后的查询表达式，见[模块文档](self)
+#[derive(Debug, Clone)]
+pub enum Filter {
+    /// 匹配所有密码信息(空表达式)
+    All,
+    And(Vec<Filter>),
+    Or(Vec<Filter>),
+    Not(Box<Filter>),
+    /// 某个字段的值匹配
+    Match(Attr, Pattern),
+    /// 有某项内容
+    Has(Attr),
+    /// 某个时间点距今在secs秒之内(within为true)或之前
+    Age {
+        time: TimeAttr,
+        within: bool,
+        secs: i64,
+    },
+}
+
+/// 查询表达式中可以搜索的字段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Attr {
+    /// 不指定字段，模糊搜索多个字段
+    Any,
+    User,
+    Url,
+    Desc,
+    Email,
+    Phone,
+    Comment,
+    Tag,
+    Folder,
+    Kind,
+    Field,
+    Otp,
+    Attachment,
+    History,
+}
+
+/// 查询表达式中的时间
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimeAttr {
+    Created,
+    Modified,
+    Used,
+}
+
+/// 值的匹配方式
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    /// 忽略大小写的子串匹配，保存的是小写形式
+    Contains(String),
+    /// 通配符或正则表达式
+    Regex(Regex),
+}
+
+impl Pattern {
+    /// 解析值：`/.../`为正则表达式，包含`*`或`?`时为通配符，否则为子串
+    fn parse(value: &str, regex: bool) -> PserResult<Self> {
+        let invalid = |e: regex::Error| Error::InvalidQuery(e.to_string());
+        if regex {
+            return Ok(Self::Regex(Regex::new(value).map_err(invalid)?));
+        }
+        if !value.contains(['*', '?']) {
+            return Ok(Self::Contains(value.to_lowercase()));
+        }
+        let mut glob = String::from("^");
+        for c in value.chars() {
+            match c {
+                '*' => glob.push_str(".*"),
+                '?' => glob.push('.'),
+                c => glob.push_str(&regex::escape(c.encode_utf8(&mut [0; 4]))),
+            }
+        }
+        glob.push('$');
+        let re = RegexBuilder::new(&glob)
+            .case_insensitive(true)
+            .build()
+            .map_err(invalid)?;
+        Ok(Self::Regex(re))
+    }
+
+    pub fn is_match(&self, value: &str) -> bool {
+        match self {
+            Self::Contains(s) => value.to_lowercase().contains(s),
+            Self::Regex(re) => re.is_match(value),
+        }
+    }
+}
+
+impl Filter {
+    /// 解析查询表达式，表达式无效时返回`Error::InvalidQuery`
+    pub fn parse(expr: &str) -> PserResult<Self> {
+        let tokens = tokenize(expr)?;
+        let mut parser = Parser { tokens, pos: 0 };
+        let filter = parser.or()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(filter),
+            Some(_) => Err(Error::InvalidQuery("多余的`)`".to_string())),
+        }
+    }
+
+    /// 在now时(秒级Epoch)，pser是否满足该表达式
+    pub fn matches(&self, pser: &Pser, now: i64) -> bool {
+        match self {
+            Self::All => true,
+            Self::And(filters) => filters.iter().all(|f| f.matches(pser, now)),
+            Self::Or(filters) => filters.iter().any(|f| f.matches(pser, now)),
+            Self::Not(filter) => !filter.matches(pser, now),
+            Self::Match(attr, pattern) => match_attr(pser, *attr, pattern),
+            Self::Has(attr) => has_attr(pser, *attr),
+            Self::Age { time, within, secs } => {
+                let t = match time {
+                    TimeAttr::Created => pser.created,
+                    TimeAttr::Modified => pser.modified,
+                    TimeAttr::Used => pser.last_used,
+                };
+                // 时间为0表示未知或从未使用过，视为很久之前
+                let recent = t != 0 && t >= now - secs;
+                recent == *within
+            }
+        }
+    }
+
+    /// pser和该表达式的相关度，越大越相关：未否定的各个模糊搜索的值的得分之和(`OR`取其中最大的)，
+    /// 没有模糊搜索的值时为0
+    pub fn score(&self, pser: &Pser) -> u32 {
+        match self {
+            Self::And(filters) => filters.iter().map(|f| f.score(pser)).sum(),
+            Self::Or(filters) => filters.iter().map(|f| f.score(pser)).max().unwrap_or(0),
+            Self::Match(Attr::Any, Pattern::Contains(value)) => {
+                pser_score(pser, value).unwrap_or(0)
+            }
+            _ => 0,
+        }
+    }
+
+    /// 在now时(秒级Epoch)，满足该表达式的所有密码信息
+    pub fn select<'a>(&self, psers: &'a Psers, now: i64) -> Vec<(&'a String, &'a Pser)> {
+        psers
+            .inner()
+            .iter()
+            .filter(|(_, pser)| self.matches(pser, now))
+            .collect()
+    }
+}
+
+impl FromStr for Filter {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::parse(s)
+    }
+}
+
+fn match_attr(pser: &Pser, attr: Attr, pattern: &Pattern) -> bool {
+    let any = |values: &[String]| values.iter().any(|v| pattern.is_match(v));
+    match attr {
+        Attr::Any => match pattern {
+            Pattern::Contains(value) => pser_score(pser, value).is_some(),
+            Pattern::Regex(_) => {
+                any(&pser.url)
+                    || any(&pser.email)
+                    || any(&pser.tags)
+                    || [&pser.desc, &pser.username, &pser.comment]
+                        .iter()
+                        .any(|v| pattern.is_match(v))
+                    || pser.fields.iter().any(|f| {
+                        pattern.is_match(&f.name) || (!f.protected && pattern.is_match(&f.value))
+                    })
+            }
+        },
+        Attr::User => pattern.is_match(&pser.username),
+        Attr::Url => any(&pser.url),
+        Attr::Desc => pattern.is_match(&pser.desc),
+        Attr::Email => any(&pser.email),
+        Attr::Phone => any(&pser.phone),
+        Attr::Comment => pattern.is_match(&pser.comment),
+        Attr::Tag => match pattern {
+            Pattern::Contains(tag) => pser.tags.iter().any(|t| t.to_lowercase() == *tag),
+            Pattern::Regex(_) => any(&pser.tags),
+        },
+        Attr::Folder => match pattern {
+            Pattern::Contains(folder) => pser.in_folder(folder),
+            Pattern::Regex(_) => pattern.is_match(&pser.folder),
+        },
+        Attr::Kind => {
+            pattern.is_match(&pser.kind.to_string()) || pattern.is_match(kind_name(&pser.kind))
+        }
+        Attr::Field => pser
+            .fields
+            .iter()
+            .any(|f| pattern.is_match(&f.name) || (!f.protected && pattern.is_match(&f.value))),
+        Attr::Otp | Attr::Attachment | Attr::History => false,
+    }
+}
+
+/// 模糊匹配pattern和text(忽略大小写)：text按顺序包含pattern中的各个字符时匹配，返回得分，否则返回None
+///
+/// 连续匹配、在单词开头匹配的字符得分更高，跳过的字符越多得分越低，因此子串的得分高于分散的匹配，
+/// 完全相同时得分最高
+pub fn fuzzy_score(pattern: &str, text: &str) -> Option<u32> {
+    let pattern: Vec<char> = pattern.to_lowercase().chars().collect();
+    let text: Vec<char> = text.to_lowercase().chars().collect();
+    let first = *pattern.first()?;
+
+    // 从每个可能的起点开始贪婪匹配，取得分最高的
+    let mut best = None;
+    for start in (0..text.len()).filter(|&i| text[i] == first) {
+        let (mut score, mut matched, mut prev) = (0i64, 0, None::<usize>);
+        for (i, &c) in text.iter().enumerate().skip(start) {
+            if c != pattern[matched] {
+                continue;
+            }
+            score += 10;
+            match prev {
+                Some(p) if p + 1 == i => score += 8,
+                Some(p) => score -= ((i - p - 1) * 3).min(10) as i64,
+                None => {}
+            }
+            if i == 0 || !text[i - 1].is_alphanumeric() {
+                score += 6;
+            }
+            prev = Some(i);
+            matched += 1;
+            if matched == pattern.len() {
+                break;
+            }
+        }
+        if matched == pattern.len() {
+            best = best.max(Some(score));
+        }
+    }
+    if pattern == text {
+        best = best.map(|s| s + 20);
+    }
+    best.map(|s| s.max(1) as u32)
+}
+
+/// 模糊搜索pser的各个字段，返回加权后的最高得分，url和desc的权重最高，comment和自定义字段的最低
+fn pser_score(pser: &Pser, pattern: &str) -> Option<u32> {
+    let weighted = |values: &mut dyn Iterator<Item = &String>, weight: u32| {
+        values
+            .filter_map(|v| fuzzy_score(pattern, v))
+            .max()
+            .map(|s| s * weight)
+    };
+    let fields = pser.fields.iter().flat_map(|f| {
+        let value = (!f.protected).then_some(&f.value);
+        std::iter::once(&f.name).chain(value)
+    });
+    [
+        weighted(&mut pser.url.iter().chain([&pser.desc]), 3),
+        weighted(&mut [&pser.username].into_iter(), 2),
+        weighted(&mut pser.email.iter().chain(&pser.tags), 2),
+        weighted(&mut [&pser.comment].into_iter().chain(fields), 1),
+    ]
+    .into_iter()
+    .flatten()
+    .max()
+}
+
+/// 类型在命令行中的名称，例如`ssh-key`
+fn kind_name(kind: &EntryKind) -> &'static str {
+    match kind {
+        EntryKind::Login => "login",
+        EntryKind::Note(_) => "note",
+        EntryKind::Card(_) => "card",
+        EntryKind::Identity(_) => "identity",
+        EntryKind::SshKey(_) => "ssh-key",
+        EntryKind::ApiToken(_) => "api-token",
+    }
+}
+
+fn has_attr(pser: &Pser, attr: Attr) -> bool {
+    match attr {
+        Attr::Otp => pser.otp.is_some(),
+        Attr::Attachment => !pser.attachments.is_empty(),
+        Attr::Field => !pser.fields.is_empty(),
+        Attr::History => !pser.history.is_empty(),
+        Attr::Email => !pser.email.is_empty(),
+        Attr::Phone => !pser.phone.is_empty(),
+        Attr::Url => !pser.url.is_empty(),
+        Attr::Tag => !pser.tags.is_empty(),
+        _ => false,
+    }
+}
+
+/// 词法单元
+#[derive(Debug)]
+enum Token {
+    LParen,
+    RParen,
+    Or,
+    And,
+    Not,
+    /// 条件：(字段, 比较符, 值, 值是否为正则表达式)，不指定字段时字段和比较符为空
+    Term(String, char, String, bool),
+}
+
+fn tokenize(expr: &str) -> PserResult<Vec<Token>> {
+    let chars: Vec<char> = expr.chars().collect();
+    let mut tokens = vec![];
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            c if c.is_whitespace() => i += 1,
+            '(' => {
+                tokens.push(Token::LParen);
+                i += 1;
+            }
+            ')' => {
+                tokens.push(Token::RParen);
+                i += 1;
+            }
+            '-' if chars.get(i + 1).is_some_and(|c| !c.is_whitespace()) => {
+                tokens.push(Token::Not);
+                i += 1;
+            }
+            _ => {
+                // 字段名只包含字母，其后紧跟比较符
+                let name_end = (i..chars.len())
+                    .find(|&j| !chars[j].is_ascii_alphabetic())
+                    .unwrap_or(chars.len());
+                let (name, op) = match chars.get(name_end) {
+                    Some(&op @ (':' | '<' | '>')) if name_end > i => {
+                        (chars[i..name_end].iter().collect(), op)
+                    }
+                    _ => (String::new(), ' '),
+                };
+                if op != ' ' {
+                    i = name_end + 1;
+                }
+                let (value, regex) = read_value(&chars, &mut i)?;
+                let token = match (op, regex, value.as_str()) {
+                    (' ', false, "OR") => Token::Or,
+                    (' ', false, "AND") => Token::And,
+                    (' ', false, "NOT") => Token::Not,
+                    _ => Token::Term(name, op, value, regex),
+                };
+                tokens.push(token);
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+/// 从chars[i]开始读取一个值(双引号中的值、`/.../`中的正则表达式或直到空白字符、括号的值)，返回(值, 是否为正则表达式)
+fn read_value(chars: &[char], i: &mut usize) -> PserResult<(String, bool)> {
+    let quote = match chars.get(*i) {
+        Some(&c @ ('"' | '/')) => c,
+        _ => {
+            let start = *i;
+            while *i < chars.len() && !chars[*i].is_whitespace() && !matches!(chars[*i], '(' | ')')
+            {
+                *i += 1;
+            }
+            return Ok((chars[start..*i].iter().collect(), false));
+        }
+    };
+
+    let mut value = String::new();
+    *i += 1;
+    while let Some(&c) = chars.get(*i) {
+        *i += 1;
+        match c {
+            c if c == quote => return Ok((value, quote == '/')),
+            // 正则表达式中的`\/`表示`/`，其它转义原样保留
+            '\\' if quote == '/' && chars.get(*i) == Some(&'/') => {
+                value.push('/');
+                *i += 1;
+            }
+            c => value.push(c),
+        }
+    }
+    Err(Error::InvalidQuery(format!("缺少结束的`{}`", quote)))
+}
+
+/// 递归下降解析：or := and (OR and)*，and := unary ([AND] unary)*，unary := (NOT|-) unary | ( or ) | 条件
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn or(&mut self) -> PserResult<Filter> {
+        let mut filters = vec![self.and()?];
+        while let Some(Token::Or) = self.peek() {
+            self.pos += 1;
+            filters.push(self.and()?);
+        }
+        Ok(match filters.len() {
+            1 => filters.pop().unwrap(),
+            _ => Filter::Or(filters),
+        })
+    }
+
+    fn and(&mut self) -> PserResult<Filter> {
+        let mut filters = vec![];
+        loop {
+            match self.peek() {
+                None | Some(Token::Or) | Some(Token::RParen) => break,
+                Some(Token::And) => self.pos += 1,
+                _ => filters.push(self.unary()?),
+            }
+        }
+        Ok(match filters.len() {
+            0 => Filter::All,
+            1 => filters.pop().unwrap(),
+            _ => Filter::And(filters),
+        })
+    }
+
+    fn unary(&mut self) -> PserResult<Filter> {
+        let Some(token) = self.tokens.get(self.pos) else {
+            return Err(Error::InvalidQuery("表达式不完整".to_string()));
+        };
+        self.pos += 1;
+        match token {
+            Token::Not => Ok(Filter::Not(Box::new(self.unary()?))),
+            Token::LParen => {
+                let filter = self.or()?;
+                match self.peek() {
+                    Some(Token::RParen) => {
+                        self.pos += 1;
+                        Ok(filter)
+                    }
+                    _ => Err(Error::InvalidQuery("缺少`)`".to_string())),
+                }
+            }
+            Token::Term(name, op, value, regex) => term(name, *op, value, *regex),
+            _ => Err(Error::InvalidQuery("表达式不完整".to_string())),
+        }
+    }
+}
+
+/// 解析一个条件
+fn term(name: &str, op: char, value: &str, regex: bool) -> PserResult<Filter> {
+    let invalid = |msg: &str| {
+        Err(Error::InvalidQuery(format!(
+            "{}: {}{}{}",
+            msg, name, op, value
+        )))
+    };
+    if op == '<' || op == '>' {
+        let time = match name.to_lowercase().as_str() {
+            "created" => TimeAttr::Created,
+            "modified" => TimeAttr::Modified,
+            "used" => TimeAttr::Used,
+            _ => return invalid("只能比较created、modified、used"),
+        };
+        let Some(secs) = parse_duration(value) else {
+            return invalid("无效的时间，例如30d");
+        };
+        return Ok(Filter::Age {
+            time,
+            within: op == '<',
+            secs,
+        });
+    }
+
+    let attr = match name.to_lowercase().as_str() {
+        "" => Attr::Any,
+        "user" | "username" => Attr::User,
+        "url" => Attr::Url,
+        "desc" => Attr::Desc,
+        "email" => Attr::Email,
+        "phone" => Attr::Phone,
+        "comment" => Attr::Comment,
+        "tag" => Attr::Tag,
+        "folder" => Attr::Folder,
+        "kind" | "type" => Attr::Kind,
+        "field" => Attr::Field,
+        "has" => {
+            let attr = match value.to_lowercase().as_str() {
+                "otp" => Attr::Otp,
+                "attachment" => Attr::Attachment,
+                "field" => Attr::Field,
+                "history" => Attr::History,
+                "email" => Attr::Email,
+                "phone" => Attr::Phone,
+                "url" => Attr::Url,
+                "tag" => Attr::Tag,
+                _ => return invalid("未知的内容"),
+            };
+            return Ok(Filter::Has(attr));
+        }
+        _ => return invalid("未知的字段"),
+    };
+    Ok(Filter::Match(attr, Pattern::parse(value, regex)?))
+}
+
+/// 解析时间长度，例如`30d`，返回秒数
+fn parse_duration(value: &str) -> Option<i64> {
+    let unit = match value.chars().last()? {
+        'h' => 3600,
+        'd' => 86400,
+        'w' => 7 * 86400,
+        'm' => 30 * 86400,
+        'y' => 365 * 86400,
+        _ => return None,
+    };
+    // 负数或溢出时视为无效的时间
+    let n: i64 = value[..value.len() - 1].parse().ok().filter(|n| *n >= 0)?;
+    n.checked_mul(unit)
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn parse_and_match() {
+        let now = 1_700_000_000;
+        let mut pser = Pser::new();
+        pser.set_username("alice")
+            .set_email("alice@corp.com")
+            .add_tag("work")
+            .add_url("github.com")
+            .unwrap();
+        pser.modified = now - 10 * 86400;
+
+        let matches = |expr: &str| Filter::parse(expr).unwrap().matches(&pser, now);
+        assert!(matches(""));
+        assert!(matches("user:alice email:*@corp.com"));
+        assert!(!matches("email:*@corp"));
+        assert!(matches("url:github.com -tag:old"));
+        assert!(!matches("url:github.com -tag:WORK"));
+        assert!(matches("modified<30d") && !matches("modified>30d"));
+        assert!(matches("used>1y") && !matches("used<1y"));
+        assert!(matches(r"user:/^a.*e$/ (tag:old OR has:tag)"));
+        assert!(matches("NOT user:bob AND git"));
+        assert!(matches(r#"user:"alice""#));
+
+        for expr in [
+            "(user:a",
+            "user:a)",
+            "size:1",
+            "modified<30",
+            "modified<99999999999999999d",
+            "modified<-5d",
+            "user:/[/",
+            "user:\"a",
+        ] {
+            assert!(matches!(Filter::parse(expr), Err(Error::InvalidQuery(_))));
+        }
+    }
+
+    #[test]
+    fn fuzzy() {
+        assert_eq!(fuzzy_score("gthub", "gitlab.com"), None);
+        assert!(fuzzy_score("gthub", "github.com").is_some());
+        assert!(fuzzy_score("", "github.com").is_none());
+        // 完全相同 > 前缀 > 子串 > 分散的匹配
+        let score = |text| fuzzy_score("git", text).unwrap();
+        assert!(score("git") > score("github.com"));
+        assert!(score("github.com") > score("legit.com"));
+        assert!(score("legit.com") > score("gxixt.com"));
+
+        let mut a = Pser::new();
+        a.set_desc("aliyun");
+        let mut b = Pser::new();
+        b.set_comment("aliyun");
+        let filter = Filter::parse("aliyn").unwrap();
+        assert!(filter.score(&a) > filter.score(&b) && filter.score(&b) > 0);
+    }
+}
@@ -4,17 +4,19 @@ const AZ_UPPER: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ";
 const AZ_LOWER: &[u8] = b"abcdefghijklmnopqrstuvwxyz";
 const NUM: &[u8] = b"0123456789";
 const PUNC: &[u8] = b")(*&^%$#@!~";
+/// 恢复码使用的字符集，去掉了容易混淆的`0O1IL`
+const RECOVERY: &[u8] = b"ABCDEFGHJKMNPQRSTUVWXYZ23456789";
 
 /// 生成随机密码
 ///
 /// up: 生成的密码是否包含大写字母字符集`A-Z`
-/// 
+///
 /// down: 生成的密码是否包含小写字母字符集`a-z`
-/// 
+///
 /// num: 生成的密码是否包含数字字符集`0-9`
-/// 
+///
 /// punc: 生成的密码是否包含一些特殊符号字符集`)(*&^%$#@!~`
-/// 
+///
 /// len: 生成的密码长度
 ///
 /// 注意：即使指定了密码要包含哪类字符集，生成的随机密码中也不一定会包含这类字符，
@@ -57,6 +59,25 @@ pub fn gen_passwd(up: bool, down: bool, num: bool, punc: bool, len: usize) -> St
     password
 }
 
+/// 生成随机恢复码，例如`7KQM-X2HD-...`，共6组，每组4个字符
+pub fn gen_recovery_code() -> String {
+    let mut rng = rand::thread_rng();
+    (0..6)
+        .map(|_| {
+            (0..4)
+                .map(|_| RECOVERY[rng.gen_range(0..RECOVERY.len())] as char)
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("-")
+}
+
+/// 生成随机密钥文件的内容(64字节)
+pub fn gen_keyfile() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    (0..64).map(|_| rng.gen()).collect()
+}
+
 #[cfg(test)]
 mod tt {
     use crate::gen_rand::gen_passwd;
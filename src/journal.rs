@@ -0,0 +1,59 @@
+//! 操作日志和回收站
+//!
+//! 每次修改密码信息(添加、修改、删除、撤销等)时，都会在操作日志中追加一条记录，
+//! 记录被修改的各密码信息在操作前后的完整状态，用于查看密码信息的历史版本和撤销操作。
+//! 操作日志使用数据密钥加密后保存，只保留最近的若干个操作(见[`PserDB`](crate::db_file::PserDB))
+//!
+//! 删除密码信息时先移到回收站，回收站中的密码信息(及其附件)在清空回收站后才被真正删除，
+//! 同时操作日志中它们的所有版本也被删除
+
+use crate::pser::Pser;
+
+/// 操作日志中的一次操作
+#[derive(Debug, Clone)]
+pub struct Operation {
+    /// 操作的序号，从1开始递增
+    pub seq: u64,
+    /// 操作的时间点(秒级Epoch)
+    pub time: i64,
+    /// 操作的说明，例如`修改`、`删除`
+    pub desc: String,
+    /// 撤销操作时，被撤销的各操作的序号，其它操作为空
+    pub undoes: Vec<u64>,
+    /// 被修改的各密码信息
+    pub changes: Vec<Change>,
+}
+
+impl Operation {
+    /// 该操作是否修改了uuid对应的密码信息
+    pub fn touches(&self, uuid: &str) -> bool {
+        self.changes.iter().any(|c| c.uuid == uuid)
+    }
+}
+
+/// 一条密码信息在某次操作前后的状态，不存在(或在回收站中)时为None
+#[derive(Debug, Clone)]
+pub struct Change {
+    pub uuid: String,
+    pub before: Option<Pser>,
+    pub after: Option<Pser>,
+}
+
+impl Change {
+    /// 修改的类型
+    pub fn kind(&self) -> &'static str {
+        match (&self.before, &self.after) {
+            (None, Some(_)) => "添加",
+            (Some(_), None) => "删除",
+            _ => "修改",
+        }
+    }
+}
+
+/// 回收站中的密码信息
+#[derive(Debug, Clone)]
+pub struct Trashed {
+    /// 移到回收站的时间点(秒级Epoch)
+    pub deleted: i64,
+    pub pser: Pser,
+}
@@ -0,0 +1,220 @@
+//! 合并数据不一致的密码库副本
+//!
+//! 以uuid对比两个副本中的每条密码信息，取修改时间较新的版本，修改时间相同时取修订版本号较大的版本。
+//! 删除操作以墓碑(删除时间)的形式保留，以免被删除的密码信息在合并时被另一个副本"复活"。
+//!
+//! 只有同一条密码信息在两个副本中的修改时间和修订版本号都相同但内容不同时，才视为冲突，需要人工决定保留哪个版本。
+//! 最近使用时间和HOTP计数器不视为修改，合并时取两者中较晚(较大)的一个，HOTP计数器因此不会回退。
+
+use crate::pser::{Pser, Psers};
+use chrono_ext::now8;
+use std::{cmp::Ordering, collections::HashMap, path::PathBuf};
+
+/// 合并副本时所需的同步信息(各密码信息的修改时间和修订版本号保存在`Pser`中)
+#[derive(Debug, Default, Clone)]
+pub struct SyncMeta {
+    /// 已删除的密码信息(墓碑)，key为uuid，value为删除的时间点(秒级Epoch)
+    tombstones: HashMap<String, i64>,
+}
+
+impl SyncMeta {
+    /// 记录uuid对应的密码信息被修改(或新增)，清除其墓碑
+    pub fn touch(&mut self, uuid: &str) {
+        self.tombstones.remove(uuid);
+    }
+
+    /// 记录uuid对应的密码信息在当前时间被删除
+    pub fn bury(&mut self, uuid: &str) {
+        self.tombstones.insert(uuid.to_string(), now8().timestamp());
+    }
+
+    /// 删除的时间点，没有被删除时为None
+    pub fn deleted_at(&self, uuid: &str) -> Option<i64> {
+        self.tombstones.get(uuid).copied()
+    }
+
+    /// 所有已删除的密码信息的uuid
+    pub fn tombstones(&self) -> impl Iterator<Item = &String> {
+        self.tombstones.keys()
+    }
+
+    /// 设置删除时间(从密码库中读取时使用)
+    pub fn set_deleted(&mut self, uuid: &str, deleted: i64) {
+        self.tombstones.insert(uuid.to_string(), deleted);
+    }
+}
+
+/// 合并时无法自动决定的冲突
+#[derive(Debug, Clone)]
+pub struct Conflict {
+    pub uuid: String,
+    /// 冲突来自哪个副本
+    pub replica: PathBuf,
+    /// 该副本中的版本(当前保留的是本地版本)
+    pub theirs: Pser,
+}
+
+/// 将另一个副本的数据(remote)合并到本地数据(local)中
+///
+/// 返回冲突的密码信息(uuid, 另一个副本中的版本)，冲突时保留本地版本
+pub fn merge(
+    local: &mut Psers,
+    local_meta: &mut SyncMeta,
+    mut remote: Psers,
+    remote_meta: SyncMeta,
+) -> Vec<(String, Pser)> {
+    let mut conflicts = vec![];
+
+    for (uuid, mut theirs) in remote.inner_mut().drain() {
+        if let Some(&deleted) = local_meta.tombstones.get(&uuid) {
+            // 本地删除之后，另一个副本又修改了，则恢复
+            if theirs.modified > deleted {
+                local_meta.tombstones.remove(&uuid);
+                local.inner_mut().insert(uuid, theirs);
+            }
+            continue;
+        }
+
+        let Some(ours) = local.inner_mut().get_mut(&uuid) else {
+            local.inner_mut().insert(uuid, theirs);
+            continue;
+        };
+        let last_used = ours.last_used.max(theirs.last_used);
+        ours.keep_hotp_counter(&theirs);
+        theirs.keep_hotp_counter(ours);
+        match newer(&theirs, ours) {
+            Ordering::Greater => {
+                theirs.last_used = last_used;
+                *ours = theirs;
+            }
+            Ordering::Equal if !ours.same_content(&theirs) => {
+                ours.last_used = last_used;
+                conflicts.push((uuid, theirs));
+            }
+            _ => ours.last_used = last_used,
+        }
+    }
+
+    for (uuid, deleted) in remote_meta.tombstones {
+        // 本地在另一个副本删除之后又修改了，则保留
+        if let Some(ours) = local.inner().get(&uuid) {
+            if ours.modified > deleted {
+                continue;
+            }
+        }
+        local.inner_mut().remove(&uuid);
+        let t = local_meta.tombstones.entry(uuid).or_insert(deleted);
+        *t = (*t).max(deleted);
+    }
+
+    conflicts
+}
+
+/// 比较两个版本的新旧：先比较修改时间，修改时间相同时比较修订版本号
+fn newer(a: &Pser, b: &Pser) -> Ordering {
+    (a.modified, a.revision).cmp(&(b.modified, b.revision))
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+    use crate::otp::OtpKind;
+
+    fn pser(passwd: &str, modified: i64) -> Pser {
+        let mut p = Pser::new();
+        p.set_passwd(passwd);
+        p.modified = modified;
+        p
+    }
+
+    fn side(entries: &[(&str, &str, i64)], tombs: &[(&str, i64)]) -> (Psers, SyncMeta) {
+        let mut psers = Psers::default();
+        let mut meta = SyncMeta::default();
+        for (uuid, passwd, t) in entries {
+            psers.inner_mut().insert(uuid.to_string(), pser(passwd, *t));
+        }
+        for (uuid, t) in tombs {
+            meta.tombstones.insert(uuid.to_string(), *t);
+        }
+        (psers, meta)
+    }
+
+    #[test]
+    fn newer_wins() {
+        let (mut l, mut lm) = side(&[("a", "old", 10), ("b", "mine", 30)], &[]);
+        let (r, rm) = side(&[("a", "new", 20), ("b", "theirs", 20), ("c", "c", 5)], &[]);
+
+        let conflicts = merge(&mut l, &mut lm, r, rm);
+        assert!(conflicts.is_empty());
+        assert_eq!(l.inner()["a"].passwd, "new");
+        assert_eq!(l.inner()["b"].passwd, "mine");
+        assert_eq!(l.inner()["c"].passwd, "c");
+    }
+
+    #[test]
+    fn tombstones() {
+        let (mut l, mut lm) = side(&[("a", "a", 10), ("b", "b", 50)], &[("c", 30)]);
+        let (r, rm) = side(&[("c", "c", 20)], &[("a", 20), ("b", 40)]);
+
+        merge(&mut l, &mut lm, r, rm);
+        // a在另一个副本中被删除
+        assert!(!l.inner().contains_key("a"));
+        // b在另一个副本删除之后又被本地修改
+        assert!(l.inner().contains_key("b"));
+        // c在本地被删除，另一个副本的修改早于删除
+        assert!(!l.inner().contains_key("c"));
+        assert_eq!(lm.tombstones["a"], 20);
+    }
+
+    #[test]
+    fn conflict() {
+        let (mut l, mut lm) = side(&[("a", "mine", 10), ("b", "same", 10)], &[]);
+        let (r, rm) = side(&[("a", "theirs", 10), ("b", "same", 10)], &[]);
+
+        let conflicts = merge(&mut l, &mut lm, r, rm);
+        assert_eq!(conflicts.len(), 1);
+        assert_eq!(conflicts[0].0, "a");
+        assert_eq!(conflicts[0].1.passwd, "theirs");
+        assert_eq!(l.inner()["a"].passwd, "mine");
+    }
+
+    #[test]
+    fn revision_and_last_used() {
+        let (mut l, mut lm) = side(&[("a", "mine", 10), ("b", "same", 10)], &[]);
+        let (mut r, rm) = side(&[("a", "theirs", 10), ("b", "same", 10)], &[]);
+        r.inner_mut().get_mut("a").unwrap().revision = 2;
+        r.inner_mut().get_mut("b").unwrap().last_used = 50;
+
+        let conflicts = merge(&mut l, &mut lm, r, rm);
+        assert!(conflicts.is_empty());
+        assert_eq!(l.inner()["a"].passwd, "theirs");
+        assert_eq!(l.inner()["b"].last_used, 50);
+    }
+
+    #[test]
+    fn hotp_counter() {
+        let (mut l, mut lm) = side(&[("a", "mine", 10), ("b", "same", 10)], &[]);
+        let (mut r, rm) = side(&[("a", "theirs", 20), ("b", "same", 10)], &[]);
+        let set_hotp = |psers: &mut Psers, uuid: &str, counter: u64| {
+            let uri = format!("otpauth://hotp/x?secret=GEZDGNBV&counter={}", counter);
+            psers
+                .inner_mut()
+                .get_mut(uuid)
+                .unwrap()
+                .set_otp(&uri)
+                .unwrap();
+        };
+        let counter = |psers: &Psers, uuid: &str| psers.inner()[uuid].otp.as_ref().unwrap().kind;
+        set_hotp(&mut l, "a", 7);
+        set_hotp(&mut r, "a", 3);
+        set_hotp(&mut l, "b", 2);
+        set_hotp(&mut r, "b", 5);
+
+        // 另一个副本中较新的修改不会使计数器回退，只有计数器不同时不视为冲突
+        let conflicts = merge(&mut l, &mut lm, r, rm);
+        assert!(conflicts.is_empty());
+        assert_eq!(l.inner()["a"].passwd, "theirs");
+        assert_eq!(counter(&l, "a"), OtpKind::Hotp { counter: 7 });
+        assert_eq!(counter(&l, "b"), OtpKind::Hotp { counter: 5 });
+    }
+}
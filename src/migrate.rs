@@ -0,0 +1,258 @@
+//! 密码库的格式版本和升级
+//!
+//! 密码库中的数据都是bincode序列化的，bincode按字段顺序编码，修改`Pser`、`Row`或`VerifyHeader`的字段后将无法解码旧数据。
+//! 因此每次修改它们的布局时，都需要将FORMAT_VERSION加1，并在MIGRATIONS中添加一个升级步骤：
+//! 在本模块中保留旧布局的类型定义，将旧布局的数据转换为新布局。
+//!
+//! 读取旧版本的密码库时，验证头和解密后的每一行依次经过各升级步骤转换为当前布局，
+//! 打开密码库后会以当前格式重新写入(见[`PserDB`](crate::db_file::PserDB))。
+//!
+//! 各版本：
+//! 1. 整个Psers使用主密码加密后保存，验证头中保存主密码的Sha512
+//! 2. 每条密码信息单独加密为一行，随机生成的数据密钥由各凭据包装后保存在验证头的密钥槽中，
+//!    验证头中保存解锁失败记录和锁定策略，密码库中记录格式版本
+
+use crate::{
+    error::{Error, PserResult},
+    pser::{OldPasswd, Pser},
+    throttle::Throttle,
+    verify_header::{KeySlot, MAGIC as HEADER_MAGIC},
+};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use zeroize::{Zeroize, Zeroizing};
+
+/// 当前的格式版本
+pub const FORMAT_VERSION: u32 = 2;
+
+/// 格式信息序列化数据的开头
+const MAGIC: [u8; 8] = *b"PSER-DB\0";
+
+/// 转换某个版本的bincode数据，得到下一个版本的bincode数据
+pub type Convert = fn(&[u8]) -> PserResult<Vec<u8>>;
+
+/// 升级步骤：从版本from升级到from+1
+pub struct Migration {
+    pub from: u32,
+    /// 该步骤修改了什么
+    pub desc: &'static str,
+    /// 转换验证头，None表示验证头的布局不变
+    pub header: Option<Convert>,
+    /// 转换解密后的每一行，None表示行的布局不变
+    pub row: Option<Convert>,
+    /// 是否需要使用主密码打开后重新加密所有数据
+    pub reencrypt: bool,
+}
+
+/// 所有升级步骤，按from排序
+///
+/// 版本1中的数据不是按行保存的，见[`upgrade_legacy`]
+pub const MIGRATIONS: &[Migration] = &[Migration {
+    from: 1,
+    desc:
+        "每条密码信息单独加密为一行，生成数据密钥保存在验证头的密钥槽中，验证头中保存解锁失败记录",
+    header: Some(header_v1),
+    row: None,
+    reencrypt: true,
+}];
+
+/// 密码库的格式信息
+#[derive(Debug, Serialize, Deserialize)]
+struct Format {
+    magic: [u8; 8],
+    version: u32,
+}
+
+/// 当前格式信息的bincode序列化数据
+pub fn encode_format() -> Vec<u8> {
+    let format = Format {
+        magic: MAGIC,
+        version: FORMAT_VERSION,
+    };
+    bincode::serialize(&format).expect("can't serialize Format")
+}
+
+/// 从格式信息中读取格式版本，版本比当前程序支持的版本新时返回Error
+pub fn decode_format(data: &[u8]) -> PserResult<u32> {
+    let format = bincode::deserialize::<Format>(data)?;
+    if format.magic != MAGIC {
+        return Err(Error::Other(anyhow!("无法识别的密码库格式")));
+    }
+    if format.version > FORMAT_VERSION {
+        return Err(Error::Other(anyhow!(
+            "密码库的格式版本({})比当前程序支持的版本({})新，请升级程序",
+            format.version,
+            FORMAT_VERSION
+        )));
+    }
+    Ok(format.version)
+}
+
+/// 从版本version升级到当前版本需要执行的步骤
+pub fn pending(version: u32) -> impl Iterator<Item = &'static Migration> {
+    MIGRATIONS.iter().filter(move |m| m.from >= version)
+}
+
+/// 将版本version的验证头转换为当前布局
+pub fn migrate_header(data: Vec<u8>, version: u32) -> PserResult<Vec<u8>> {
+    pending(version)
+        .filter_map(|m| m.header)
+        .try_fold(data, |data, convert| convert(&data))
+}
+
+/// 将版本version中解密后的一行转换为当前布局，转换过程中的明文在drop时清零
+pub fn migrate_row(data: Zeroizing<Vec<u8>>, version: u32) -> PserResult<Zeroizing<Vec<u8>>> {
+    pending(version)
+        .filter_map(|m| m.row)
+        .try_fold(data, |data, convert| convert(&data).map(Zeroizing::new))
+}
+
+/// 版本1的验证头(升级后不再限制每分钟的尝试次数，timestamp和try_count只用于按布局解码)
+#[allow(dead_code)]
+#[derive(Debug, Deserialize)]
+struct HeaderV1 {
+    timestamp: i64,
+    try_count: u16,
+    verify_data: Vec<u8>,
+}
+
+/// 版本2的验证头
+#[derive(Debug, Serialize)]
+struct HeaderV2 {
+    magic: [u8; 8],
+    throttle: Throttle,
+    legacy_check: Vec<u8>,
+    slots: Vec<KeySlot>,
+}
+
+/// 版本1的密码信息
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct PserV1 {
+    username: String,
+    url: String,
+    desc: String,
+    email: String,
+    phone: String,
+    passwd: String,
+    comment: String,
+    history: HashMap<i64, String>,
+}
+
+impl Drop for PserV1 {
+    fn drop(&mut self) {
+        self.passwd.zeroize();
+        self.history.values_mut().for_each(Zeroize::zeroize);
+    }
+}
+
+/// 版本1中整个加密保存的密码信息，key为uuid
+pub(crate) type PsersV1 = HashMap<String, PserV1>;
+
+/// 版本1到2：没有密钥槽，打开后使用主密码验证并生成数据密钥；不再限制每分钟的尝试次数，改为保存失败记录
+fn header_v1(data: &[u8]) -> PserResult<Vec<u8>> {
+    let old = bincode::deserialize::<HeaderV1>(data)?;
+    let new = HeaderV2 {
+        magic: HEADER_MAGIC,
+        throttle: Throttle::default(),
+        legacy_check: old.verify_data,
+        slots: vec![],
+    };
+    Ok(bincode::serialize(&new)?)
+}
+
+/// 将版本1中的密码信息转换为当前布局
+///
+/// 没有记录创建和修改时间，以最早的历史密码的修改时间作为创建时间；
+/// URL最多一个，多个邮箱或联系方式使用逗号分隔
+pub(crate) fn upgrade_legacy(psers: PsersV1) -> Vec<(String, Pser)> {
+    let mut upgraded = vec![];
+    for (uuid, mut old) in psers {
+        let mut history: Vec<_> = old
+            .history
+            .drain()
+            .map(|(changed, passwd)| OldPasswd { changed, passwd })
+            .collect();
+        history.sort_by_key(|h| h.changed);
+        let mut pser = Pser::new();
+        pser.username = std::mem::take(&mut old.username);
+        pser.url = split_list(&old.url);
+        pser.desc = std::mem::take(&mut old.desc);
+        pser.email = split_list(&old.email);
+        pser.phone = split_list(&old.phone);
+        pser.passwd = std::mem::take(&mut old.passwd);
+        pser.comment = std::mem::take(&mut old.comment);
+        pser.created = history.first().map(|h| h.changed).unwrap_or(0);
+        pser.history = history;
+        pser.revision = 1;
+        upgraded.push((uuid, pser));
+    }
+    upgraded
+}
+
+/// 将逗号分隔的字符串拆分为列表，去掉空白和重复项
+fn split_list(joined: &str) -> Vec<String> {
+    let mut list: Vec<String> = vec![];
+    for item in joined.split(',').map(str::trim) {
+        if !item.is_empty() && !list.iter().any(|i| i == item) {
+            list.push(item.to_string());
+        }
+    }
+    list
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+    use crate::verify_header::{Credential, Unlocked, VerifyHeader};
+    use sha2::{Digest, Sha512};
+
+    #[test]
+    fn legacy_header() {
+        let v1 = (1700000000i64, 3u16, Sha512::digest("main").to_vec());
+        let v1 = bincode::serialize(&v1).unwrap();
+        let header = VerifyHeader::decode(&migrate_header(v1, 1).unwrap()).unwrap();
+        assert!(header.slots().is_empty());
+        assert_eq!(header.throttle().failures(), 0);
+        assert!(matches!(
+            header.unlock_slots(&Credential::passwd("main")),
+            Some(Unlocked::Legacy)
+        ));
+        assert!(header.unlock_slots(&Credential::passwd("wrong")).is_none());
+
+        let key = crypt::EncryptData::gen_key();
+        let header = VerifyHeader::new(&Credential::passwd("main"), &key).unwrap();
+        let current = migrate_header(header.encode(), FORMAT_VERSION).unwrap();
+        assert_eq!(current, header.encode());
+    }
+
+    #[test]
+    fn legacy_psers() {
+        let mut old = PserV1::default();
+        old.passwd = "secret".to_string();
+        old.url = "abc.com".to_string();
+        old.email = "a@abc.com, b@abc.com,a@abc.com".to_string();
+        old.history.insert(200, "older".to_string());
+        old.history.insert(100, "oldest".to_string());
+
+        let upgraded = upgrade_legacy(PsersV1::from([("a".to_string(), old)]));
+        let (uuid, pser) = &upgraded[0];
+        assert_eq!((uuid.as_str(), pser.passwd.as_str()), ("a", "secret"));
+        assert_eq!(pser.url, ["abc.com"]);
+        assert_eq!(pser.email, ["a@abc.com", "b@abc.com"]);
+        assert!(pser.phone.is_empty());
+        let history: Vec<_> = pser.history.iter().map(|h| h.changed).collect();
+        assert_eq!(history, [100, 200]);
+        assert_eq!((pser.created, pser.modified, pser.revision), (100, 0, 1));
+    }
+
+    #[test]
+    fn newer_format() {
+        assert_eq!(decode_format(&encode_format()).unwrap(), FORMAT_VERSION);
+        let newer = Format {
+            magic: MAGIC,
+            version: FORMAT_VERSION + 1,
+        };
+        assert!(decode_format(&bincode::serialize(&newer).unwrap()).is_err());
+    }
+}
@@ -1,36 +1,46 @@
+use crate::{
+    entry::EntryKind,
+    error::{Error, PserResult},
+    otp::{Otp, OtpKind},
+};
+use anyhow::anyhow;
 use chrono_ext::{east8, now8, EpochToDateTimeExt};
 use regex::Regex;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, fmt::Write};
+use zeroize::{Zeroize, Zeroizing};
+
+/// 显示密码信息时预先分配的容量，尽量避免字符串扩容时在已释放的内存中留下密码
+const DISPLAY_CAPACITY: usize = 1024;
 
 /// ```
-/// // 按需调用`set_xxx()`方法
+/// // 按需调用`set_xxx()`方法，`set_url`在无法解析出域名时返回Error
 /// let mut pser = Pser::new();
 /// pser.set_username("juji")
-///     .set_url("google.com")
+///     .set_url("google.com")?
 ///     .set_desc("google")
 ///     .set_passwd("juji@ha124")
-///     .set_email("juji@hotmail.com")
-///     .set_phone("18812345678")
+///     .set_email("juji@hotmail.com,juji@gmail.com")
+///     .add_phone("18812345678")
 ///     .set_comment("card_num:9120837490102991");
 /// ```
-#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
 pub struct Pser {
     /// 账户名/用户名
     pub username: String,
 
-    /// 哪个网站的账户(基于域名)
-    pub url: String,
+    /// 哪些网站的账户(基于域名)，同一个账户可能用于多个域名
+    pub url: Vec<String>,
 
     /// 什么账户，可以结合url或者和url二选一
     /// 例如google的账户，可以记录为`url:google.com`或者`desc:google`，或者两者都记录
     pub desc: String,
 
-    /// 邮箱，如果有第二邮箱，则应该使用备注保存
-    pub email: String,
+    /// 邮箱，可以有多个
+    pub email: Vec<String>,
 
-    /// 联系方式，如果有第二联系方式，则应该使用备注保存
-    pub phone: String,
+    /// 联系方式，可以有多个
+    pub phone: Vec<String>,
 
     /// 密码，可以是单个密码，也可以是逗号分隔的多个单词助记词
     pub passwd: String,
@@ -38,12 +48,125 @@ pub struct Pser {
     /// 备注，记录额外信息
     pub comment: String,
 
-    /// 历史密码信息。密码被修改后，旧密码保存在此  
-    ///
-    /// - key: 被修改的时间点(秒级Epoch)
+    /// 类型及该类型的字段，账户只使用上面的通用字段
+    #[serde(default)]
+    pub kind: EntryKind,
+
+    /// 所在的文件夹，以`/`分隔的路径，例如`work/aws/prod`，为空时在根目录下
+    #[serde(default)]
+    pub folder: String,
+
+    /// 标签，按字母顺序排列，不重复
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// 自定义字段，按添加的顺序排列，名称不重复
+    #[serde(default)]
+    pub fields: Vec<Field>,
+
+    /// 一次性密码(2FA)的配置
+    #[serde(default)]
+    pub otp: Option<Otp>,
+
+    /// 附件，按添加的顺序排列，名称不重复
     ///
-    /// - value: 被修改的旧密码(可能是纯密码，可能是多个逗号分隔的单词助记词)
-    pub history: HashMap<i64, String>,
+    /// 这里只保存附件的元数据，附件内容加密后单独保存(见[`PserDB`](crate::db_file::PserDB))
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+
+    /// 历史密码信息。密码被修改后，旧密码保存在此，按修改的先后顺序排列(最后一个是最近被修改的)，
+    /// 同一秒内修改多次时各旧密码都会保留
+    #[serde(default)]
+    pub history: Vec<OldPasswd>,
+
+    /// 该密码信息的旧密码保留策略，为None时使用密码库的保留策略(见[`PserDB`](crate::db_file::PserDB))
+    #[serde(default)]
+    pub history_policy: Option<HistoryPolicy>,
+
+    /// 创建的时间点(秒级Epoch)，旧版本中保存的密码信息为已知的最早时间点，未知时为0
+    #[serde(default)]
+    pub created: i64,
+
+    /// 最后一次修改的时间点(秒级Epoch)，合并副本时修改时间较新的版本胜出
+    #[serde(default)]
+    pub modified: i64,
+
+    /// 最近一次使用(查询出密码)的时间点(秒级Epoch)，从未使用过时为0
+    #[serde(default)]
+    pub last_used: i64,
+
+    /// 修订版本号，每次修改加1，合并副本时修改时间相同则比较修订版本号
+    #[serde(default)]
+    pub revision: u64,
+}
+
+/// 自定义字段，例如卡号、安全问题、API Key
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Field {
+    pub name: String,
+    pub value: String,
+    /// 受保护的字段，查询时默认隐藏其值，也不参与搜索
+    pub protected: bool,
+}
+
+/// 被修改的旧密码
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct OldPasswd {
+    /// 被修改的时间点(秒级Epoch)
+    pub changed: i64,
+    /// 旧密码(可能是纯密码，可能是多个逗号分隔的单词助记词)
+    pub passwd: String,
+}
+
+/// 旧密码的保留策略，两个限制都设置时同时生效，都不设置时保留所有旧密码
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryPolicy {
+    /// 最多保留最近的几个旧密码
+    pub keep: Option<u32>,
+    /// 最多保留最近几天内被修改的旧密码
+    pub max_days: Option<u32>,
+}
+
+impl HistoryPolicy {
+    /// 在now时(秒级Epoch)，按该策略需要删除的旧密码的数量(history按修改的先后顺序排列)
+    fn expired(&self, history: &[OldPasswd], now: i64) -> usize {
+        let by_count = match self.keep {
+            Some(keep) => history.len().saturating_sub(keep as usize),
+            None => 0,
+        };
+        let by_age = match self.max_days {
+            Some(days) => {
+                let oldest = now - days as i64 * 86400;
+                history.iter().take_while(|h| h.changed < oldest).count()
+            }
+            None => 0,
+        };
+        by_count.max(by_age)
+    }
+}
+
+impl std::fmt::Display for HistoryPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.keep, self.max_days) {
+            (None, None) => write!(f, "保留所有旧密码"),
+            (Some(keep), None) => write!(f, "保留最近{}个旧密码", keep),
+            (None, Some(days)) => write!(f, "保留{}天内的旧密码", days),
+            (Some(keep), Some(days)) => write!(f, "保留{}天内、最近{}个旧密码", days, keep),
+        }
+    }
+}
+
+/// 附件的元数据，例如恢复码PDF、SSH私钥、授权文件
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct Attachment {
+    /// 附件内容在密码库中的id
+    pub id: String,
+    /// 文件名
+    pub name: String,
+    /// 附件大小(字节)
+    pub size: u64,
+    /// 添加的时间点(秒级Epoch)
+    pub added: i64,
 }
 
 impl Pser {
@@ -53,11 +176,63 @@ impl Pser {
 
     /// 给定字符串，查询是否是该账号。例如，查询是否是google的账号
     ///
-    /// 只查询 url 和 desc 两个字段
+    /// 查询 url、desc、自定义字段的名称以及未受保护的自定义字段的值
     pub fn is_me(&self, str: &str) -> bool {
         let str = str.to_lowercase();
-        self.url.to_ascii_lowercase().contains(&str)
+        self.url
+            .iter()
+            .any(|url| url.to_ascii_lowercase().contains(&str))
             || self.desc.to_ascii_lowercase().contains(&str)
+            || self.fields.iter().any(|f| {
+                f.name.to_lowercase().contains(&str)
+                    || (!f.protected && f.value.to_lowercase().contains(&str))
+            })
+    }
+
+    /// 记录在now时被修改(或新增)：更新修改时间，修订版本号加1，没有创建时间时以now作为创建时间
+    ///
+    /// 由`PserDB`在保存时调用，一般不需要手动调用
+    pub fn touch(&mut self, now: i64) -> &mut Self {
+        if self.created == 0 {
+            self.created = now;
+        }
+        self.modified = now;
+        self.revision += 1;
+        self
+    }
+
+    /// 除最近使用时间和HOTP计数器外，内容是否相同(最近使用时间和HOTP计数器不视为修改)
+    pub fn same_content(&self, other: &Pser) -> bool {
+        let mut ours = self.clone();
+        let mut other = other.clone();
+        ours.keep_hotp_counter(&other);
+        other.keep_hotp_counter(self);
+        other.last_used = self.last_used;
+        ours == other
+    }
+
+    /// 和other使用同一个HOTP(密钥、算法和位数都相同)时，计数器取两者中较大的一个
+    ///
+    /// 计数器只能增加，以免再次生成已经使用过的一次性密码
+    pub fn keep_hotp_counter(&mut self, other: &Pser) -> &mut Self {
+        let (Some(ours), Some(theirs)) = (self.otp.as_mut(), other.otp.as_ref()) else {
+            return self;
+        };
+        if let (
+            OtpKind::Hotp { counter },
+            OtpKind::Hotp {
+                counter: other_counter,
+            },
+        ) = (&mut ours.kind, &theirs.kind)
+        {
+            if ours.secret == theirs.secret
+                && ours.algorithm == theirs.algorithm
+                && ours.digits == theirs.digits
+            {
+                *counter = (*counter).max(*other_counter);
+            }
+        }
+        self
     }
 
     pub fn set_username(&mut self, username: &str) -> &mut Self {
@@ -65,109 +240,378 @@ impl Pser {
         self
     }
 
-    pub fn set_url(&mut self, url: &str) -> &mut Self {
-        let domain = domain_from_url(url).expect("unsupport url format");
-        self.url = domain.to_string();
+    /// 替换所有URL，只保存URL中的域名部分，无法解析出域名时返回`Error::InvalidUrl`
+    pub fn set_url(&mut self, url: &str) -> PserResult<&mut Self> {
+        let domain = domain_from_url(url).ok_or_else(|| Error::InvalidUrl(url.to_string()))?;
+        self.url = vec![domain.to_string()];
+        Ok(self)
+    }
+
+    /// 添加URL(只保存域名部分)，已存在时什么都不做，无法解析出域名时返回`Error::InvalidUrl`
+    pub fn add_url(&mut self, url: &str) -> PserResult<&mut Self> {
+        let domain = domain_from_url(url).ok_or_else(|| Error::InvalidUrl(url.to_string()))?;
+        add_item(&mut self.url, domain);
+        Ok(self)
+    }
+
+    /// 删除URL，url可以是完整的URL或域名
+    pub fn remove_url(&mut self, url: &str) -> &mut Self {
+        let domain = domain_from_url(url).unwrap_or(url);
+        self.url.retain(|u| u != domain);
         self
     }
 
+    /// 第一个URL，没有URL时为desc，用于简短地表示该密码信息
+    pub fn title(&self) -> &str {
+        self.url.first().unwrap_or(&self.desc)
+    }
+
     pub fn set_desc(&mut self, desc: &str) -> &mut Self {
         self.desc = desc.to_string();
         self
     }
 
+    /// 替换所有邮箱，可以使用逗号分隔多个邮箱
     pub fn set_email(&mut self, email: &str) -> &mut Self {
-        self.email = email.to_string();
+        self.email.clear();
+        email.split(',').for_each(|e| add_item(&mut self.email, e));
         self
     }
 
+    /// 添加邮箱，已存在时什么都不做
+    pub fn add_email(&mut self, email: &str) -> &mut Self {
+        add_item(&mut self.email, email);
+        self
+    }
+
+    pub fn remove_email(&mut self, email: &str) -> &mut Self {
+        self.email.retain(|e| e != email.trim());
+        self
+    }
+
+    /// 替换所有联系方式，可以使用逗号分隔多个联系方式
     pub fn set_phone(&mut self, phone: &str) -> &mut Self {
-        self.phone = phone.to_string();
+        self.phone.clear();
+        phone.split(',').for_each(|p| add_item(&mut self.phone, p));
+        self
+    }
+
+    /// 添加联系方式，已存在时什么都不做
+    pub fn add_phone(&mut self, phone: &str) -> &mut Self {
+        add_item(&mut self.phone, phone);
         self
     }
 
+    pub fn remove_phone(&mut self, phone: &str) -> &mut Self {
+        self.phone.retain(|p| p != phone.trim());
+        self
+    }
+
+    /// 修改密码，旧密码(非空且和新密码不同时)保存到历史密码中
     pub fn set_passwd(&mut self, passwd: &str) -> &mut Self {
+        if self.passwd == passwd {
+            return self;
+        }
         let old_passwd = std::mem::replace(&mut self.passwd, passwd.to_string());
         if !old_passwd.is_empty() {
-            self.history.insert(now8().timestamp(), old_passwd);
+            self.history.push(OldPasswd {
+                changed: now8().timestamp(),
+                passwd: old_passwd,
+            });
         }
         self
     }
 
+    /// 恢复第n个(从1开始，最近被修改的为第1个，即`history_passwds`中的顺序)旧密码，
+    /// 该旧密码从历史密码中移除，当前密码保存到历史密码中
+    pub fn restore_passwd(&mut self, n: usize) -> PserResult<&mut Self> {
+        let len = self.history.len();
+        if n == 0 || n > len {
+            return Err(Error::Other(anyhow!(
+                "没有第{}个旧密码，共有{}个旧密码",
+                n,
+                len
+            )));
+        }
+        let mut old = self.history.remove(len - n);
+        self.set_passwd(&old.passwd);
+        old.passwd.zeroize();
+        Ok(self)
+    }
+
+    /// 设置该密码信息的旧密码保留策略，为None时使用密码库的保留策略
+    pub fn set_history_policy(&mut self, policy: Option<HistoryPolicy>) -> &mut Self {
+        self.history_policy = policy;
+        self
+    }
+
+    /// 在now时(秒级Epoch)，按保留策略删除过期的旧密码(已设置该密码信息自己的策略时忽略给定的策略)，
+    /// 返回删除的数量
+    pub fn prune_history(&mut self, policy: &HistoryPolicy, now: i64) -> usize {
+        let policy = self.history_policy.as_ref().unwrap_or(policy);
+        let expired = policy.expired(&self.history, now);
+        self.history
+            .drain(..expired)
+            .for_each(|mut old| old.passwd.zeroize());
+        expired
+    }
+
     pub fn set_comment(&mut self, comment: &str) -> &mut Self {
         self.comment = comment.to_string();
         self
     }
 
-    /// 查看所有被修改过的旧密码
+    /// 设置类型及该类型的字段，字段无效(例如卡号无法通过Luhn校验)时返回Error
+    pub fn set_kind(&mut self, kind: EntryKind) -> PserResult<&mut Self> {
+        kind.check()?;
+        self.kind = kind;
+        Ok(self)
+    }
+
+    /// 设置所在的文件夹，去掉路径中多余的`/`和各级名称两端的空白字符
+    pub fn set_folder(&mut self, folder: &str) -> &mut Self {
+        self.folder = normalize_folder(folder);
+        self
+    }
+
+    /// 是否在文件夹folder(包括其子文件夹)中，folder为空时表示根目录，即所有密码信息
+    pub fn in_folder(&self, folder: &str) -> bool {
+        let folder = normalize_folder(folder);
+        folder.is_empty()
+            || self.folder == folder
+            || self
+                .folder
+                .strip_prefix(&folder)
+                .is_some_and(|rest| rest.starts_with('/'))
+    }
+
+    /// 将文件夹from(包括其子文件夹)移动到文件夹to下，
+    /// 例如将`work/aws`移动到`cloud`时，`work/aws/prod`变为`cloud/aws/prod`，不在from中时什么都不做
+    pub fn move_folder(&mut self, from: &str, to: &str) -> &mut Self {
+        if !self.in_folder(from) {
+            return self;
+        }
+        let from = normalize_folder(from);
+        // from的最后一级及其之后的部分
+        let start = from.rfind('/').map_or(0, |i| i + 1);
+        let moved = format!("{}/{}", to, &self.folder[start..]);
+        self.set_folder(&moved)
+    }
+
+    /// 添加标签(去掉两端的空白字符)，已存在时什么都不做
+    pub fn add_tag(&mut self, tag: &str) -> &mut Self {
+        let tag = tag.trim();
+        if let Err(idx) = self.tags.binary_search_by(|t| t.as_str().cmp(tag)) {
+            if !tag.is_empty() {
+                self.tags.insert(idx, tag.to_string());
+            }
+        }
+        self
+    }
+
+    /// 删除标签
+    pub fn remove_tag(&mut self, tag: &str) -> &mut Self {
+        self.tags.retain(|t| t != tag.trim());
+        self
+    }
+
+    /// 是否有该标签
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag.trim())
+    }
+
+    /// 设置自定义字段，已存在同名字段时替换其值(位置不变)，否则添加到最后
+    pub fn set_field(&mut self, name: &str, value: &str, protected: bool) -> &mut Self {
+        let field = Field {
+            name: name.to_string(),
+            value: value.to_string(),
+            protected,
+        };
+        match self.fields.iter_mut().find(|f| f.name == name) {
+            Some(old) => {
+                old.value.zeroize();
+                *old = field;
+            }
+            None => self.fields.push(field),
+        }
+        self
+    }
+
+    /// 设置一次性密码：`otpauth://`URI，或base32编码的密钥(使用默认参数的TOTP)
+    pub fn set_otp(&mut self, otp: &str) -> PserResult<&mut Self> {
+        let otp = match otp.starts_with("otpauth://") {
+            true => Otp::from_uri(otp)?,
+            false => Otp::from_base32(otp)?,
+        };
+        self.otp = Some(otp);
+        Ok(self)
+    }
+
+    /// 删除自定义字段
+    pub fn remove_field(&mut self, name: &str) -> &mut Self {
+        if let Some(idx) = self.fields.iter().position(|f| f.name == name) {
+            self.fields.remove(idx).value.zeroize();
+        }
+        self
+    }
+
+    /// 根据文件名查找附件
+    pub fn attachment(&self, name: &str) -> Option<&Attachment> {
+        self.attachments.iter().find(|a| a.name == name)
+    }
+
+    /// 查看所有被修改过的旧密码，最近被修改的在前
     /// Vec<(被修改时间点，被修改的旧密码)>
-    pub fn history_passwds(&self) -> Vec<(String, String)> {
+    pub fn history_passwds(&self) -> Vec<(String, Zeroizing<String>)> {
         self.history
             .iter()
-            .map(|(k, v)| (k.secs_to_dt(east8()).to_string(), v.to_owned()))
-            .collect::<Vec<(String, String)>>()
+            .rev()
+            .map(|old| {
+                (
+                    old.changed.secs_to_dt(east8()).to_string(),
+                    Zeroizing::new(old.passwd.to_owned()),
+                )
+            })
+            .collect::<Vec<(String, Zeroizing<String>)>>()
     }
 
-    pub fn simple_display(&self, uuid: Option<&str>) -> String {
-        let mut str = String::new();
+    /// 单行显示，包含密码，返回的字符串在drop时清零
+    pub fn simple_display(&self, uuid: Option<&str>) -> Zeroizing<String> {
+        let mut str = Zeroizing::new(String::with_capacity(DISPLAY_CAPACITY));
 
         match self.url.is_empty() {
-            true => write!(&mut str, "desc:{}", self.desc).unwrap(),
-            false => write!(&mut str, "url:{}", self.url).unwrap(),
+            true => write!(str, "desc:{}", self.desc).unwrap(),
+            false => write!(str, "url:{}", self.url.join(",")).unwrap(),
         };
 
+        match &self.kind {
+            EntryKind::Login => {}
+            EntryKind::Card(card) => {
+                let _ = write!(str, "|{}:{}", self.kind, card.masked_number());
+            }
+            kind => {
+                let _ = write!(str, "|类型:{}", kind);
+            }
+        }
+
         if !self.username.is_empty() {
-            let _ = write!(&mut str, "|账户名:{}", self.username);
+            let _ = write!(str, "|账户名:{}", self.username);
         }
         if !self.passwd.is_empty() {
-            let _ = write!(&mut str, "|密码:{}", self.passwd);
+            let _ = write!(str, "|密码:{}", self.passwd);
         }
         if let Some(uuid) = uuid {
-            let _ = write!(&mut str, "|UUID:{}", uuid);
+            let _ = write!(str, "|UUID:{}", uuid);
         }
 
         str
     }
 
-    pub fn verical_display(&self, uuid: Option<&str>) -> String {
-        let mut str = String::new();
+    /// 多行显示，包含密码，先显示类型及该类型的字段，
+    /// reveal为false时隐藏受保护的自定义字段的值以及安全码、私钥等，返回的字符串在drop时清零
+    pub fn verical_display(&self, uuid: Option<&str>, reveal: bool) -> Zeroizing<String> {
+        let mut str = Zeroizing::new(String::with_capacity(DISPLAY_CAPACITY));
 
         if let Some(uuid) = uuid {
-            let _ = writeln!(&mut str, "-[ UUID: {} ]----------------------", uuid);
+            let _ = writeln!(str, "-[ UUID: {} ]----------------------", uuid);
+        }
+
+        if self.kind != EntryKind::Login {
+            let _ = writeln!(str, "类型(kind): {}", self.kind);
+            self.kind.write_details(&mut str, reveal);
         }
 
         if !self.username.is_empty() {
-            let _ = writeln!(&mut str, "账户名(account): {}", self.username);
+            let _ = writeln!(str, "账户名(account): {}", self.username);
         }
         if !self.desc.is_empty() {
-            let _ = writeln!(&mut str, "所属(desc): {}", self.desc);
+            let _ = writeln!(str, "所属(desc): {}", self.desc);
         }
         if !self.url.is_empty() {
-            let _ = writeln!(&mut str, "所属网站(url): {}", self.url);
+            let _ = writeln!(str, "所属网站(url): {}", self.url.join(", "));
+        }
+
+        if !self.folder.is_empty() {
+            let _ = writeln!(str, "文件夹(folder): {}", self.folder);
+        }
+        if !self.tags.is_empty() {
+            let _ = writeln!(str, "标签(tags): {}", self.tags.join(", "));
         }
 
         if !self.email.is_empty() {
-            let _ = writeln!(&mut str, "邮箱(email): {}", self.email);
+            let _ = writeln!(str, "邮箱(email): {}", self.email.join(", "));
         }
 
         if !self.phone.is_empty() {
-            let _ = writeln!(&mut str, "联系方式(phone): {}", self.phone);
+            let _ = writeln!(str, "联系方式(phone): {}", self.phone.join(", "));
         }
 
         if !self.passwd.is_empty() {
-            let _ = writeln!(&mut str, "密码(passwd): {}", self.passwd);
+            let _ = writeln!(str, "密码(passwd): {}", self.passwd);
+        }
+
+        if !self.history.is_empty() {
+            let _ = writeln!(str, "旧密码(history): {}个", self.history.len());
         }
 
         if !self.comment.is_empty() {
             let comment = self.comment.split([',', ';']).collect::<Vec<_>>();
-            let _ = writeln!(&mut str, "备注(comment): {}", comment.join("\n"));
-            // let _ = writeln!(&mut str, "备注(comment): {}", self.comment);
+            let _ = writeln!(str, "备注(comment): {}", comment.join("\n"));
+            // let _ = writeln!(str, "备注(comment): {}", self.comment);
+        }
+
+        if let Some(otp) = &self.otp {
+            let _ = writeln!(str, "一次性密码(otp): {}", otp);
+        }
+
+        for field in &self.fields {
+            let value = match field.protected && !reveal {
+                true => "******",
+                false => &field.value,
+            };
+            let _ = writeln!(str, "{}: {}", field.name, value);
+        }
+
+        for a in &self.attachments {
+            let _ = writeln!(str, "附件(attachment): {} ({}字节)", a.name, a.size);
+        }
+
+        if self.created != 0 {
+            let _ = writeln!(
+                str,
+                "创建时间(created): {}",
+                self.created.secs_to_dt(east8())
+            );
+        }
+        if self.modified != 0 {
+            let _ = writeln!(
+                str,
+                "修改时间(modified): {} (第{}版)",
+                self.modified.secs_to_dt(east8()),
+                self.revision
+            );
+        }
+        if self.last_used != 0 {
+            let _ = writeln!(
+                str,
+                "最近使用(last used): {}",
+                self.last_used.secs_to_dt(east8())
+            );
         }
 
         str
     }
 }
 
+/// 密码、历史密码、自定义字段的值在drop时清零
+impl Drop for Pser {
+    fn drop(&mut self) {
+        self.passwd.zeroize();
+        self.history.iter_mut().for_each(|h| h.passwd.zeroize());
+        self.fields.iter_mut().for_each(|f| f.value.zeroize());
+    }
+}
+
 /// key： Uuid(Simple)
 #[derive(Debug, Default, Serialize, Deserialize)]
 pub struct Psers(HashMap<String, Pser>);
@@ -182,21 +626,153 @@ impl Psers {
     }
 }
 
+/// 向列表中添加一项(去掉两端的空白字符)，为空或已存在时什么都不做
+fn add_item(list: &mut Vec<String>, item: &str) {
+    let item = item.trim();
+    if !item.is_empty() && !list.iter().any(|i| i == item) {
+        list.push(item.to_string());
+    }
+}
+
+/// 规范化文件夹路径：去掉多余的`/`和各级名称两端的空白字符，例如` work//aws/ `变为`work/aws`
+pub fn normalize_folder(folder: &str) -> String {
+    folder
+        .split('/')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
 /// 从URL中获取域名部分，
 ///
 /// 从`schema://_x.x_/y_`中获取`_x.x_`，即协议之后(协议可省略)，Path之前的内容(Path可省略)
 ///
 /// 例如，"http://id1.cloud.abc.com/a/b/c.html"，将得到`id1.cloud.abc.com`
+///
+/// 域名为空或包含空白字符时返回None
 fn domain_from_url(url: &str) -> Option<&str> {
     let re = Regex::new(r"^(?:.*://)?(?<domain>.*?)(?:/|$)").unwrap();
-    let capt = re.captures(url).unwrap();
-    capt.name("domain").map(|x| x.as_str())
+    re.captures(url)?
+        .name("domain")
+        .map(|x| x.as_str())
+        .filter(|d| !d.is_empty() && !d.contains(char::is_whitespace))
 }
 
 #[cfg(test)]
 mod t {
+    use super::*;
     use regex::Regex;
 
+    #[test]
+    fn set_url() {
+        let mut pser = Pser::new();
+        pser.set_url("https://id1.cloud.abc.com/a/b/c.html")
+            .unwrap();
+        assert_eq!(pser.url, ["id1.cloud.abc.com"]);
+        assert!(matches!(
+            pser.set_url("https:///a"),
+            Err(Error::InvalidUrl(_))
+        ));
+        assert!(matches!(
+            pser.set_url("abc .com"),
+            Err(Error::InvalidUrl(_))
+        ));
+        assert_eq!(pser.url, ["id1.cloud.abc.com"]);
+
+        pser.add_url("http://abc.com/login")
+            .unwrap()
+            .add_url("abc.com")
+            .unwrap();
+        assert_eq!(pser.url, ["id1.cloud.abc.com", "abc.com"]);
+        assert!(pser.is_me("abc.com") && pser.is_me("cloud"));
+        pser.remove_url("https://id1.cloud.abc.com/");
+        assert_eq!(pser.title(), "abc.com");
+    }
+
+    #[test]
+    fn emails_and_phones() {
+        let mut pser = Pser::new();
+        pser.set_email("a@x.com, b@x.com,,a@x.com")
+            .add_email("c@x.com")
+            .remove_email("b@x.com");
+        assert_eq!(pser.email, ["a@x.com", "c@x.com"]);
+
+        pser.add_phone("123").add_phone(" 456 ").set_phone("789");
+        assert_eq!(pser.phone, ["789"]);
+    }
+
+    #[test]
+    fn fields() {
+        let mut pser = Pser::new();
+        pser.set_field("card", "6222", true)
+            .set_field("question", "pet", false)
+            .set_field("card", "6223", true);
+        assert_eq!(pser.fields.len(), 2);
+        assert_eq!(pser.fields[0].value, "6223");
+
+        assert!(pser.is_me("quest") && pser.is_me("pet") && !pser.is_me("6223"));
+        assert!(!pser.verical_display(None, false).contains("6223"));
+        assert!(pser.verical_display(None, true).contains("6223"));
+
+        pser.remove_field("card");
+        assert_eq!(pser.fields[0].name, "question");
+    }
+
+    #[test]
+    fn folders_and_tags() {
+        let mut pser = Pser::new();
+        pser.set_folder(" work//aws/ prod/");
+        assert_eq!(pser.folder, "work/aws/prod");
+        assert!(pser.in_folder("work") && pser.in_folder("work/aws/") && pser.in_folder(""));
+        assert!(!pser.in_folder("wor") && !pser.in_folder("work/aws/prod/x"));
+
+        pser.move_folder("work/aws", "cloud");
+        assert_eq!(pser.folder, "cloud/aws/prod");
+        pser.move_folder("cloud/aws/prod", "");
+        assert_eq!(pser.folder, "prod");
+        pser.move_folder("other", "x");
+        assert_eq!(pser.folder, "prod");
+
+        pser.add_tag("b").add_tag(" a ").add_tag("b").add_tag("");
+        assert_eq!(pser.tags, ["a", "b"]);
+        pser.remove_tag("a");
+        assert!(!pser.has_tag("a") && pser.has_tag("b"));
+    }
+
+    #[test]
+    fn history() {
+        let mut pser = Pser::new();
+        pser.set_passwd("a")
+            .set_passwd("b")
+            .set_passwd("b")
+            .set_passwd("c");
+        let history: Vec<_> = pser.history_passwds().into_iter().map(|h| h.1).collect();
+        assert_eq!(
+            history,
+            [Zeroizing::new("b".into()), Zeroizing::new("a".into())]
+        );
+
+        pser.restore_passwd(2).unwrap();
+        assert_eq!(pser.passwd, "a");
+        assert_eq!(pser.history_passwds()[0].1.as_str(), "c");
+        assert!(pser.restore_passwd(3).is_err());
+
+        let now = pser.history[0].changed;
+        pser.history[0].changed -= 10 * 86400;
+        let policy = HistoryPolicy {
+            keep: None,
+            max_days: Some(7),
+        };
+        assert_eq!(pser.prune_history(&policy, now), 1);
+        pser.set_history_policy(Some(HistoryPolicy {
+            keep: Some(0),
+            max_days: None,
+        }));
+        assert_eq!(pser.prune_history(&policy, now), 1);
+        assert!(pser.history.is_empty());
+    }
+
     #[test]
     fn tt() {
         let re = Regex::new(r"^(?:.*://)?(?<domain>.*?)/").unwrap();
@@ -0,0 +1,232 @@
+//! 防止在线爆破：解锁失败后逐渐延长等待时间
+//!
+//! 连续失败的次数超过锁定策略允许的次数后，每次尝试解锁前都需要等待，等待时间随失败次数成倍增长，
+//! 直到策略规定的最长等待时间。失败记录和锁定策略保存在验证头中，不会因为重新运行程序而重置。
+//!
+//! 解锁失败时无法得到数据密钥，因此只在成功解锁时设置检查点：使用数据密钥对锁定策略、失败记录和
+//! 检查点时的失败总次数计算HMAC-SHA256。失败总次数只增不减，等待时间由它和检查点时的值之差决定，
+//! 因此只清空最近的失败记录不能跳过等待；下一次成功解锁时先校验上一个检查点，锁定策略、已确认的失败记录被修改过，
+//! 或最近的失败记录和失败总次数不一致，都将被检测出来。
+//! 注意：将失败总次数等一起改回上一个检查点时的值，和用之前备份的密码库文件整体覆盖一样无法被检测，
+//! 离线爆破也不受此限制(由 argon2 负责)
+
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+/// 最多保留的失败记录数
+const MAX_HISTORY: usize = 20;
+
+/// 锁定策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LockoutPolicy {
+    /// 允许连续失败的次数，超过后每次尝试前都需要等待
+    pub free_attempts: u32,
+    /// 第一次需要等待的秒数，之后每失败一次等待时间翻倍
+    pub base_delay: u64,
+    /// 最长等待秒数
+    pub max_delay: u64,
+}
+
+impl Default for LockoutPolicy {
+    fn default() -> Self {
+        Self {
+            free_attempts: 3,
+            base_delay: 2,
+            max_delay: 3600,
+        }
+    }
+}
+
+impl LockoutPolicy {
+    /// 连续失败failures次之后，下一次尝试前需要等待的秒数
+    pub fn delay(&self, failures: u32) -> u64 {
+        if failures <= self.free_attempts {
+            return 0;
+        }
+        let exp = (failures - self.free_attempts - 1).min(63);
+        self.base_delay
+            .saturating_mul(1u64 << exp)
+            .min(self.max_delay)
+    }
+}
+
+/// 成功解锁时的报告
+#[derive(Debug, Clone, Default)]
+pub struct UnlockReport {
+    /// 上次成功解锁之后失败的时间点(秒级Epoch)，最多MAX_HISTORY个
+    pub failed: Vec<i64>,
+    /// 上次成功解锁之后连续失败的次数
+    pub failures: u32,
+    /// 锁定策略或失败记录是否被篡改
+    pub tampered: bool,
+}
+
+/// 失败记录和锁定策略
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Throttle {
+    policy: LockoutPolicy,
+    /// 解锁失败的总次数，只增不减
+    failed_total: u64,
+    /// 上一个检查点时的failed_total，受检查点保护，和failed_total之差为上次成功解锁之后连续失败的次数
+    confirmed: u64,
+    /// 最近一次解锁失败的时间点(秒级Epoch)
+    last_failed: i64,
+    /// 上次成功解锁之后失败的时间点(秒级Epoch)，最多MAX_HISTORY个
+    pending: Vec<i64>,
+    /// 已确认的失败时间点(秒级Epoch)，最多MAX_HISTORY个，受检查点保护
+    history: Vec<i64>,
+    /// 检查点：使用数据密钥对policy、history和confirmed计算的HMAC-SHA256，从未设置过检查点时为空
+    mac: Vec<u8>,
+}
+
+impl Throttle {
+    pub fn policy(&self) -> &LockoutPolicy {
+        &self.policy
+    }
+
+    /// 最近失败的时间点(秒级Epoch)，从早到晚
+    pub fn history(&self) -> impl Iterator<Item = &i64> {
+        self.history.iter().chain(self.pending.iter())
+    }
+
+    /// 上次成功解锁之后连续失败的次数
+    pub fn failures(&self) -> u32 {
+        self.failed_total
+            .saturating_sub(self.confirmed)
+            .min(u32::MAX as u64) as u32
+    }
+
+    /// 在now时还需等待多少秒才能尝试解锁，0表示无需等待
+    pub fn retry_after(&self, now: i64) -> u64 {
+        let failures = self.failures();
+        if failures == 0 {
+            return 0;
+        }
+        let last = self
+            .pending
+            .last()
+            .map_or(self.last_failed, |&t| t.max(self.last_failed));
+        let delay = self.policy.delay(failures);
+        let wait = (last + delay as i64 - now).max(0) as u64;
+        // 系统时间被调回时，最多等待max_delay
+        wait.min(self.policy.max_delay)
+    }
+
+    /// 记录在now时的一次解锁失败
+    pub fn record_failure(&mut self, now: i64) {
+        self.failed_total = self.failed_total.saturating_add(1);
+        self.last_failed = now;
+        push_capped(&mut self.pending, now);
+    }
+
+    /// 成功解锁后设置检查点：校验上一个检查点，确认之前的失败记录，然后重新计算HMAC
+    ///
+    /// 检测到篡改时，锁定策略恢复为默认值
+    pub fn checkpoint(&mut self, key: &[u8]) -> UnlockReport {
+        // 最近的失败记录最多保留MAX_HISTORY个，数量应和失败总次数的增量一致
+        let failures = self.failures();
+        let tampered = (!self.mac.is_empty() && self.mac != self.mac(key))
+            || self.failed_total < self.confirmed
+            || self.pending.len() != (failures as usize).min(MAX_HISTORY);
+        if tampered {
+            self.policy = LockoutPolicy::default();
+        }
+        let report = UnlockReport {
+            failed: self.pending.clone(),
+            failures,
+            tampered,
+        };
+
+        for t in std::mem::take(&mut self.pending) {
+            push_capped(&mut self.history, t);
+        }
+        self.confirmed = self.failed_total;
+        self.mac = self.mac(key);
+        report
+    }
+
+    /// 修改锁定策略，需要数据密钥重新计算HMAC
+    pub fn set_policy(&mut self, policy: LockoutPolicy, key: &[u8]) {
+        self.policy = policy;
+        self.mac = self.mac(key);
+    }
+
+    fn mac(&self, key: &[u8]) -> Vec<u8> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC can take key of any size");
+        mac.update(b"pser throttle");
+        let checked = (&self.policy, &self.history, self.confirmed);
+        mac.update(&bincode::serialize(&checked).unwrap());
+        mac.finalize().into_bytes().to_vec()
+    }
+}
+
+fn push_capped(v: &mut Vec<i64>, t: i64) {
+    v.push(t);
+    if v.len() > MAX_HISTORY {
+        v.remove(0);
+    }
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn escalating_delay() {
+        let policy = LockoutPolicy::default();
+        assert_eq!(policy.delay(3), 0);
+        assert_eq!(policy.delay(4), 2);
+        assert_eq!(policy.delay(6), 8);
+        assert_eq!(policy.delay(100), 3600);
+
+        let mut throttle = Throttle::default();
+        for _ in 0..5 {
+            throttle.record_failure(1000);
+        }
+        assert_eq!(throttle.retry_after(1001), 3);
+        assert_eq!(throttle.retry_after(1010), 0);
+    }
+
+    #[test]
+    fn tampered() {
+        let key = b"data key";
+        let mut throttle = Throttle::default();
+        throttle.record_failure(1000);
+        throttle.record_failure(1001);
+        let report = throttle.checkpoint(key);
+        assert_eq!(report.failed, vec![1000, 1001]);
+        assert!(!report.tampered);
+
+        // 放宽锁定策略、删除失败记录
+        throttle.policy.free_attempts = 1000;
+        throttle.history.clear();
+        let report = throttle.checkpoint(key);
+        assert!(report.tampered);
+        assert_eq!(throttle.policy, LockoutPolicy::default());
+        assert!(!throttle.checkpoint(key).tampered);
+    }
+
+    #[test]
+    fn counter_tampered() {
+        let key = b"data key";
+        let mut throttle = Throttle::default();
+        throttle.checkpoint(key);
+        for _ in 0..5 {
+            throttle.record_failure(1000);
+        }
+
+        // 只清空最近的失败记录不能跳过等待，且会被检测出来
+        throttle.pending.clear();
+        assert_eq!(throttle.retry_after(1001), 3);
+        let report = throttle.checkpoint(key);
+        assert!(report.tampered);
+        assert_eq!(report.failures, 5);
+
+        // 将失败总次数改小
+        throttle.record_failure(2000);
+        throttle.failed_total = 0;
+        assert!(throttle.checkpoint(key).tampered);
+        assert!(!throttle.checkpoint(key).tampered);
+    }
+}
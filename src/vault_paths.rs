@@ -0,0 +1,222 @@
+//! 密码库文件的位置
+//!
+//! 密码库由多个互相同步的副本组成，第一个为主密码库，其余为从密码库。
+//!
+//! 确定各副本位置的优先级(从高到低)：
+//! - 命令行的`--vault`选项(只指定主密码库文件)
+//! - 环境变量`PSER_DB_DIR`(主密码库所在目录)和`PSER_REPLICA`(从密码库文件，
+//!   多个文件使用路径分隔符分隔，unix下为`:`，windows下为`;`)
+//! - 配置文件`<config_dir>/pser/config.json`中的`db_dir`和`replicas`字段
+//! - 默认位置：主密码库为`$HOME/.local/share/.sper/sper.db`，没有从密码库
+//!
+//! 可执行程序所在的目录通常不可写(例如安装在`/usr/bin`下)，因此不再作为从密码库的默认位置
+
+use crate::error::{Error, PserResult};
+use anyhow::anyhow;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+/// 指定主密码库所在目录的环境变量
+pub const ENV_DB_DIR: &str = "PSER_DB_DIR";
+/// 指定从密码库文件的环境变量
+pub const ENV_REPLICA: &str = "PSER_REPLICA";
+
+/// 主密码库的文件名
+const DB_FILE_NAME: &str = "sper.db";
+
+/// 配置文件的内容(json格式)，各字段都可以省略
+///
+/// ```json
+/// {
+///   "db_dir": "/home/juji/.local/share/.sper",
+///   "replicas": ["/mnt/nas/pser.db", "/media/usb/pser.db"]
+/// }
+/// ```
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct VaultConfig {
+    /// 主密码库所在目录
+    pub db_dir: Option<PathBuf>,
+    /// 从密码库文件
+    #[serde(default)]
+    pub replicas: Vec<PathBuf>,
+}
+
+impl VaultConfig {
+    /// 配置文件路径`<config_dir>/pser/config.json`
+    pub fn file() -> Option<PathBuf> {
+        dirs::config_dir().map(|d| d.join("pser").join("config.json"))
+    }
+
+    /// 读取配置文件，配置文件不存在时返回默认配置
+    pub fn load() -> PserResult<Self> {
+        match Self::file() {
+            Some(f) if f.exists() => {
+                let s = std::fs::read_to_string(&f)?;
+                serde_json::from_str(&s)
+                    .map_err(|e| Error::Other(anyhow!("配置文件{}格式错误: {}", f.display(), e)))
+            }
+            _ => Ok(Self::default()),
+        }
+    }
+}
+
+/// 密码库各副本文件的位置
+#[derive(Debug, Clone)]
+pub struct VaultPaths {
+    /// 第一个为主密码库文件，其余为从密码库文件，不会包含重复的路径
+    replicas: Vec<PathBuf>,
+}
+
+impl VaultPaths {
+    /// 给定主密码库文件和从密码库文件，重复的路径将被忽略
+    pub fn new<P: Into<PathBuf>>(primary: P, replicas: Vec<PathBuf>) -> Self {
+        let mut all: Vec<PathBuf> = vec![primary.into()];
+        for r in replicas {
+            if !all.contains(&r) {
+                all.push(r);
+            }
+        }
+        Self { replicas: all }
+    }
+
+    /// 按优先级确定各副本文件的位置，`vault`为命令行中指定的主密码库文件
+    pub fn resolve(vault: Option<&Path>) -> PserResult<Self> {
+        Self::from_config(vault, VaultConfig::load()?)
+    }
+
+    /// 和`resolve`相同，但使用给定的配置代替配置文件
+    fn from_config(vault: Option<&Path>, config: VaultConfig) -> PserResult<Self> {
+        let primary = match vault {
+            Some(v) => v.to_path_buf(),
+            None => match std::env::var_os(ENV_DB_DIR) {
+                Some(dir) => PathBuf::from(dir).join(DB_FILE_NAME),
+                None => match config.db_dir {
+                    Some(dir) => dir.join(DB_FILE_NAME),
+                    None => default_primary()?,
+                },
+            },
+        };
+
+        let replicas = match std::env::var_os(ENV_REPLICA) {
+            Some(f) => std::env::split_paths(&f)
+                .filter(|p| !p.as_os_str().is_empty())
+                .collect(),
+            None => config.replicas,
+        };
+
+        Ok(Self::new(primary, replicas))
+    }
+
+    /// 主密码库文件
+    pub fn primary(&self) -> &Path {
+        &self.replicas[0]
+    }
+
+    /// 所有副本文件，第一个为主密码库文件
+    pub fn replicas(&self) -> &[PathBuf] {
+        &self.replicas
+    }
+
+    /// 是否至少有一个副本文件存在
+    pub fn exists(&self) -> bool {
+        self.replicas.iter().any(|p| p.exists())
+    }
+}
+
+/// 默认的主密码库文件：`$HOME/.local/share/.sper/sper.db`
+fn default_primary() -> PserResult<PathBuf> {
+    let home = dirs::home_dir().ok_or_else(|| anyhow!("无法确定家目录"))?;
+    Ok(home
+        .join(".local")
+        .join("share")
+        .join(".sper")
+        .join(DB_FILE_NAME))
+}
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    #[test]
+    fn dedup() {
+        let paths = VaultPaths::new(
+            "/a/sper.db",
+            vec![
+                "/b/pser.db".into(),
+                "/a/sper.db".into(),
+                "/b/pser.db".into(),
+            ],
+        );
+        assert_eq!(paths.primary(), Path::new("/a/sper.db"));
+        assert_eq!(
+            paths.replicas(),
+            [PathBuf::from("/a/sper.db"), PathBuf::from("/b/pser.db")]
+        );
+    }
+
+    #[test]
+    fn config() {
+        let config: VaultConfig =
+            serde_json::from_str(r#"{"db_dir": "/c", "replicas": ["/nas/pser.db"]}"#).unwrap();
+        assert_eq!(config.db_dir, Some(PathBuf::from("/c")));
+        assert_eq!(config.replicas, [PathBuf::from("/nas/pser.db")]);
+
+        // 各字段都可以省略
+        let config: VaultConfig = serde_json::from_str("{}").unwrap();
+        assert!(config.db_dir.is_none() && config.replicas.is_empty());
+        assert!(serde_json::from_str::<VaultConfig>(r#"{"replicas": "/nas"}"#).is_err());
+    }
+
+    /// 环境变量是整个进程共享的，所有读写环境变量的断言都放在这一个测试中
+    #[test]
+    fn resolve_precedence() {
+        let config = || VaultConfig {
+            db_dir: Some("/c".into()),
+            replicas: vec!["/c/nas.db".into()],
+        };
+        let resolve = |vault: Option<&str>, config: VaultConfig| {
+            VaultPaths::from_config(vault.map(Path::new), config)
+                .unwrap()
+                .replicas()
+                .to_vec()
+        };
+        let paths = |p: &[&str]| p.iter().map(PathBuf::from).collect::<Vec<_>>();
+        std::env::remove_var(ENV_DB_DIR);
+        std::env::remove_var(ENV_REPLICA);
+
+        // 没有配置从密码库时只有主密码库
+        let default = resolve(None, VaultConfig::default());
+        assert_eq!(default, [default_primary().unwrap()]);
+        assert_eq!(
+            resolve(Some("/v.db"), VaultConfig::default()),
+            paths(&["/v.db"])
+        );
+
+        // 配置文件
+        assert_eq!(resolve(None, config()), paths(&["/c/sper.db", "/c/nas.db"]));
+        assert_eq!(
+            resolve(Some("/v.db"), config()),
+            paths(&["/v.db", "/c/nas.db"])
+        );
+
+        // 环境变量优先于配置文件，空路径被忽略
+        std::env::set_var(ENV_DB_DIR, "/e");
+        let replicas = std::env::join_paths(["/e/usb.db", "", "/e/nas.db"]).unwrap();
+        std::env::set_var(ENV_REPLICA, replicas);
+        assert_eq!(
+            resolve(None, config()),
+            paths(&["/e/sper.db", "/e/usb.db", "/e/nas.db"])
+        );
+        // --vault优先于环境变量
+        assert_eq!(
+            resolve(Some("/v.db"), config()),
+            paths(&["/v.db", "/e/usb.db", "/e/nas.db"])
+        );
+        // 环境变量为空时不使用配置文件中的从密码库
+        std::env::set_var(ENV_REPLICA, "");
+        assert_eq!(resolve(None, config()), paths(&["/e/sper.db"]));
+
+        std::env::remove_var(ENV_DB_DIR);
+        std::env::remove_var(ENV_REPLICA);
+    }
+}
@@ -1,58 +1,363 @@
 //! 加密数据库的验证头
+//!
+//! 密码信息使用随机生成的数据密钥加密，数据密钥再使用主密码加密(包装)后保存在验证头的密钥槽中，
+//! 因此修改主密码时只需重新包装数据密钥，无需重新加密所有密码信息。
+//!
+//! 同一个数据密钥可以被不同的凭据分别包装后保存在多个密钥槽中(类似LUKS)：主密码、额外的口令、
+//! 密钥文件、打印出来的恢复码，解开其中任意一个密钥槽都能打开密码库。
+//!
+//! 主密码还可以配合密钥文件使用(第二因素)：密钥文件的sha256作为 argon2 的secret输入，
+//! 只有同时提供主密码和密钥文件才能解开主密码密钥槽。
+//!
+//! 验证凭据是否正确时，使用凭据经过 argon2 生成的密钥对密钥槽进行认证解密(AEAD)，
+//! 解密成功即说明凭据正确，验证头中不保存任何可被离线快速爆破的密码hash。
+//!
+//! 验证头中还保存了解锁失败的记录和锁定策略，见[`Throttle`]
 
-use crate::error::PserResult;
-use chrono_ext::{now8, DateTimeExt};
+use crate::{
+    error::{Error, PserResult},
+    throttle::{LockoutPolicy, Throttle, UnlockReport},
+};
+use anyhow::anyhow;
+use chrono_ext::now8;
+use crypt::{EncryptData, KdfParams};
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha512};
-use std::cell::RefCell;
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt::Write;
+use zeroize::{Zeroize, Zeroizing};
+
+/// 验证头序列化数据的开头，用于和旧版本的验证头区分(旧版本以秒级Epoch开头，不会和它相同)
+pub const MAGIC: [u8; 8] = *b"PSER-VH1";
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct VerifyHeader {
-    /// 当前分钟的起始Epoch(秒级的Epoch)，即第0秒，
-    /// 可通过该字段和try_count来设置每分钟内最大允许解密次数，避免被爆破
-    timestamp: RefCell<i64>,
-    /// 每次尝试解密，都更新该字段，
-    /// 在一分钟内已尝试解密的次数
-    try_count: RefCell<u16>,
-    /// 主密码对应的Sha512值，用于验证密码的正确性
-    verify_data: Vec<u8>,
+    magic: [u8; 8],
+    /// 解锁失败的记录和锁定策略
+    throttle: Throttle,
+    /// 旧版本中保存的主密码Sha512值，只用于验证没有密钥槽的旧版本密码库，
+    /// 有密钥槽的验证头中该字段为空(打开旧版本的密码库时会自动清空)
+    legacy_check: Vec<u8>,
+    /// 密钥槽，每个密钥槽都保存了被加密的同一个数据密钥
+    slots: Vec<KeySlot>,
 }
 
-impl VerifyHeader {
-    const MAX_TRY: u16 = 200u16;
+/// 密钥槽的类型，即使用什么解开该密钥槽
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SlotKind {
+    /// 主密码，和PasswordWithKeyfile只能有一个
+    Password,
+    /// 主密码之外的口令，例如家庭成员各自的密码
+    Passphrase,
+    /// 密钥文件，使用文件内容的sha256(十六进制)作为密码
+    Keyfile,
+    /// 恢复码，忽略大小写、空白和`-`
+    Recovery,
+    /// 主密码+密钥文件(第二因素)，和Password只能有一个
+    PasswordWithKeyfile,
+}
 
-    pub fn new(main_passwd: &str) -> Self {
+impl SlotKind {
+    /// 是否为主密码密钥槽
+    pub fn is_main(&self) -> bool {
+        matches!(self, SlotKind::Password | SlotKind::PasswordWithKeyfile)
+    }
+}
+
+impl std::fmt::Display for SlotKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SlotKind::Password => write!(f, "主密码"),
+            SlotKind::Passphrase => write!(f, "口令"),
+            SlotKind::Keyfile => write!(f, "密钥文件"),
+            SlotKind::Recovery => write!(f, "恢复码"),
+            SlotKind::PasswordWithKeyfile => write!(f, "主密码+密钥文件"),
+        }
+    }
+}
+
+/// 解开密钥槽所用的凭据，drop时清零
+#[derive(Clone, Default)]
+pub struct Credential {
+    /// 输入的密码，可以是主密码、口令或恢复码
+    pub passwd: Option<String>,
+    /// 密钥文件的内容
+    pub keyfile: Option<Vec<u8>>,
+}
+
+impl Credential {
+    pub fn passwd(passwd: &str) -> Self {
         Self {
-            timestamp: RefCell::new(cur_min_timestamp()),
-            try_count: RefCell::new(0),
-            verify_data: passwd_sha512(main_passwd),
+            passwd: Some(passwd.to_string()),
+            keyfile: None,
         }
     }
 
-    /// 验证给定明文密码是否正确(是否能解开加密头)，还验证是否超出单位时间内的验证限制次数
-    pub fn verify_header(&self, main_passwd: &str) -> bool {
-        // 返回true表示需要等待，返回false表示可以继续验证
-        let try_wait = self.update_header_status();
-        !try_wait && self.verify_data == passwd_sha512(main_passwd)
+    pub fn keyfile(keyfile: Vec<u8>) -> Self {
+        Self {
+            passwd: None,
+            keyfile: Some(keyfile),
+        }
+    }
+
+    /// 同时使用密钥文件
+    pub fn with_keyfile(mut self, keyfile: Vec<u8>) -> Self {
+        self.keyfile = Some(keyfile);
+        self
     }
 
-    /// 更新验证头的状态信息，同时返回是否超过单位时间内验证头的验证限制次数，
-    /// 超出限制次数返回true，没有超出限制次数返回false
-    fn update_header_status(&self) -> bool {
-        let cur_m = cur_min_timestamp();
-        let mut try_cnt = self.try_count.borrow_mut();
-        let t = *self.timestamp.borrow();
-        match t == cur_m {
-            true => {
-                *try_cnt += 1;
+    /// 该凭据用于解开指定类型的密钥槽时所使用的(密码, argon2 secret)，凭据不适用于该类型时返回None
+    fn secret_for(&self, kind: SlotKind) -> Option<(Zeroizing<String>, Zeroizing<Vec<u8>>)> {
+        let passwd = match kind {
+            SlotKind::Password | SlotKind::Passphrase | SlotKind::PasswordWithKeyfile => {
+                Zeroizing::new(self.passwd.clone()?)
             }
-            false => {
-                *self.timestamp.borrow_mut() = cur_m;
-                *try_cnt = 0;
+            SlotKind::Recovery => normalize_recovery(self.passwd.as_deref()?),
+            SlotKind::Keyfile => keyfile_secret(self.keyfile.as_deref()?),
+        };
+        let secret = match kind {
+            SlotKind::PasswordWithKeyfile => Sha256::digest(self.keyfile.as_deref()?).to_vec(),
+            _ => vec![],
+        };
+        Some((passwd, Zeroizing::new(secret)))
+    }
+}
+
+impl Drop for Credential {
+    fn drop(&mut self) {
+        self.passwd.zeroize();
+        self.keyfile.zeroize();
+    }
+}
+
+impl std::fmt::Debug for Credential {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Credential")
+            .field("passwd", &self.passwd.as_ref().map(|_| "***"))
+            .field("keyfile", &self.keyfile.as_ref().map(|_| "***"))
+            .finish()
+    }
+}
+
+/// 解开验证头的结果
+#[derive(Debug)]
+pub enum Unlocked {
+    /// 解开了编号为slot的密钥槽，得到数据密钥
+    Key { slot: u32, key: Zeroizing<Vec<u8>> },
+    /// 旧版本的验证头没有密钥槽，只验证了主密码
+    Legacy,
+}
+
+/// 密钥槽：使用某个密码加密后的数据密钥
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeySlot {
+    /// 密钥槽编号，在同一个验证头中唯一
+    pub id: u32,
+    pub kind: SlotKind,
+    /// 密钥槽的描述信息
+    pub desc: String,
+    /// 创建时间(秒级Epoch)
+    pub created: i64,
+    /// 使用`EncryptData::encrypt_with_params`加密后的数据密钥
+    wrapped_key: Vec<u8>,
+}
+
+impl KeySlot {
+    /// 包装数据密钥时使用的 argon2 参数
+    pub fn kdf_params(&self) -> Option<KdfParams> {
+        EncryptData::kdf_params(&self.wrapped_key).ok().flatten()
+    }
+
+    /// 使用凭据解开该密钥槽，凭据错误或不适用于该密钥槽时返回None
+    fn unwrap_key(&self, cred: &Credential) -> Option<Unlocked> {
+        let (passwd, secret) = cred.secret_for(self.kind)?;
+        EncryptData::decrypt_with_secret::<Vec<u8>>(&self.wrapped_key, &passwd, &secret)
+            .ok()
+            .map(|key| Unlocked::Key {
+                slot: self.id,
+                key: Zeroizing::new(key),
+            })
+    }
+}
+
+/// 解锁被拒绝的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Denied {
+    /// 凭据错误
+    WrongCredential,
+    /// 连续失败的次数过多，需要等待retry_after秒后才能再次尝试
+    Throttled { retry_after: u64 },
+}
+
+impl VerifyHeader {
+    /// 创建验证头，同时创建一个使用主密码包装数据密钥的密钥槽，
+    /// 凭据中包含密钥文件时，该密钥槽需要同时使用主密码和密钥文件解开
+    pub fn new(main_cred: &Credential, data_key: &[u8]) -> PserResult<Self> {
+        let mut header = Self {
+            magic: MAGIC,
+            throttle: Throttle::default(),
+            legacy_check: vec![],
+            slots: vec![],
+        };
+        header.change_passwd(main_cred, data_key)?;
+        Ok(header)
+    }
+
+    /// 使用凭据解开验证头：先检查是否需要等待(连续失败的次数过多)，再尝试解开密钥槽，失败时记录本次失败
+    ///
+    /// 成功解锁并得到数据密钥后，应调用`checkpoint`
+    pub fn unlock(&mut self, cred: &Credential) -> Result<Unlocked, Denied> {
+        let now = now8().timestamp();
+        let retry_after = self.throttle.retry_after(now);
+        if retry_after > 0 {
+            return Err(Denied::Throttled { retry_after });
+        }
+
+        self.unlock_slots(cred).ok_or_else(|| {
+            self.throttle.record_failure(now);
+            Denied::WrongCredential
+        })
+    }
+
+    /// 使用凭据解开验证头，凭据错误时返回None，不会检查或记录失败次数(用于已成功解锁之后读取其它副本)
+    ///
+    /// 先尝试解开主密码密钥槽，再依次尝试解开其它适用于该凭据的密钥槽，
+    /// 能够认证解密某个密钥槽即说明凭据正确
+    pub fn unlock_slots(&self, cred: &Credential) -> Option<Unlocked> {
+        // 旧版本的验证头没有密钥槽，只能通过Sha512验证主密码
+        if self.slots.is_empty() {
+            let passwd = cred.passwd.as_deref()?;
+            return (!self.legacy_check.is_empty() && self.legacy_check == passwd_sha512(passwd))
+                .then_some(Unlocked::Legacy);
+        }
+
+        let main = self.slots.iter().filter(|s| s.kind.is_main());
+        let others = self.slots.iter().filter(|s| !s.kind.is_main());
+        main.chain(others).find_map(|slot| slot.unwrap_key(cred))
+    }
+
+    /// 成功解锁后设置失败记录的检查点(见[`Throttle::checkpoint`])，
+    /// 同时清除旧版本保存的主密码Sha512值(只在已有密钥槽时清除)
+    pub fn checkpoint(&mut self, data_key: &[u8]) -> UnlockReport {
+        if !self.slots.is_empty() {
+            self.legacy_check.clear();
+        }
+        self.throttle.checkpoint(data_key)
+    }
+
+    /// 解锁失败的记录和锁定策略
+    pub fn throttle(&self) -> &Throttle {
+        &self.throttle
+    }
+
+    /// 修改锁定策略
+    pub fn set_lockout_policy(&mut self, policy: LockoutPolicy, data_key: &[u8]) {
+        self.throttle.set_policy(policy, data_key);
+    }
+
+    /// 主密码密钥槽，旧版本的验证头没有密钥槽
+    pub fn main_slot(&self) -> Option<&KeySlot> {
+        self.slots.iter().find(|s| s.kind.is_main())
+    }
+
+    /// 所有密钥槽
+    pub fn slots(&self) -> &[KeySlot] {
+        &self.slots
+    }
+
+    /// 新密钥槽使用的 argon2 参数：和主密码密钥槽相同，主密码密钥槽使用的是旧版本的参数时，使用默认参数
+    pub fn kdf_params(&self) -> KdfParams {
+        self.main_slot()
+            .and_then(|s| s.kdf_params())
+            .filter(|p| *p != KdfParams::legacy())
+            .unwrap_or_default()
+    }
+
+    /// 使用新的 argon2 参数重新包装编号为id的密钥槽，cred必须是该密钥槽对应的凭据
+    pub fn rekey(
+        &mut self,
+        id: u32,
+        cred: &Credential,
+        data_key: &[u8],
+        params: &KdfParams,
+    ) -> PserResult<()> {
+        let slot = self
+            .slots
+            .iter_mut()
+            .find(|s| s.id == id)
+            .ok_or_else(|| anyhow!("密钥槽{}不存在", id))?;
+        slot.wrapped_key = wrap_key(slot.kind, cred, data_key, params)?;
+        Ok(())
+    }
+
+    /// 使用凭据包装数据密钥，添加一个新的密钥槽，返回新密钥槽的编号
+    ///
+    /// 主密码密钥槽只能有一个，需通过`change_passwd`修改
+    pub fn add_slot(
+        &mut self,
+        kind: SlotKind,
+        desc: &str,
+        cred: &Credential,
+        data_key: &[u8],
+    ) -> PserResult<u32> {
+        if kind.is_main() {
+            return Err(Error::Other(anyhow!(
+                "主密码密钥槽只能有一个，请修改主密码"
+            )));
+        }
+        let params = self.kdf_params();
+        self.push_slot(kind, desc, cred, data_key, &params)
+    }
+
+    /// 删除编号为id的密钥槽，不能删除主密码密钥槽
+    pub fn remove_slot(&mut self, id: u32) -> PserResult<()> {
+        match self.slots.iter().find(|s| s.id == id) {
+            None => Err(Error::Other(anyhow!("密钥槽{}不存在", id))),
+            Some(slot) if slot.kind.is_main() => Err(Error::Other(anyhow!("不能删除主密码密钥槽"))),
+            Some(_) => {
+                self.slots.retain(|s| s.id != id);
+                Ok(())
             }
         }
+    }
+
+    /// 修改主密码：只需使用新的凭据重新包装数据密钥，替换原有的主密码密钥槽
+    ///
+    /// 凭据中包含密钥文件时，之后需要同时使用主密码和密钥文件才能解开主密码密钥槽
+    pub fn change_passwd(&mut self, main_cred: &Credential, data_key: &[u8]) -> PserResult<()> {
+        let kind = match main_cred.keyfile {
+            Some(_) => SlotKind::PasswordWithKeyfile,
+            None => SlotKind::Password,
+        };
+
+        let old = self.slots.iter().position(|s| s.kind.is_main());
+        let params = self.kdf_params();
+        self.push_slot(kind, "main password", main_cred, data_key, &params)?;
+        if let Some(idx) = old {
+            self.slots.remove(idx);
+        }
+        self.legacy_check.clear();
+        Ok(())
+    }
 
-        try_cnt.ge(&Self::MAX_TRY)
+    /// 使用凭据包装数据密钥，追加一个密钥槽
+    fn push_slot(
+        &mut self,
+        kind: SlotKind,
+        desc: &str,
+        cred: &Credential,
+        data_key: &[u8],
+        params: &KdfParams,
+    ) -> PserResult<u32> {
+        let wrapped_key = wrap_key(kind, cred, data_key, params)?;
+        let id = self.slots.iter().map(|s| s.id + 1).max().unwrap_or(0);
+        self.slots.push(KeySlot {
+            id,
+            kind,
+            desc: desc.to_string(),
+            created: now8().timestamp(),
+            wrapped_key,
+        });
+        Ok(id)
     }
 
     /// bincode序列化的验证头
@@ -60,19 +365,168 @@ impl VerifyHeader {
         bincode::serialize(self).expect("can't serialize VerifyHeader")
     }
 
+    /// 旧版本的验证头需先使用`migrate::migrate_header`转换为当前布局
     pub fn decode(data: &[u8]) -> PserResult<Self> {
+        if !data.starts_with(&MAGIC) {
+            return Err(Error::Corrupt("无法识别的验证头".to_string()));
+        }
         Ok(bincode::deserialize::<Self>(data)?)
     }
 }
 
-fn cur_min_timestamp() -> i64 {
-    now8().zero_from_sec().timestamp()
+/// 使用凭据中适用于kind类型密钥槽的部分包装数据密钥
+fn wrap_key(
+    kind: SlotKind,
+    cred: &Credential,
+    data_key: &[u8],
+    params: &KdfParams,
+) -> PserResult<Vec<u8>> {
+    let (passwd, secret) = cred
+        .secret_for(kind)
+        .ok_or_else(|| anyhow!("没有提供{}", kind))?;
+    Ok(EncryptData::encrypt_with_params(
+        &data_key, &passwd, &secret, params,
+    )?)
 }
 
-/// 明文密码转换为sha512
+/// 恢复码忽略大小写、空白和`-`
+fn normalize_recovery(code: &str) -> Zeroizing<String> {
+    let mut normalized = Zeroizing::new(String::with_capacity(code.len()));
+    code.chars()
+        .filter(|c| !c.is_whitespace() && *c != '-')
+        .for_each(|c| normalized.push(c.to_ascii_uppercase()));
+    normalized
+}
+
+/// 密钥文件内容的sha256(十六进制)，作为解开密钥文件密钥槽的密码
+fn keyfile_secret(keyfile: &[u8]) -> Zeroizing<String> {
+    let mut hex = Zeroizing::new(String::with_capacity(64));
+    for b in Sha256::digest(keyfile) {
+        let _ = write!(hex, "{:02x}", b);
+    }
+    hex
+}
+
+/// 明文密码转换为sha512，只用于验证旧版本的密码库
 fn passwd_sha512(plain_passwd: &str) -> Vec<u8> {
     let mut hasher = Sha512::new();
     hasher.update(plain_passwd.as_bytes());
     let res = hasher.finalize();
     res.to_vec()
 }
+
+#[cfg(test)]
+mod t {
+    use super::*;
+
+    fn unlock(header: &VerifyHeader, cred: &Credential) -> Option<Zeroizing<Vec<u8>>> {
+        match header.unlock_slots(cred) {
+            Some(Unlocked::Key { key, .. }) => Some(key),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn key_slots() {
+        let key = EncryptData::gen_key();
+        let keyfile = Credential::keyfile(b"keyfile".to_vec());
+        let mut header = VerifyHeader::new(&Credential::passwd("main"), &key).unwrap();
+        header
+            .add_slot(SlotKind::Keyfile, "usb", &keyfile, &key)
+            .unwrap();
+        let code = Credential::passwd("ABCD-EFGH");
+        let id = header
+            .add_slot(SlotKind::Recovery, "paper", &code, &key)
+            .unwrap();
+
+        let main = Credential::passwd("main");
+        assert_eq!(unlock(&header, &main), Some(key.clone()));
+        assert_eq!(unlock(&header, &keyfile), Some(key.clone()));
+        let typed = Credential::passwd("abcd efgh");
+        assert_eq!(unlock(&header, &typed), Some(key.clone()));
+        assert_eq!(unlock(&header, &Credential::passwd("wrong")), None);
+
+        // 主密码密钥槽的编号为0
+        assert!(header.remove_slot(0).is_err());
+        header.remove_slot(id).unwrap();
+        assert_eq!(unlock(&header, &typed), None);
+    }
+
+    #[test]
+    fn second_factor() {
+        let key = EncryptData::gen_key();
+        let main = Credential::passwd("main").with_keyfile(b"keyfile".to_vec());
+        let header = VerifyHeader::new(&main, &key).unwrap();
+
+        assert_eq!(unlock(&header, &main), Some(key));
+        assert_eq!(unlock(&header, &Credential::passwd("main")), None);
+        let other = Credential::passwd("main").with_keyfile(b"other".to_vec());
+        assert_eq!(unlock(&header, &other), None);
+    }
+
+    #[test]
+    fn legacy_check() {
+        let mut header = VerifyHeader {
+            magic: MAGIC,
+            throttle: Throttle::default(),
+            legacy_check: passwd_sha512("main"),
+            slots: vec![],
+        };
+        assert!(matches!(
+            header.unlock_slots(&Credential::passwd("main")),
+            Some(Unlocked::Legacy)
+        ));
+        assert!(header.unlock_slots(&Credential::passwd("wrong")).is_none());
+
+        // 升级后不再保存主密码的Sha512
+        let key = EncryptData::gen_key();
+        header
+            .change_passwd(&Credential::passwd("main"), &key)
+            .unwrap();
+        assert!(header.legacy_check.is_empty());
+        assert_eq!(unlock(&header, &Credential::passwd("main")), Some(key));
+    }
+
+    #[test]
+    fn rekey() {
+        let key = EncryptData::gen_key();
+        let main = Credential::passwd("main");
+        let mut header = VerifyHeader::new(&main, &key).unwrap();
+        assert_eq!(header.kdf_params(), KdfParams::default());
+
+        let params = KdfParams {
+            mem_cost: 1024,
+            time_cost: 1,
+            ..KdfParams::default()
+        };
+        assert!(header
+            .rekey(0, &Credential::default(), &key, &params)
+            .is_err());
+        header.rekey(0, &main, &key, &params).unwrap();
+        assert_eq!(header.kdf_params(), params);
+        assert_eq!(unlock(&header, &main), Some(key.clone()));
+
+        // 之后添加的密钥槽使用主密码密钥槽的参数
+        let code = Credential::passwd("ABCD");
+        let id = header
+            .add_slot(SlotKind::Recovery, "paper", &code, &key)
+            .unwrap();
+        assert_eq!(header.slots()[id as usize].kdf_params(), Some(params));
+    }
+
+    #[test]
+    fn throttled() {
+        let key = EncryptData::gen_key();
+        let mut header = VerifyHeader::new(&Credential::passwd("main"), &key).unwrap();
+        let wrong = Credential::passwd("wrong");
+        for _ in 0..=header.throttle.policy().free_attempts {
+            assert_eq!(header.unlock(&wrong).err(), Some(Denied::WrongCredential));
+        }
+        // 即使凭据正确也需要等待，且保存后重新读取也不会重置
+        let mut header = VerifyHeader::decode(&header.encode()).unwrap();
+        assert!(matches!(
+            header.unlock(&Credential::passwd("main")),
+            Err(Denied::Throttled { .. })
+        ));
+    }
+}